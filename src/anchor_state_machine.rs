@@ -1,5 +1,10 @@
+use bilge::prelude::*;
 use heapless::Vec;
 
+use crate::packet::{FinalPacket, PacketType, PriorityResponsePacket, PROTOCOL_VERSION};
+use crate::util::{check_disjoint, wrapping_interval, TIMESTAMP_MASK};
+use crate::{sealed, Phase, TerminalState, WaitingState, WrongPhase};
+
 /// Type-state state machine for the multi-anchor AltDS-TWR protocol, anchor side.
 ///
 /// This state machine is used to implement the multi-anchor multi-tag AltDS-TWR protocol.
@@ -10,7 +15,7 @@ use heapless::Vec;
 /// 3. All anchors send a final message to all tags.
 ///
 /// At the end of the protocol, the tags will have the distance to all anchors.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct AnchorSideStateMachine<STATE> {
     /// Anchor address
     address: u16,
@@ -35,22 +40,151 @@ pub struct AnchorSideStateMachine<STATE> {
     _state: STATE,
 }
 
+/// Error when a tag (or anchor) index is out of range of the configured tags (or anchors).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct IndexError {
+    /// The requested index
+    pub index: usize,
+
+    /// The number of configured tags (or anchors)
+    pub len: usize,
+}
+
+/// First field in which two anchor state machines differ, see `AnchorSideStateMachine::diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FieldDiff {
+    /// The anchor address
+    Address,
+    /// The anchor addresses in the network
+    AnchorAddresses,
+    /// The tag addresses
+    Tags,
+    /// The poll TX timestamp
+    PollTxTs,
+    /// The response RX timestamp of a tag
+    ResponseRxTs { tag_idx: usize },
+}
+
 /// The `Idle` state, where there is no ranging in progress.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Idle;
 
 /// The `WaitingForResponse` state, where the anchor is waiting for response messages from all tags.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct WaitingForResponse;
 
 /// The `SendingFinal` state, where the anchor is sending final messages to all tags.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct SendingFinal;
 
+impl sealed::Sealed for Idle {}
+impl sealed::Sealed for WaitingForResponse {}
+impl sealed::Sealed for SendingFinal {}
+
+impl TerminalState for Idle {}
+impl WaitingState for WaitingForResponse {}
+
+/// Abort the round from any waiting state, and transition back to the `Idle` state.
+///
+/// The responses received so far are dropped with the round, and no final is sent.
+pub fn abort<S: WaitingState>(
+    state_machine: AnchorSideStateMachine<S>,
+) -> AnchorSideStateMachine<Idle> {
+    AnchorSideStateMachine {
+        tags: state_machine.tags,
+        poll_tx_ts: None,
+        response_rx_ts: state_machine.response_rx_ts,
+        _state: Idle,
+        address: state_machine.address,
+        anchor_addresses: state_machine.anchor_addresses,
+    }
+}
+
+/// Implement `AnchorSideStateMachine` for all states.
+impl<STATE> AnchorSideStateMachine<STATE> {
+    /// Size of the state machine in bytes.
+    pub const FOOTPRINT: usize = core::mem::size_of::<Self>();
+
+    /// First field in which `other` differs from this machine, `None` if they are equal.
+    ///
+    /// For redundant anchors fed the same inputs, a difference points to a bug or corrupted
+    /// memory.
+    pub fn diff(&self, other: &Self) -> Option<FieldDiff> {
+        if self.address != other.address {
+            return Some(FieldDiff::Address);
+        }
+        if self.anchor_addresses != other.anchor_addresses {
+            return Some(FieldDiff::AnchorAddresses);
+        }
+        if self.tags != other.tags {
+            return Some(FieldDiff::Tags);
+        }
+        if self.poll_tx_ts != other.poll_tx_ts {
+            return Some(FieldDiff::PollTxTs);
+        }
+        if self.response_rx_ts.len() != other.response_rx_ts.len() {
+            return Some(FieldDiff::ResponseRxTs {
+                tag_idx: self.response_rx_ts.len().min(other.response_rx_ts.len()),
+            });
+        }
+
+        self.response_rx_ts
+            .iter()
+            .zip(&other.response_rx_ts)
+            .position(|(a, b)| a != b)
+            .map(|tag_idx| FieldDiff::ResponseRxTs { tag_idx })
+    }
+
+    /// The anchor addresses as an array, `None` if there are not exactly `N` anchors.
+    pub fn anchors_array<const N: usize>(&self) -> Option<[u16; N]> {
+        self.anchor_addresses.as_slice().try_into().ok()
+    }
+
+    /// Whether this anchor serves the priority slot of the superframe: it is the root, the
+    /// first of the anchor addresses.
+    ///
+    /// The root polls at the start of the priority slot every superframe, and sends the final
+    /// if a priority response arrived (see `Schedule::priority_frame_slot`).
+    pub fn serves_priority_slot(&self) -> bool {
+        self.anchor_addresses.first() == Some(&self.address)
+    }
+
+    /// Forget the poll TX timestamp and the responses of the current round.
+    ///
+    /// The addresses are kept.
+    pub fn clear_round_data(&mut self) {
+        self.poll_tx_ts = None;
+        self.response_rx_ts.iter_mut().for_each(|ts| *ts = None);
+    }
+
+    /// Bitmask of tags (by index) whose response was received in this round.
+    pub fn responded_tags(&self) -> u16 {
+        self.response_rx_ts
+            .iter()
+            .enumerate()
+            .filter(|(_, ts)| ts.is_some())
+            .fold(0, |mask, (i, _)| mask | (1 << i))
+    }
+
+    /// Whether the response of tag `tag_idx` was received in this round.
+    pub fn responded(&self, tag_idx: usize) -> bool {
+        self.response_rx_ts
+            .get(tag_idx)
+            .is_some_and(|ts| ts.is_some())
+    }
+}
+
 /// Implement `AnchorSideStateMachine` for `Idle`.
 impl AnchorSideStateMachine<Idle> {
     /// Create a new `AnchorSideStateMachine` in the `Idle` state.
+    ///
+    /// In debug builds, panics if an address is both in `anchors` and in `tags`.
     pub fn new(address: u16, anchors: Vec<u16, 16>, tags: Vec<u16, 16>) -> Self {
+        debug_assert!(
+            check_disjoint(&anchors, &tags).is_ok(),
+            "anchor and tag addresses overlap"
+        );
+
         Self {
             address,
             anchor_addresses: anchors,
@@ -61,11 +195,46 @@ impl AnchorSideStateMachine<Idle> {
         }
     }
 
+    /// Create a new `AnchorSideStateMachine` in the `Idle` state from address tables, e.g. arrays.
+    ///
+    /// Will panic if there are more than 16 anchors or tags. See `new`.
+    pub fn from_addresses(
+        address: u16,
+        anchors: impl AsRef<[u16]>,
+        tags: impl AsRef<[u16]>,
+    ) -> Self {
+        Self::new(
+            address,
+            Vec::from_slice(anchors.as_ref()).expect("more than 16 anchors"),
+            Vec::from_slice(tags.as_ref()).expect("more than 16 tags"),
+        )
+    }
+
+    /// TX timestamp of this anchor's poll: the start of its TDMA slot in the poll phase.
+    ///
+    /// Anchors poll in the order of the anchor addresses, one `slot_duration` each, starting at
+    /// `frame_start`. This is the value to program into the delayed TX register.
+    ///
+    /// Will panic if this anchor's address is not in the anchor addresses.
+    pub fn poll_tx_time(&self, frame_start: u64, slot_duration: u64) -> u64 {
+        let anchor_idx = self
+            .anchor_addresses
+            .iter()
+            .position(|&addr| addr == self.address)
+            .expect("anchor address not in the anchor addresses");
+
+        frame_start.wrapping_add(slot_duration.wrapping_mul(anchor_idx as u64)) & TIMESTAMP_MASK
+    }
+
     /// Transition to the `WaitingForResponse` state.
+    ///
+    /// This starts a new round, so the response RX timestamps of the previous round are cleared.
     pub fn waiting_for_response(
-        self,
+        mut self,
         poll_tx_ts: u64,
     ) -> AnchorSideStateMachine<WaitingForResponse> {
+        self.response_rx_ts.iter_mut().for_each(|ts| *ts = None);
+
         AnchorSideStateMachine {
             tags: self.tags,
             poll_tx_ts: Some(poll_tx_ts),
@@ -80,8 +249,70 @@ impl AnchorSideStateMachine<Idle> {
 /// Implement `AnchorSideStateMachine` for `WaitingForResponse`.
 impl AnchorSideStateMachine<WaitingForResponse> {
     /// Set the RX timestamp for a response message.
+    ///
+    /// Will panic if `tag_idx` is not a valid tag index.
     pub fn set_response_rx_ts(&mut self, tag_idx: usize, response_rx_ts: u64) {
+        if let Err(e) = self.set_response_rx_ts_checked(tag_idx, response_rx_ts) {
+            panic!("tag index {} out of range for {} tags", e.index, e.len);
+        }
+    }
+
+    /// Set the RX timestamp for a response message.
+    ///
+    /// Error if `tag_idx` is not a valid tag index.
+    pub fn set_response_rx_ts_checked(
+        &mut self,
+        tag_idx: usize,
+        response_rx_ts: u64,
+    ) -> Result<(), IndexError> {
+        if tag_idx >= self.tags.len() {
+            return Err(IndexError {
+                index: tag_idx,
+                len: self.tags.len(),
+            });
+        }
+
         self.response_rx_ts[tag_idx] = Some(response_rx_ts);
+        Ok(())
+    }
+
+    /// Set the RX timestamp of a priority response, attributed to its tag by the address it
+    /// carries rather than by its slot.
+    ///
+    /// Returns the index of the tag, `None` if the address is not one of the tags.
+    pub fn set_priority_response_rx_ts(
+        &mut self,
+        packet: &PriorityResponsePacket,
+        response_rx_ts: u64,
+    ) -> Option<usize> {
+        let tag_idx = self
+            .tags
+            .iter()
+            .position(|&addr| addr == packet.address())?;
+        self.response_rx_ts[tag_idx] = Some(response_rx_ts);
+
+        Some(tag_idx)
+    }
+
+    /// Expected RX time of the response of tag `tag_idx`, when all tags answer one poll in
+    /// sub-slots of `slot` device time units starting at `base`.
+    ///
+    /// Compare against the actual RX timestamp to check that a response arrived in its slot.
+    pub fn expected_response_ts(&self, tag_idx: usize, base: u64, slot: u64) -> u64 {
+        base.wrapping_add(slot.wrapping_mul(tag_idx as u64)) & TIMESTAMP_MASK
+    }
+
+    /// RX window `(start, end)` in device time for the response of tag `tag_idx`: its expected
+    /// RX time (see `expected_response_ts`) widened by `guard` on either side.
+    ///
+    /// Opening the receiver only within it keeps frames from neighboring slots out.
+    pub fn response_window(&self, tag_idx: usize, base: u64, slot: u64, guard: u64) -> (u64, u64) {
+        let expected = self.expected_response_ts(tag_idx, base, slot);
+
+        (
+            expected.wrapping_sub(guard) & TIMESTAMP_MASK,
+            expected.wrapping_add(guard) & TIMESTAMP_MASK,
+        )
     }
 
     /// Transition to the `SendingFinal` state.
@@ -95,6 +326,15 @@ impl AnchorSideStateMachine<WaitingForResponse> {
             anchor_addresses: self.anchor_addresses,
         }
     }
+
+    /// Transition to the `SendingFinal` state without waiting for the missing responses.
+    ///
+    /// For when a tag dropped out and its response will never arrive. The final then only
+    /// carries the responses that did arrive, the slots of the missing tags are marked invalid
+    /// (see `build_final_packet`), so the other tags still complete the round.
+    pub fn force_sending_final_partial(self) -> AnchorSideStateMachine<SendingFinal> {
+        self.sending_final()
+    }
 }
 
 /// Implement `AnchorSideStateMachine` for `SendingFinal`.
@@ -117,6 +357,86 @@ impl AnchorSideStateMachine<SendingFinal> {
     pub fn get_response_rx_ts(&self, tag_idx: usize) -> Option<u64> {
         self.response_rx_ts[tag_idx]
     }
+
+    /// Compute the TX timestamp for the final message.
+    ///
+    /// The final is scheduled `min_reply_ticks` after the latest response RX timestamp (or after
+    /// the poll if no response was received), so it is never scheduled in the past. This is the
+    /// value to program into the delayed TX register.
+    pub fn compute_final_tx_ts(&self, min_reply_ticks: u64) -> u64 {
+        let poll_tx_ts = self.poll_tx_ts.unwrap_or_default();
+
+        // Latest response, ordered by the time elapsed since the poll on the wrapping counter
+        let latest = self
+            .response_rx_ts
+            .iter()
+            .flatten()
+            .map(|&ts| wrapping_interval(poll_tx_ts, ts))
+            .max()
+            .unwrap_or_default();
+
+        poll_tx_ts
+            .wrapping_add(latest)
+            .wrapping_add(min_reply_ticks)
+            & TIMESTAMP_MASK
+    }
+
+    /// Build the final packet for this round, with `final_tx_ts` as its TX timestamp.
+    ///
+    /// Slot `i` carries the response RX timestamp of tag `i`. Slots of tags that did not respond
+    /// are zero and marked invalid. The poll TX timestamp already went out in the poll packet.
+    ///
+    /// Panics if there are more tags than the packet has slots.
+    pub fn build_final_packet<const N: usize>(&self, final_tx_ts: u64) -> FinalPacket<N> {
+        assert!(
+            self.tags.len() <= N,
+            "{} tags do not fit in a final packet with {} slots",
+            self.tags.len(),
+            N
+        );
+
+        let rx_timestamps = core::array::from_fn(|i| {
+            let ts = self.response_rx_ts.get(i).copied().flatten().unwrap_or(0);
+            u40::new(ts & TIMESTAMP_MASK)
+        });
+
+        let mut packet = FinalPacket::new(
+            PacketType::Final,
+            PROTOCOL_VERSION,
+            rx_timestamps,
+            u40::new(final_tx_ts & TIMESTAMP_MASK),
+        );
+        for slot in 0..N {
+            let received = matches!(self.response_rx_ts.get(slot), Some(Some(_)));
+            packet.set_slot_valid(slot, received);
+        }
+
+        packet
+    }
+
+    /// One single-slot final per tag, for unicast finals, as `(tag address, packet)`.
+    ///
+    /// The packet for a tag carries only that tag's response RX timestamp, marked invalid if its
+    /// response was not received, and `final_tx_ts` as its TX timestamp.
+    pub fn finals_per_tag(
+        &self,
+        final_tx_ts: u64,
+    ) -> impl Iterator<Item = (u16, FinalPacket<1>)> + '_ {
+        self.tags
+            .iter()
+            .zip(&self.response_rx_ts)
+            .map(move |(&tag_addr, &response_rx_ts)| {
+                let mut packet = FinalPacket::new(
+                    PacketType::Final,
+                    PROTOCOL_VERSION,
+                    [u40::new(response_rx_ts.unwrap_or(0) & TIMESTAMP_MASK)],
+                    u40::new(final_tx_ts & TIMESTAMP_MASK),
+                );
+                packet.set_slot_valid(0, response_rx_ts.is_some());
+
+                (tag_addr, packet)
+            })
+    }
 }
 
 /// Type erased state machine for the multi-anchor AltDS-TWR protocol, anchor side.
@@ -133,6 +453,26 @@ pub struct AnyAnchorSideStateMachine {
 }
 
 impl AnyAnchorSideStateMachine {
+    /// Protocol phase of the current state.
+    ///
+    /// An idle anchor is about to send its poll.
+    pub fn phase(&self) -> Phase {
+        match self.state_machine {
+            AnchorSideStateMachineTypeErased::Idle(_) => Phase::Poll,
+            AnchorSideStateMachineTypeErased::WaitingForResponse(_) => Phase::Response,
+            AnchorSideStateMachineTypeErased::SendingFinal(_) => Phase::Final,
+        }
+    }
+
+    /// Names of the transitions that are valid from the current state.
+    pub fn valid_transitions(&self) -> &'static [&'static str] {
+        match self.state_machine {
+            AnchorSideStateMachineTypeErased::Idle(_) => &["waiting_for_response"],
+            AnchorSideStateMachineTypeErased::WaitingForResponse(_) => &["sending_final"],
+            AnchorSideStateMachineTypeErased::SendingFinal(_) => &["idle"],
+        }
+    }
+
     /// Get a mutable reference to the state machine in the `Idle` state.
     pub fn as_idle_mut(&mut self) -> Option<&mut AnchorSideStateMachine<Idle>> {
         match &mut self.state_machine {
@@ -161,6 +501,16 @@ impl AnyAnchorSideStateMachine {
         }
     }
 
+    /// Set the RX timestamp for a response message.
+    ///
+    /// Error if the state machine is not in the `WaitingForResponse` state. Will panic if
+    /// `tag_idx` is not a valid tag index.
+    pub fn set_response_rx_ts(&mut self, tag_idx: usize, ts: u64) -> Result<(), WrongPhase> {
+        let state_machine = self.as_waiting_for_response_mut().ok_or(WrongPhase)?;
+        state_machine.set_response_rx_ts(tag_idx, ts);
+        Ok(())
+    }
+
     /// Transition to the `WaitingForResponse` state, from the `Idle` state.
     ///
     /// Error if the state machine is not in the `Idle` state.
@@ -364,6 +714,293 @@ mod tests {
         state_machines[0] = state_machine.clone().sending_final().into();
     }
 
+    #[test]
+    fn test_compute_final_tx_ts() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+
+        // Poll just before the 40-bit rollover, responses after it
+        let mut state_machine = state_machine.waiting_for_response(TIMESTAMP_MASK - 1000);
+        state_machine.set_response_rx_ts(0, 2_000);
+        state_machine.set_response_rx_ts(2, TIMESTAMP_MASK - 500);
+        let state_machine = state_machine.sending_final();
+
+        assert_eq!(state_machine.compute_final_tx_ts(10_000), 12_000);
+
+        // Without responses the final follows the poll
+        let state_machine = state_machine
+            .idle()
+            .waiting_for_response(5_000)
+            .sending_final();
+        assert_eq!(state_machine.compute_final_tx_ts(10_000), 15_000);
+    }
+
+    #[test]
+    fn test_build_final_packet() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+        let mut state_machine = state_machine.waiting_for_response(1_000);
+        state_machine.set_response_rx_ts(0, 2_000);
+        state_machine.set_response_rx_ts(2, 4_000);
+        let state_machine = state_machine.sending_final();
+
+        let packet: FinalPacket<3> = state_machine.build_final_packet(10_000);
+
+        for (i, ts) in packet.rx_timestamps.iter().enumerate() {
+            let expected = state_machine.get_response_rx_ts(i).unwrap_or(0);
+            assert_eq!(ts.value().value(), expected);
+        }
+        assert_eq!(packet.tx_timestamp.value().value(), 10_000);
+        assert_eq!(packet.valid_mask.get(), 0b101);
+        assert_eq!(packet.header().packet_type(), PacketType::Final);
+    }
+
+    #[test]
+    fn test_finals_per_tag() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+        let mut state_machine = state_machine.waiting_for_response(1_000);
+        state_machine.set_response_rx_ts(0, 2_000);
+        state_machine.set_response_rx_ts(2, 4_000);
+        let state_machine = state_machine.sending_final();
+
+        let finals: Vec<(u16, FinalPacket<1>), 16> = state_machine.finals_per_tag(10_000).collect();
+        assert_eq!(finals.len(), 3);
+
+        for (i, (tag_addr, packet)) in finals.iter().enumerate() {
+            assert_eq!(*tag_addr, 100 + i as u16);
+            assert_eq!(
+                packet.rx_timestamps[0].value().value(),
+                state_machine.get_response_rx_ts(i).unwrap_or(0)
+            );
+            assert_eq!(packet.is_slot_valid(0), state_machine.responded(i));
+            assert_eq!(packet.tx_timestamp.value().value(), 10_000);
+        }
+        assert!(!finals[1].1.is_slot_valid(0));
+    }
+
+    #[test]
+    fn test_abort() {
+        let state_machine = AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_response(1_000);
+        state_machine.set_response_rx_ts(0, 2_000);
+
+        let state_machine = abort(state_machine);
+        assert_eq!(state_machine.poll_tx_ts, None);
+
+        // The next round starts clean
+        let state_machine = state_machine.waiting_for_response(5_000);
+        assert_eq!(state_machine.responded_tags(), 0);
+    }
+
+    #[test]
+    fn test_clear_round_data() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::from_iter([0, 1]), Vec::from_iter([100, 101]));
+        let mut state_machine = state_machine.waiting_for_response(1_000);
+        state_machine.set_response_rx_ts(1, 2_000);
+
+        state_machine.clear_round_data();
+        assert_eq!(state_machine.poll_tx_ts, None);
+        assert_eq!(state_machine.response_rx_ts, [None, None]);
+        assert_eq!(state_machine.address, 0);
+        assert_eq!(state_machine.anchor_addresses, [0, 1]);
+        assert_eq!(state_machine.tags, [100, 101]);
+    }
+
+    #[test]
+    fn test_address_arrays() {
+        let state_machine = AnchorSideStateMachine::from_addresses(1, [0, 1, 2], [100]);
+
+        assert_eq!(state_machine.anchors_array(), Some([0, 1, 2]));
+        assert_eq!(state_machine.tags, [100]);
+        assert_eq!(state_machine.anchors_array::<4>(), None);
+    }
+
+    #[test]
+    fn test_diff() {
+        let run = |response_rx_ts: u64| {
+            let state_machine =
+                AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100, 101]));
+            let mut state_machine = state_machine.waiting_for_response(1_000);
+            state_machine.set_response_rx_ts(0, 2_000);
+            state_machine.set_response_rx_ts(1, response_rx_ts);
+            state_machine.sending_final()
+        };
+
+        let (a, b) = (run(3_000), run(3_000));
+        assert_eq!(a, b);
+        assert_eq!(a.diff(&b), None);
+
+        let tampered = run(3_001);
+        assert_ne!(a, tampered);
+        assert_eq!(
+            a.diff(&tampered),
+            Some(FieldDiff::ResponseRxTs { tag_idx: 1 })
+        );
+
+        let mut tampered = b;
+        tampered.poll_tx_ts = Some(999);
+        assert_eq!(a.diff(&tampered), Some(FieldDiff::PollTxTs));
+    }
+
+    #[test]
+    fn test_poll_tx_time() {
+        let state_machine =
+            AnchorSideStateMachine::new(13, Vec::from_iter([10, 11, 12, 13]), Vec::new());
+        assert_eq!(
+            state_machine.poll_tx_time(1_000, 50_000),
+            1_000 + 3 * 50_000
+        );
+
+        // Across the 40-bit rollover
+        assert_eq!(
+            state_machine.poll_tx_time(TIMESTAMP_MASK - 99_999, 50_000),
+            50_000
+        );
+    }
+
+    #[test]
+    fn test_force_sending_final_partial() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+        let mut state_machine = state_machine.waiting_for_response(1_000);
+
+        // Tag 101 dropped out
+        state_machine.set_response_rx_ts(0, 2_000);
+        state_machine.set_response_rx_ts(2, 4_000);
+        let state_machine = state_machine.force_sending_final_partial();
+
+        assert_eq!(state_machine.responded_tags(), 0b101);
+        assert_eq!(state_machine.compute_final_tx_ts(10_000), 14_000);
+
+        let packet: FinalPacket<3> = state_machine.build_final_packet(14_000);
+        assert_eq!(packet.valid_mask.get(), 0b101);
+        assert_eq!(packet.rx_timestamps[0].value().value(), 2_000);
+        assert_eq!(packet.rx_timestamps[2].value().value(), 4_000);
+    }
+
+    #[test]
+    fn test_expected_response_ts() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+        let state_machine = state_machine.waiting_for_response(1_000);
+
+        assert_eq!(
+            state_machine.expected_response_ts(0, 50_000, 20_000),
+            50_000
+        );
+        assert_eq!(
+            state_machine.expected_response_ts(2, 50_000, 20_000),
+            90_000
+        );
+
+        // Wraps around the 40-bit counter
+        assert_eq!(
+            state_machine.expected_response_ts(2, TIMESTAMP_MASK - 9_999, 20_000),
+            30_000
+        );
+    }
+
+    #[test]
+    fn test_response_window() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+        let state_machine = state_machine.waiting_for_response(1_000);
+
+        let first = state_machine.response_window(0, 50_000, 20_000, 1_000);
+        let second = state_machine.response_window(1, 50_000, 20_000, 1_000);
+        assert_eq!(first, (49_000, 51_000));
+        assert_eq!(second, (first.0 + 20_000, first.1 + 20_000));
+
+        // The guard may reach back across the 40-bit rollover
+        assert_eq!(
+            state_machine.response_window(0, 500, 20_000, 1_000),
+            (TIMESTAMP_MASK - 499, 1_500)
+        );
+    }
+
+    #[test]
+    fn test_responded_tags() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+        let mut state_machine = state_machine.waiting_for_response(1_000);
+        assert_eq!(state_machine.responded_tags(), 0);
+
+        // A zero timestamp still counts as a response
+        state_machine.set_response_rx_ts(0, 0);
+        state_machine.set_response_rx_ts(2, 4_000);
+        assert_eq!(state_machine.responded_tags(), 0b101);
+
+        let state_machine = state_machine.sending_final();
+        assert_eq!(state_machine.responded_tags(), 0b101);
+        assert!(state_machine.responded(0));
+        assert!(!state_machine.responded(1));
+        assert!(state_machine.responded(2));
+        assert!(!state_machine.responded(3));
+        assert!(!state_machine.responded(16));
+
+        // Cleared when the next round starts
+        let state_machine = state_machine.idle().waiting_for_response(9_000);
+        assert_eq!(state_machine.responded_tags(), 0);
+    }
+
+    #[test]
+    fn test_set_response_rx_ts_checked() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+        let mut state_machine = state_machine.waiting_for_response(0);
+
+        assert_eq!(state_machine.set_response_rx_ts_checked(2, 1_000), Ok(()));
+        assert_eq!(
+            state_machine.set_response_rx_ts_checked(3, 1_000),
+            Err(IndexError { index: 3, len: 3 })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "tag index 5 out of range for 3 tags")]
+    fn test_set_response_rx_ts_out_of_range() {
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::new(), Vec::from_iter([100, 101, 102]));
+        let mut state_machine = state_machine.waiting_for_response(0);
+
+        state_machine.set_response_rx_ts(5, 1_000);
+    }
+
+    #[test]
+    fn test_any_set_response_rx_ts() {
+        let mut any_sm = AnyAnchorSideStateMachine::from(AnchorSideStateMachine::new(
+            0,
+            Vec::new(),
+            Vec::from_iter([100, 101]),
+        ));
+
+        assert_eq!(any_sm.set_response_rx_ts(1, 2_000), Err(WrongPhase));
+
+        any_sm.to_waiting_for_response(1_000).unwrap();
+        assert_eq!(any_sm.set_response_rx_ts(1, 2_000), Ok(()));
+
+        any_sm.to_sending_final().unwrap();
+        assert_eq!(any_sm.set_response_rx_ts(0, 2_000), Err(WrongPhase));
+
+        let state_machine = any_sm.as_sending_final_mut().unwrap();
+        assert_eq!(state_machine.get_response_rx_ts(0), None);
+        assert_eq!(state_machine.get_response_rx_ts(1), Some(2_000));
+    }
+
+    #[test]
+    fn test_valid_transitions() {
+        let mut any_sm =
+            AnyAnchorSideStateMachine::from(AnchorSideStateMachine::new(0, Vec::new(), Vec::new()));
+
+        assert_eq!(any_sm.valid_transitions(), ["waiting_for_response"]);
+        any_sm.to_waiting_for_response(0).unwrap();
+        assert_eq!(any_sm.valid_transitions(), ["sending_final"]);
+        any_sm.to_sending_final().unwrap();
+        assert_eq!(any_sm.valid_transitions(), ["idle"]);
+    }
+
     #[test]
     fn test_any_mutate() {
         let mut any_sm =
@@ -0,0 +1,203 @@
+// Post-processing corrections of the measured distances
+//
+// The leading edge detection of the DW3000 depends on the received signal level, so the
+// measured distance carries a bias that changes with the RX power: strong (close) signals read
+// long, weak (far) ones read short. The bias is a property of the radio, the antennas and the
+// channel configuration, so the bias curve is an input: the vendor's published data for the
+// configuration in use, or better a calibration of the actual boards.
+//
+// Separately, every radio timestamps frames at a point in the digital receiver and transmitter,
+// not at the antenna. The antenna delays bridge that gap.
+//
+// Finally, the receiver's carrier recovery measures how far the sender's crystal is off ours,
+// which lets the intervals measured by the sender be put on our clock rate.
+
+use dw3000_ng::configs::UwbChannel;
+use dw3000_ng::Config;
+
+use crate::util::{DWT_TIME_UNIT, SPEED_OF_LIGHT, TIMESTAMP_MASK};
+
+/// TX and RX antenna delays of a radio, in device time units.
+///
+/// A frame leaves the antenna `tx_delay_ticks` after its TX timestamp, and reached the antenna
+/// `rx_delay_ticks` before its RX timestamp. So the TX delay is added to TX timestamps and the RX
+/// delay subtracted from RX timestamps.
+///
+/// In two-way ranging only the sum of a radio's delays enters the distance, but the two differ
+/// on real hardware, and applying the wrong one to a timestamp skews it. The DW3000 can apply
+/// both itself (TX and RX antenna delay registers); set these only if it does not.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct AntennaDelays {
+    /// Delay from the TX timestamp to the antenna
+    pub tx_delay_ticks: u16,
+
+    /// Delay from the antenna to the RX timestamp
+    pub rx_delay_ticks: u16,
+}
+
+impl AntennaDelays {
+    /// Time the frame with TX timestamp `tx_ts` left the antenna.
+    pub fn antenna_tx_ts(&self, tx_ts: u64) -> u64 {
+        tx_ts.wrapping_add(self.tx_delay_ticks as u64) & TIMESTAMP_MASK
+    }
+
+    /// Time the frame with RX timestamp `rx_ts` reached the antenna.
+    pub fn antenna_rx_ts(&self, rx_ts: u64) -> u64 {
+        rx_ts.wrapping_sub(self.rx_delay_ticks as u64) & TIMESTAMP_MASK
+    }
+}
+
+/// Estimate the antenna delay of a tag from reference measurements at known distances.
+///
+/// `measured_ticks` are measured times of flight in device time units, `true_distances_m` the
+/// corresponding true distances. Least-squares fits the constant offset between the two, which
+/// is half the sum of the antenna delays in the round trip. Returns that sum, `tx_delay_ticks +
+/// rx_delay_ticks` of the `AntennaDelays` which zero the error, or 0 without measurements.
+///
+/// Extra elements of the longer slice are ignored.
+pub fn estimate_antenna_delay(measured_ticks: &[i64], true_distances_m: &[f32]) -> i64 {
+    let (sum, count) = measured_ticks.iter().zip(true_distances_m).fold(
+        (0.0, 0),
+        |(sum, count), (&measured, &distance)| {
+            let true_ticks = distance as f64 / (DWT_TIME_UNIT * SPEED_OF_LIGHT);
+            (sum + (measured as f64 - true_ticks), count + 1)
+        },
+    );
+
+    match count {
+        0 => 0,
+        _ => libm::round(2.0 * sum / count as f64) as i64,
+    }
+}
+
+/// Carrier frequency offset in Hz per unit of the carrier integrator (998.4 MHz / 2 / 1024 / 2^17)
+const FREQ_OFFSET_MULTIPLIER: f64 = 998.4e6 / 2.0 / 1024.0 / 131_072.0;
+
+/// Clock offset of the sender of a frame relative to the receiver, as a ratio (1e-6 per ppm).
+///
+/// From the carrier integrator the DW3000 reports for the frame (DRX_CARRIER_INT, 21-bit
+/// signed): `ratio = -carrier_integrator * FREQ_OFFSET_MULTIPLIER / f_c`, where `f_c` is the
+/// center frequency of the channel. Positive if the sender's clock runs fast.
+///
+/// An interval the sender measured is `1 + ratio` times as long on its clock as on ours. Only
+/// intervals are scaled, never absolute timestamps: the counters have unrelated origins. See
+/// `TagSideStateMachine::set_clock_offset_ratio_idx`.
+pub fn clock_offset_ratio(carrier_integrator: i32, config: &Config) -> f64 {
+    let center_freq_hz = match config.channel {
+        UwbChannel::Channel5 => 6489.6e6,
+        UwbChannel::Channel9 => 7987.2e6,
+    };
+
+    -(carrier_integrator as f64 * FREQ_OFFSET_MULTIPLIER) / center_freq_hz
+}
+
+/// Range bias curve: points of (RX power in dBm, measured minus true distance in cm), sorted by
+/// increasing RX power.
+///
+/// The crate ships no curve of its own. Take it from the vendor's range bias data for the channel
+/// and PRF in use, or measure it on the actual boards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RangeBiasTable<'a> {
+    points: &'a [(f32, f32)],
+}
+
+impl<'a> RangeBiasTable<'a> {
+    /// Wrap a bias curve. `None` if it is empty or not sorted by strictly increasing RX power.
+    pub fn new(points: &'a [(f32, f32)]) -> Option<Self> {
+        let sorted = points.windows(2).all(|w| w[0].0 < w[1].0);
+        (!points.is_empty() && sorted).then_some(Self { points })
+    }
+
+    /// Range bias in meters at an RX power, linearly interpolated between the points.
+    ///
+    /// Powers outside the curve are clamped to its ends.
+    pub fn bias_m(&self, rx_power_dbm: f32) -> f32 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if rx_power_dbm <= first.0 {
+            return first.1 / 100.0;
+        }
+        if rx_power_dbm >= last.0 {
+            return last.1 / 100.0;
+        }
+
+        let bias_cm = self
+            .points
+            .windows(2)
+            .find(|w| rx_power_dbm <= w[1].0)
+            .map(|w| {
+                let ((p0, b0), (p1, b1)) = (w[0], w[1]);
+                b0 + (b1 - b0) * (rx_power_dbm - p0) / (p1 - p0)
+            })
+            .unwrap_or(last.1);
+
+        bias_cm / 100.0
+    }
+}
+
+/// Remove the RX-power-dependent range bias from a measured distance.
+///
+/// Meant as a post-processing step on the output of `compute_distances`, with the RX power of
+/// the anchor's messages and the bias curve of the radio.
+pub fn apply_range_bias_correction(
+    distance_m: f32,
+    rx_power_dbm: f32,
+    table: &RangeBiasTable,
+) -> f32 {
+    distance_m - table.bias_m(rx_power_dbm)
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_antenna_delay() {
+        // 600 + 200 ticks of antenna delay lengthen every time of flight by 400 ticks
+        let true_distances_m = [1.0, 5.0, 10.0, 20.0];
+        let noise = [2, -2, 1, -1];
+        let measured_ticks: [i64; 4] = core::array::from_fn(|i| {
+            let true_ticks = true_distances_m[i] as f64 / (DWT_TIME_UNIT * SPEED_OF_LIGHT);
+            libm::round(true_ticks) as i64 + 400 + noise[i]
+        });
+
+        let delay = estimate_antenna_delay(&measured_ticks, &true_distances_m);
+        assert!((delay - 800).abs() <= 1);
+
+        assert_eq!(estimate_antenna_delay(&[], &true_distances_m), 0);
+    }
+
+    #[test]
+    fn test_clock_offset_ratio() {
+        let config = Config::default();
+        assert_eq!(config.channel, UwbChannel::Channel5);
+
+        // 1000 units are ~3.72 kHz, -0.573 ppm on channel 5
+        let ratio = clock_offset_ratio(1_000, &config);
+        assert!((ratio * 1e6 + 0.5731).abs() < 1e-4);
+        assert_eq!(clock_offset_ratio(-1_000, &config), -ratio);
+        assert_eq!(clock_offset_ratio(0, &config), 0.0);
+    }
+
+    #[test]
+    fn test_range_bias_correction() {
+        let points = [(-90.0, -8.0), (-80.0, 0.0), (-70.0, 10.0)];
+        let table = RangeBiasTable::new(&points).unwrap();
+
+        // On a point, reads 10 cm long at -70 dBm
+        assert!((apply_range_bias_correction(5.0, -70.0, &table) - 4.9).abs() < 1e-6);
+        assert!((apply_range_bias_correction(5.0, -80.0, &table) - 5.0).abs() < 1e-6);
+
+        // Between points: 5 cm at -75 dBm, -4 cm at -85 dBm
+        assert!((table.bias_m(-75.0) - 0.05).abs() < 1e-6);
+        assert!((apply_range_bias_correction(20.0, -85.0, &table) - 20.04).abs() < 1e-5);
+
+        // Clamped outside the curve
+        assert!((table.bias_m(-40.0) - 0.1).abs() < 1e-6);
+        assert!((table.bias_m(-110.0) + 0.08).abs() < 1e-6);
+
+        assert_eq!(RangeBiasTable::new(&[]), None);
+        assert_eq!(RangeBiasTable::new(&[(-70.0, 10.0), (-80.0, 0.0)]), None);
+    }
+}
@@ -0,0 +1,138 @@
+// RF constants of the UWB channels supported by the DW3000
+use dw3000_ng::configs::{BitRate, PreambleLength, PulseRepetitionFrequency};
+
+use crate::util::SPEED_OF_LIGHT;
+
+/// Total TX power at the regulatory limit of -41.3 dBm/MHz over 500 MHz, in dBm
+const TX_POWER_DBM: f32 = -14.3;
+
+/// Margin for antenna, body and fading losses in the link budget, in dB
+const LINK_MARGIN_DB: f32 = 10.0;
+
+/// Receiver sensitivity with a 64-symbol preamble, 6.8 Mbps and 64 MHz PRF, in dBm
+const BASE_SENSITIVITY_DBM: f32 = -94.0;
+
+/// Preamble lengths in increasing order
+const PREAMBLE_LENGTHS: [PreambleLength; 10] = [
+    PreambleLength::Symbols32,
+    PreambleLength::Symbols64,
+    PreambleLength::Symbols72,
+    PreambleLength::Symbols128,
+    PreambleLength::Symbols256,
+    PreambleLength::Symbols512,
+    PreambleLength::Symbols1024,
+    PreambleLength::Symbols1536,
+    PreambleLength::Symbols2048,
+    PreambleLength::Symbols4096,
+];
+
+/// Center frequency of a UWB channel in Hz.
+///
+/// Returns `None` for channels the DW3000 does not support (only channels 5 and 9).
+pub fn center_freq_hz(channel: u8) -> Option<u64> {
+    match channel {
+        5 => Some(6_489_600_000),
+        9 => Some(7_987_200_000),
+        _ => None,
+    }
+}
+
+/// Wavelength of a UWB channel's center frequency in meters.
+///
+/// Used to express the antenna spacing in wavelengths for PDoA/AoA.
+pub fn wavelength_m(channel: u8) -> Option<f64> {
+    center_freq_hz(channel).map(|freq| SPEED_OF_LIGHT / freq as f64)
+}
+
+/// Number of symbols of a preamble length.
+fn preamble_symbols(preamble_length: PreambleLength) -> u32 {
+    match preamble_length {
+        PreambleLength::Symbols32 => 32,
+        PreambleLength::Symbols64 => 64,
+        PreambleLength::Symbols72 => 72,
+        PreambleLength::Symbols128 => 128,
+        PreambleLength::Symbols256 => 256,
+        PreambleLength::Symbols512 => 512,
+        PreambleLength::Symbols1024 => 1024,
+        PreambleLength::Symbols1536 => 1536,
+        PreambleLength::Symbols2048 => 2048,
+        PreambleLength::Symbols4096 => 4096,
+    }
+}
+
+/// Shortest preamble whose link budget reaches `range_m` on channel 5.
+///
+/// Simplified line-of-sight model: free-space path loss from the TX power at the regulatory
+/// limit, minus a fixed margin, against a receiver sensitivity that improves by 3 dB per
+/// doubling of the preamble, by 6 dB at 850 kbps and by 1 dB at 64 MHz PRF. A rough guide, not
+/// a substitute for a site survey. Falls back to the longest preamble if none is enough.
+pub fn min_preamble_for_range(
+    range_m: f32,
+    bitrate: BitRate,
+    prf: PulseRepetitionFrequency,
+) -> PreambleLength {
+    // Cannot fail, channel 5 is supported
+    let wavelength_m = wavelength_m(5).unwrap_or_default() as f32;
+    let path_loss_db = 20.0 * libm::log10f(4.0 * core::f32::consts::PI * range_m / wavelength_m);
+    let rx_power_dbm = TX_POWER_DBM - LINK_MARGIN_DB - path_loss_db;
+
+    let mut sensitivity_dbm = BASE_SENSITIVITY_DBM;
+    if bitrate == BitRate::Kbps850 {
+        sensitivity_dbm -= 6.0;
+    }
+    if prf == PulseRepetitionFrequency::Mhz16 {
+        sensitivity_dbm += 1.0;
+    }
+
+    PREAMBLE_LENGTHS
+        .into_iter()
+        .find(|&preamble_length| {
+            let gain_db = 10.0 * libm::log10f(preamble_symbols(preamble_length) as f32 / 64.0);
+            rx_power_dbm >= sensitivity_dbm - gain_db
+        })
+        .unwrap_or(PreambleLength::Symbols4096)
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_center_freq() {
+        assert_eq!(center_freq_hz(5), Some(6_489_600_000));
+        assert_eq!(center_freq_hz(9), Some(7_987_200_000));
+        assert_eq!(center_freq_hz(2), None);
+
+        // ~4.6 cm and ~3.8 cm
+        assert!((wavelength_m(5).unwrap() - 0.04618).abs() < 1e-4);
+        assert!((wavelength_m(9).unwrap() - 0.03752).abs() < 1e-4);
+        assert_eq!(wavelength_m(2), None);
+    }
+
+    #[test]
+    fn test_min_preamble_for_range() {
+        let preamble = |range_m| {
+            preamble_symbols(min_preamble_for_range(
+                range_m,
+                BitRate::Kbps6800,
+                PulseRepetitionFrequency::Mhz64,
+            ))
+        };
+
+        assert_eq!(preamble(5.0), 32);
+        assert!(preamble(20.0) > preamble(10.0));
+        assert!(preamble(40.0) > preamble(20.0));
+        assert_eq!(preamble(1_000.0), 4096);
+
+        // The lower bitrate needs less preamble for the same range
+        assert!(
+            preamble_symbols(min_preamble_for_range(
+                40.0,
+                BitRate::Kbps850,
+                PulseRepetitionFrequency::Mhz64
+            )) < preamble(40.0)
+        );
+    }
+}
@@ -0,0 +1,759 @@
+// Event-driven driver for the tag side of the protocol
+//
+// The driver owns a type-erased tag state machine and advances it from radio events, so the
+// application only has to forward what the radio reports. Rejected events and round outcomes
+// are counted in `Metrics` for field monitoring. The tag's own timestamps can either be passed
+// explicitly or read from a `TimestampSource`, which keeps the driver independent of the radio.
+use defmt::Format;
+use dw3000_ng::Config;
+
+use crate::packet::{PacketType, PriorityResponsePacket, MAX_FINAL_SLOTS, PROTOCOL_VERSION};
+use crate::schedule::Schedule;
+use crate::tag_state_machine::{AnyTagSideStateMachine, Idle, RoundResults, TagSideStateMachine};
+use crate::util::{packet_tx_time, RngSource, PHASE_GUARD_TIME};
+use crate::Phase;
+
+/// RX timeout for a protocol phase, in the DW3000's RX timeout units (512 / 499.2 MHz, ~1.026 us)
+///
+/// Covers the air time of the frames expected from `num_peers` peers in that phase (polls or
+/// finals from the anchors, responses from the tags), plus a `PHASE_GUARD_TIME` margin.
+pub fn rx_timeout_for_phase(phase: Phase, config: &Config, num_peers: usize) -> u32 {
+    let packet_type = match phase {
+        Phase::Poll => PacketType::Poll,
+        Phase::Response => PacketType::Response,
+        Phase::Final => PacketType::Final,
+    };
+    // Finals are sized for the most tag slots, so the timeout is never too short
+    let air_time = packet_tx_time(packet_type, MAX_FINAL_SLOTS, config);
+
+    let timeout_ns = num_peers as u64 * air_time as u64 + PHASE_GUARD_TIME as u64;
+
+    // Round up, a short timeout would cut off the last frame
+    (timeout_ns * 499_200).div_ceil(512 * 1_000_000) as u32
+}
+
+/// Source of the radio timestamps, in device time units.
+///
+/// Implemented on top of the radio driver (e.g. reading the DW3000 TX and RX timestamp
+/// registers), or by a mock in tests.
+pub trait TimestampSource {
+    /// Current device time, e.g. to schedule a delayed transmission
+    fn now(&self) -> u64;
+
+    /// TX timestamp of the last transmitted frame
+    fn last_tx_ts(&self) -> u64;
+
+    /// RX timestamp of the last received frame
+    fn last_rx_ts(&self) -> u64;
+}
+
+/// Bounded, jittered backoff before retrying a failed round.
+///
+/// When the responses of two tags collide, both rounds fail; retrying after the same delay would
+/// collide again. The delay is drawn from a window that doubles with every attempt, at a position
+/// derived from the tag address, so different tags spread out while each tag stays
+/// deterministic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Backoff {
+    /// Smallest backoff window in device time units
+    base_delay: u64,
+
+    /// Number of retries before giving up
+    max_attempts: u8,
+
+    /// Retries since the last successful round
+    attempts: u8,
+}
+
+impl Default for Backoff {
+    /// 1 ms base window, 5 retries.
+    fn default() -> Self {
+        Self::new(63_897_600, 5)
+    }
+}
+
+impl Backoff {
+    /// Create a new `Backoff` with a `base_delay` window (in device time units) and at most
+    /// `max_attempts` retries.
+    pub fn new(base_delay: u64, max_attempts: u8) -> Self {
+        Self {
+            base_delay,
+            max_attempts,
+            attempts: 0,
+        }
+    }
+
+    /// Number of retries since the last reset.
+    pub fn attempts(&self) -> u8 {
+        self.attempts
+    }
+
+    /// Whether all retries are used up.
+    pub fn is_exhausted(&self) -> bool {
+        self.attempts >= self.max_attempts
+    }
+
+    /// Delay before the next retry of the tag `addr`, in device time units, and count the retry.
+    ///
+    /// The delay lies in `[window, 2 * window)`, with the window doubling on every retry. Once
+    /// exhausted, the window stops growing.
+    pub fn next_delay(&mut self, addr: u16) -> u64 {
+        // SplitMix64 finalizer over the address and the attempt
+        let mut x = ((addr as u64) << 8 | self.attempts as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^= x >> 31;
+
+        self.delay_from(x)
+    }
+
+    /// Like `next_delay`, with the randomness drawn from `rng` instead of the address.
+    pub fn next_delay_with(&mut self, rng: &mut impl RngSource) -> u64 {
+        let x = (0..4).fold(0, |x, _| x << 16 | rng.next_u16() as u64);
+
+        self.delay_from(x)
+    }
+
+    /// Delay in the window of the current retry for the random value `x`, and count the retry.
+    fn delay_from(&mut self, x: u64) -> u64 {
+        let exponent = self.attempts.min(self.max_attempts).min(16);
+        let window = self.base_delay << exponent;
+
+        self.attempts = self.attempts.saturating_add(1);
+
+        window + x % window.max(1)
+    }
+
+    /// Forget the retries, e.g. after a successful round.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Counters of rejected events and round outcomes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Metrics {
+    /// Polls dropped (unknown or ignored anchor, or received outside the poll phase)
+    pub polls_dropped: u32,
+
+    /// Finals rejected (unknown or ignored anchor, or received outside the final phase)
+    pub finals_rejected: u32,
+
+    /// Rounds that received all expected finals
+    pub rounds_completed: u32,
+
+    /// Rounds aborted by a timeout
+    pub rounds_timed_out: u32,
+}
+
+/// Completed rounds per tag, to detect tags starved of ranging opportunities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct FairnessTracker {
+    /// Number of tracked tags
+    num_tags: usize,
+
+    /// Completed rounds per tag index
+    rounds: [u32; 16],
+}
+
+impl FairnessTracker {
+    /// Create a new `FairnessTracker` for `num_tags` tags, without rounds.
+    ///
+    /// Will panic if `num_tags` exceeds 16.
+    pub fn new(num_tags: usize) -> Self {
+        assert!(num_tags <= 16);
+
+        Self {
+            num_tags,
+            rounds: [0; 16],
+        }
+    }
+
+    /// Count a completed round of a tag.
+    ///
+    /// Will panic if `tag_idx` is not a valid tag index.
+    pub fn record_round(&mut self, tag_idx: usize) {
+        let rounds = &mut self.rounds[..self.num_tags][tag_idx];
+        *rounds = rounds.saturating_add(1);
+    }
+
+    /// Completed rounds per tag index.
+    pub fn rounds(&self) -> &[u32] {
+        &self.rounds[..self.num_tags]
+    }
+
+    /// Jain's fairness index of the per-tag round counts, `(Σx)² / (n Σx²)`.
+    ///
+    /// 1 if all tags got the same number of rounds, down to `1 / n` if a single tag got them
+    /// all. 1 without rounds.
+    pub fn jain_index(&self) -> f32 {
+        let (sum, sum_sq) = self.rounds().iter().fold((0.0, 0.0), |(sum, sum_sq), &x| {
+            let x = x as f64;
+            (sum + x, sum_sq + x * x)
+        });
+        if sum_sq == 0.0 {
+            return 1.0;
+        }
+
+        (sum * sum / (self.num_tags as f64 * sum_sq)) as f32
+    }
+
+    /// Forget all recorded rounds.
+    pub fn reset(&mut self) {
+        self.rounds = [0; 16];
+    }
+}
+
+/// Driver for the tag side of the protocol.
+#[derive(Debug)]
+pub struct TagDriver {
+    /// The tag state machine
+    state_machine: AnyTagSideStateMachine,
+
+    /// Accumulated metrics
+    metrics: Metrics,
+
+    /// Backoff of the retries after failed rounds
+    backoff: Backoff,
+
+    /// Whether the next round is a priority round, see `request_priority_round`
+    priority_requested: bool,
+}
+
+impl TagDriver {
+    /// Create a new driver from an idle tag state machine.
+    pub fn new(state_machine: TagSideStateMachine<Idle>) -> Self {
+        Self {
+            state_machine: state_machine.into(),
+            metrics: Metrics::default(),
+            backoff: Backoff::default(),
+            priority_requested: false,
+        }
+    }
+
+    /// Create a new driver from an idle tag state machine, with a custom retry backoff.
+    pub fn with_backoff(state_machine: TagSideStateMachine<Idle>, backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            ..Self::new(state_machine)
+        }
+    }
+
+    /// Get the tag state machine.
+    pub fn state_machine(&self) -> &AnyTagSideStateMachine {
+        &self.state_machine
+    }
+
+    /// Get the accumulated metrics.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Reset all metrics to zero.
+    pub fn reset_metrics(&mut self) {
+        self.metrics = Metrics::default();
+    }
+
+    /// Request a priority round, e.g. for an alert: the tag transmits in the priority contention
+    /// slot of the schedule instead of its regular slot (see `tx_slot`).
+    ///
+    /// The request stays pending until a round completes, so a failed priority round is retried
+    /// as one.
+    pub fn request_priority_round(&mut self) {
+        self.priority_requested = true;
+    }
+
+    /// Whether a priority round is pending.
+    pub fn is_priority_pending(&self) -> bool {
+        self.priority_requested
+    }
+
+    /// Priority response to send instead of the regular response while a priority round is
+    /// pending, in the priority slot (see `tx_slot`). `None` without a pending priority round.
+    ///
+    /// Carries the tag address, by which the root anchor attributes it (see
+    /// `AnchorSideStateMachine::set_priority_response_rx_ts`).
+    pub fn priority_response(&self) -> Option<PriorityResponsePacket> {
+        self.priority_requested.then(|| {
+            PriorityResponsePacket::new(
+                PacketType::PriorityResponse,
+                PROTOCOL_VERSION,
+                self.state_machine.address(),
+                0,
+            )
+        })
+    }
+
+    /// Slot in which the tag `tag_idx` of `schedule` transmits next, in nanoseconds from the
+    /// superframe start, see `Schedule::tx_slot`.
+    pub fn tx_slot(
+        &self,
+        schedule: &Schedule,
+        tag_idx: usize,
+        config: &Config,
+    ) -> Option<(u64, u64)> {
+        schedule.tx_slot(tag_idx, self.priority_requested, config)
+    }
+
+    /// Start a new round, waiting for the anchors' polls.
+    ///
+    /// Error if a round is already in progress.
+    pub fn start_round(&mut self) -> Result<(), ()> {
+        self.state_machine.to_waiting_for_anchor_poll()
+    }
+
+    /// Handle a poll from `anchor_addr`, sent at `poll_tx_ts` (anchor time) and received at
+    /// `poll_rx_ts` (tag time).
+    ///
+    /// Error (and counted as dropped) if the anchor is unknown or ignored (see
+    /// `TagSideStateMachine::listens_to`), or no poll is expected.
+    pub fn on_poll(
+        &mut self,
+        anchor_addr: u16,
+        poll_tx_ts: u64,
+        poll_rx_ts: u64,
+    ) -> Result<(), ()> {
+        let Some(state_machine) = self.state_machine.as_waiting_for_anchor_poll_mut() else {
+            self.metrics.polls_dropped = self.metrics.polls_dropped.saturating_add(1);
+            return Err(());
+        };
+
+        let Some(anchor_idx) = state_machine
+            .anchor_index(anchor_addr)
+            .filter(|&idx| state_machine.listens_to(idx))
+        else {
+            self.metrics.polls_dropped = self.metrics.polls_dropped.saturating_add(1);
+            return Err(());
+        };
+
+        state_machine.set_poll_tx_ts_idx(anchor_idx, poll_tx_ts);
+        state_machine.set_poll_rx_ts_idx(anchor_idx, poll_rx_ts);
+
+        Ok(())
+    }
+
+    /// Record that the response was sent at `response_tx_ts`, and wait for the finals.
+    ///
+    /// Error if the tag is not waiting for polls.
+    pub fn on_response_sent(&mut self, response_tx_ts: u64) -> Result<(), ()> {
+        self.state_machine.to_waiting_for_anchor_final()?;
+
+        if let Some(state_machine) = self.state_machine.as_waiting_for_anchor_final_mut() {
+            state_machine.set_response_tx_ts(response_tx_ts);
+        }
+
+        Ok(())
+    }
+
+    /// Handle a final from `anchor_addr`, carrying the anchor's `response_rx_ts` and
+    /// `final_tx_ts` (anchor time), received at `final_rx_ts` (tag time).
+    ///
+    /// Returns the round results once the finals of all polling anchors have arrived. Error (and
+    /// counted as rejected) if the anchor is unknown or ignored, or no final is expected.
+    pub fn on_final(
+        &mut self,
+        anchor_addr: u16,
+        response_rx_ts: u64,
+        final_tx_ts: u64,
+        final_rx_ts: u64,
+    ) -> Result<Option<RoundResults>, ()> {
+        let Some(state_machine) = self.state_machine.as_waiting_for_anchor_final_mut() else {
+            self.metrics.finals_rejected = self.metrics.finals_rejected.saturating_add(1);
+            return Err(());
+        };
+
+        let Some(anchor_idx) = state_machine
+            .anchor_index(anchor_addr)
+            .filter(|&idx| state_machine.listens_to(idx))
+        else {
+            self.metrics.finals_rejected = self.metrics.finals_rejected.saturating_add(1);
+            return Err(());
+        };
+
+        state_machine.set_response_rx_ts_idx(anchor_idx, response_rx_ts);
+        state_machine.set_final_tx_ts_idx(anchor_idx, final_tx_ts);
+        state_machine.set_final_rx_ts_idx(anchor_idx, final_rx_ts);
+
+        if !state_machine.is_complete() {
+            return Ok(None);
+        }
+
+        let (state_machine, results) = core::mem::take(state_machine).idle_with_results();
+        self.state_machine = state_machine.into();
+        self.metrics.rounds_completed = self.metrics.rounds_completed.saturating_add(1);
+        self.backoff.reset();
+        self.priority_requested = false;
+
+        Ok(Some(results))
+    }
+
+    /// Handle a poll from `anchor_addr` sent at `poll_tx_ts` (anchor time), just received by the
+    /// radio behind `source`.
+    ///
+    /// Same as `on_poll` with the last RX timestamp of `source`.
+    pub fn on_poll_received<S: TimestampSource>(
+        &mut self,
+        source: &S,
+        anchor_addr: u16,
+        poll_tx_ts: u64,
+    ) -> Result<(), ()> {
+        self.on_poll(anchor_addr, poll_tx_ts, source.last_rx_ts())
+    }
+
+    /// Record that the radio behind `source` just sent the response, and wait for the finals.
+    ///
+    /// Same as `on_response_sent` with the last TX timestamp of `source`.
+    pub fn on_response_transmitted<S: TimestampSource>(&mut self, source: &S) -> Result<(), ()> {
+        self.on_response_sent(source.last_tx_ts())
+    }
+
+    /// Handle a final from `anchor_addr` carrying the anchor's `response_rx_ts` and
+    /// `final_tx_ts` (anchor time), just received by the radio behind `source`.
+    ///
+    /// Same as `on_final` with the last RX timestamp of `source`.
+    pub fn on_final_received<S: TimestampSource>(
+        &mut self,
+        source: &S,
+        anchor_addr: u16,
+        response_rx_ts: u64,
+        final_tx_ts: u64,
+    ) -> Result<Option<RoundResults>, ()> {
+        self.on_final(
+            anchor_addr,
+            response_rx_ts,
+            final_tx_ts,
+            source.last_rx_ts(),
+        )
+    }
+
+    /// Abort the round in progress after a timeout.
+    ///
+    /// Returns the delay (in device time units) to wait before starting the retry, or `None` if
+    /// the retries are used up. Does nothing and returns `None` if no round is in progress.
+    pub fn on_timeout(&mut self) -> Option<u64> {
+        self.state_machine.to_idle().ok()?;
+        self.metrics.rounds_timed_out = self.metrics.rounds_timed_out.saturating_add(1);
+
+        if self.backoff.is_exhausted() {
+            return None;
+        }
+
+        let address = self
+            .state_machine
+            .as_idle_mut()
+            .map_or(0, |sm| sm.address());
+        Some(self.backoff.next_delay(address))
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::cell::Cell;
+
+    use dw3000_ng::configs::{BitRate, PreambleLength, PulseRepetitionFrequency};
+    use heapless::Vec;
+
+    use crate::util::Xorshift16;
+
+    /// Radio stand-in whose timestamps are set by the test
+    #[derive(Default)]
+    struct MockRadio {
+        now: Cell<u64>,
+        tx_ts: Cell<u64>,
+        rx_ts: Cell<u64>,
+    }
+
+    impl MockRadio {
+        fn transmit(&self, ts: u64) {
+            self.now.set(ts);
+            self.tx_ts.set(ts);
+        }
+
+        fn receive(&self, ts: u64) {
+            self.now.set(ts);
+            self.rx_ts.set(ts);
+        }
+    }
+
+    impl TimestampSource for MockRadio {
+        fn now(&self) -> u64 {
+            self.now.get()
+        }
+
+        fn last_tx_ts(&self) -> u64 {
+            self.tx_ts.get()
+        }
+
+        fn last_rx_ts(&self) -> u64 {
+            self.rx_ts.get()
+        }
+    }
+
+    #[test]
+    fn test_rx_timeout_for_phase() {
+        let config = Config {
+            bitrate: BitRate::Kbps6800,
+            pulse_repetition_frequency: PulseRepetitionFrequency::Mhz64,
+            preamble_length: PreambleLength::Symbols128,
+            ..Default::default()
+        };
+
+        let two = rx_timeout_for_phase(Phase::Poll, &config, 2);
+        let eight = rx_timeout_for_phase(Phase::Poll, &config, 8);
+        assert!(eight > two);
+
+        // Guard time only: 500 us ~ 488 units
+        assert_eq!(rx_timeout_for_phase(Phase::Final, &config, 0), 488);
+        assert!(
+            rx_timeout_for_phase(Phase::Final, &config, 8)
+                > rx_timeout_for_phase(Phase::Response, &config, 8)
+        );
+    }
+
+    #[test]
+    fn test_timestamp_source_round() {
+        let radio = MockRadio::default();
+        let mut driver = TagDriver::new(TagSideStateMachine::new(
+            100,
+            Vec::from_iter([0, 1]),
+            Vec::from_iter([100]),
+        ));
+        assert!(driver.start_round().is_ok());
+
+        // Tag clock 2_000 ahead of the anchors, ToF 2_000 (~9.39 m)
+        radio.receive(5_000);
+        assert!(driver.on_poll_received(&radio, 0, 1_000).is_ok());
+        radio.receive(5_500);
+        assert!(driver.on_poll_received(&radio, 1, 1_500).is_ok());
+
+        radio.transmit(105_000);
+        assert!(driver.on_response_transmitted(&radio).is_ok());
+        assert_eq!(radio.now(), 105_000);
+
+        radio.receive(209_000);
+        assert_eq!(
+            driver.on_final_received(&radio, 0, 105_000, 205_000),
+            Ok(None)
+        );
+        radio.receive(209_500);
+        let results = driver
+            .on_final_received(&radio, 1, 105_000, 205_500)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(results.participation(), 0b11);
+        for distance in results.distances() {
+            assert!((distance.unwrap() - 9.384).abs() < 0.01);
+        }
+        assert_eq!(driver.metrics().rounds_completed, 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut first = Backoff::new(1_000, 3);
+        let mut second = Backoff::new(1_000, 3);
+
+        let delays = [first.next_delay(100), second.next_delay(101)];
+        assert_ne!(delays[0], delays[1]);
+        assert!(delays.iter().all(|d| (1_000..2_000).contains(d)));
+
+        // Deterministic per address, growing window
+        assert_eq!(Backoff::new(1_000, 3).next_delay(100), delays[0]);
+        assert!((2_000..4_000).contains(&first.next_delay(100)));
+
+        first.next_delay(100);
+        assert!(first.is_exhausted());
+        first.reset();
+        assert_eq!(first.attempts(), 0);
+    }
+
+    #[test]
+    fn test_backoff_with_rng() {
+        let sequence = |seed: u16| {
+            let mut rng = Xorshift16::new(seed);
+            let mut backoff = Backoff::new(1_000, 3);
+            [(); 3].map(|_| backoff.next_delay_with(&mut rng))
+        };
+
+        let (first, second) = (sequence(1), sequence(2));
+        assert_ne!(first, second);
+        assert_eq!(sequence(1), first);
+        for delays in [first, second] {
+            assert!((1_000..2_000).contains(&delays[0]));
+            assert!((2_000..4_000).contains(&delays[1]));
+            assert!((4_000..8_000).contains(&delays[2]));
+        }
+    }
+
+    #[test]
+    fn test_timeout_backoff() {
+        let mut driver = TagDriver::with_backoff(
+            TagSideStateMachine::new(100, Vec::from_iter([0]), Vec::from_iter([100])),
+            Backoff::new(1_000, 1),
+        );
+
+        assert_eq!(driver.on_timeout(), None);
+
+        assert!(driver.start_round().is_ok());
+        assert!(driver.on_timeout().is_some());
+        assert!(driver.start_round().is_ok());
+        assert_eq!(driver.on_timeout(), None);
+        assert_eq!(driver.metrics().rounds_timed_out, 2);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut driver = TagDriver::new(TagSideStateMachine::new(
+            100,
+            Vec::from_iter([0, 1, 2]),
+            Vec::from_iter([100]),
+        ));
+
+        // A final before any poll is out of order
+        assert!(driver.start_round().is_ok());
+        assert!(driver.on_final(0, 0, 0, 0).is_err());
+
+        // Unknown anchor
+        assert!(driver.on_poll(7, 1_000, 4_000).is_err());
+
+        for anchor in 0..3 {
+            assert!(driver.on_poll(anchor, 1_000, 5_000).is_ok());
+        }
+        assert!(driver.on_response_sent(105_000).is_ok());
+
+        // A late poll is out of order
+        assert!(driver.on_poll(0, 1_000, 5_000).is_err());
+
+        assert_eq!(driver.on_final(0, 103_000, 203_000, 207_000), Ok(None));
+        assert_eq!(driver.on_final(1, 103_000, 203_000, 207_000), Ok(None));
+        let results = driver
+            .on_final(2, 103_000, 203_000, 207_000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(results.participation(), 0b111);
+
+        // The next round times out
+        assert!(driver.start_round().is_ok());
+        assert!(driver.on_poll(1, 1_000, 5_000).is_ok());
+        driver.on_timeout();
+        // No round in progress, not counted again
+        driver.on_timeout();
+
+        assert_eq!(
+            *driver.metrics(),
+            Metrics {
+                polls_dropped: 2,
+                finals_rejected: 1,
+                rounds_completed: 1,
+                rounds_timed_out: 1,
+            }
+        );
+
+        driver.reset_metrics();
+        assert_eq!(*driver.metrics(), Metrics::default());
+    }
+
+    #[test]
+    fn test_metrics_ignored_anchor() {
+        let mut state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0, 1, 2]), Vec::from_iter([100]));
+        state_machine.set_active_anchors(0b011);
+        let mut driver = TagDriver::new(state_machine);
+
+        // The inactive anchor is dropped like an unknown one
+        assert!(driver.start_round().is_ok());
+        assert!(driver.on_poll(2, 1_000, 5_000).is_err());
+        assert!(driver.on_poll(0, 1_000, 5_000).is_ok());
+        assert!(driver.on_poll(1, 1_000, 5_000).is_ok());
+        assert!(driver.on_response_sent(105_000).is_ok());
+
+        assert!(driver.on_final(2, 103_000, 203_000, 207_000).is_err());
+        assert_eq!(driver.on_final(0, 103_000, 203_000, 207_000), Ok(None));
+        let results = driver
+            .on_final(1, 103_000, 203_000, 207_000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(results.participation(), 0b011);
+
+        assert_eq!(
+            *driver.metrics(),
+            Metrics {
+                polls_dropped: 1,
+                finals_rejected: 1,
+                rounds_completed: 1,
+                rounds_timed_out: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_priority_round() {
+        let config = Config::default();
+        let mut schedule = Schedule::new(3, 2);
+        schedule.reserve_priority_slot(&config);
+
+        let mut driver = TagDriver::new(TagSideStateMachine::new(
+            101,
+            Vec::from_iter([0, 1, 2]),
+            Vec::from_iter([100, 101]),
+        ));
+        let regular = driver.tx_slot(&schedule, 1, &config).unwrap();
+        assert_eq!(regular, schedule.ranging_slot(4, &config).unwrap());
+
+        assert_eq!(driver.priority_response(), None);
+
+        driver.request_priority_round();
+        assert_eq!(
+            driver.tx_slot(&schedule, 1, &config),
+            schedule.priority_frame_slot(1, &config)
+        );
+        let response = driver.priority_response().unwrap();
+        assert_eq!(response.packet_type(), PacketType::PriorityResponse);
+        assert_eq!(response.address(), 101);
+
+        // Kept over a failed round, cleared by a completed one
+        assert!(driver.start_round().is_ok());
+        driver.on_timeout();
+        assert!(driver.is_priority_pending());
+
+        assert!(driver.start_round().is_ok());
+        for anchor in 0..3 {
+            assert!(driver.on_poll(anchor, 1_000, 5_000).is_ok());
+        }
+        assert!(driver.on_response_sent(105_000).is_ok());
+        for anchor in 0..3 {
+            assert!(driver.on_final(anchor, 103_000, 203_000, 207_000).is_ok());
+        }
+        assert!(!driver.is_priority_pending());
+        assert_eq!(driver.tx_slot(&schedule, 1, &config), Some(regular));
+        assert_eq!(driver.priority_response(), None);
+    }
+
+    #[test]
+    fn test_fairness_tracker() {
+        let mut tracker = FairnessTracker::new(4);
+        assert_eq!(tracker.jain_index(), 1.0);
+
+        for _ in 0..100 {
+            for tag_idx in 0..4 {
+                tracker.record_round(tag_idx);
+            }
+        }
+        tracker.record_round(2);
+        assert!(tracker.jain_index() > 0.999);
+
+        // Tag 3 starved: 100, 100, 100, 0 gives 3 / 4
+        tracker.reset();
+        for _ in 0..100 {
+            for tag_idx in 0..3 {
+                tracker.record_round(tag_idx);
+            }
+        }
+        assert_eq!(tracker.rounds(), &[100, 100, 100, 0]);
+        assert!((tracker.jain_index() - 0.75).abs() < 1e-6);
+    }
+}
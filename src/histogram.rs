@@ -0,0 +1,75 @@
+// Histogram of the arrival time residuals
+//
+// The residual of a frame is its actual RX timestamp minus the expected one (e.g. from
+// `expected_response_ts`), in device time units. Accumulated over many rounds, a skewed or
+// double-peaked histogram points to systematic multipath or a badly placed antenna rather than
+// random noise.
+
+/// Histogram of timestamp residuals in `BINS` bins of equal width, centered on zero.
+///
+/// Residuals outside the covered range are counted in the outermost bins.
+#[derive(Debug, Clone, PartialEq, Eq, defmt::Format)]
+pub struct ResidualHistogram<const BINS: usize> {
+    /// Width of a bin in device time units
+    bin_width: i64,
+
+    /// Number of residuals recorded per bin, from the most negative up
+    bins: [u32; BINS],
+}
+
+impl<const BINS: usize> ResidualHistogram<BINS> {
+    /// Create a new, empty `ResidualHistogram`.
+    ///
+    /// The bins cover `BINS * bin_width` device time units around zero.
+    /// Will panic if `BINS` or `bin_width` is zero.
+    pub fn new(bin_width: u32) -> Self {
+        assert!(BINS > 0 && bin_width > 0);
+
+        Self {
+            bin_width: bin_width as i64,
+            bins: [0; BINS],
+        }
+    }
+
+    /// Count a residual (actual minus expected timestamp) in device time units.
+    pub fn record(&mut self, residual: i64) {
+        let lowest = -(BINS as i64 * self.bin_width) / 2;
+        let idx = residual
+            .saturating_sub(lowest)
+            .div_euclid(self.bin_width)
+            .clamp(0, BINS as i64 - 1) as usize;
+
+        self.bins[idx] = self.bins[idx].saturating_add(1);
+    }
+
+    /// Counts per bin, from the most negative residuals up.
+    pub fn bins(&self) -> &[u32] {
+        &self.bins
+    }
+
+    /// Forget all recorded residuals.
+    pub fn clear(&mut self) {
+        self.bins = [0; BINS];
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        // Bins [-200, -100), [-100, 0), [0, 100), [100, 200)
+        let mut histogram = ResidualHistogram::<4>::new(100);
+
+        for residual in [-5000, -150, -50, 0, 99, 150, 1000, i64::MIN, i64::MAX] {
+            histogram.record(residual);
+        }
+        assert_eq!(histogram.bins(), &[3, 1, 2, 3]);
+
+        histogram.clear();
+        assert_eq!(histogram.bins(), &[0; 4]);
+    }
+}
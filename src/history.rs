@@ -0,0 +1,241 @@
+// History of recent ranging rounds of a tag
+//
+// A single round says little about the quality of a link; keeping the distances of the last few
+// rounds allows to estimate the jitter of each anchor, e.g. to assess a stationary tag.
+
+use heapless::{Deque, Vec};
+
+use crate::tag_state_machine::RoundResults;
+
+/// Running mean and sample standard deviation, single-pass Welford algorithm
+#[derive(Debug, Clone, Copy, Default)]
+struct Welford {
+    n: u32,
+    mean: f32,
+    m2: f32,
+}
+
+impl Welford {
+    /// Add a sample.
+    fn push(&mut self, x: f32) {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f32;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    /// Sample standard deviation, NaN with fewer than two samples.
+    fn std(&self) -> f32 {
+        if self.n < 2 {
+            return f32::NAN;
+        }
+
+        libm::sqrtf(self.m2 / (self.n - 1) as f32)
+    }
+}
+
+/// Ring of the distances of the last `K` rounds, per anchor (by index).
+#[derive(Debug, Clone, Default)]
+pub struct RoundHistory<const K: usize> {
+    /// Distances of each buffered round in meters, oldest first
+    rounds: Deque<Vec<Option<f32>, 16>, K>,
+}
+
+impl<const K: usize> RoundHistory<K> {
+    /// Create a new, empty `RoundHistory`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the distances of a finished round, dropping the oldest round if the ring is full.
+    pub fn push(&mut self, results: &RoundResults) {
+        self.push_distances(results.distances());
+    }
+
+    /// Add the distances (in meters, `None` if missing) of a round, by anchor index.
+    ///
+    /// Distances beyond the 16th anchor are dropped.
+    pub fn push_distances(&mut self, distances: &[Option<f64>]) {
+        if self.rounds.is_full() {
+            self.rounds.pop_front();
+        }
+        let distances = distances
+            .iter()
+            .take(16)
+            .map(|d| d.map(|d| d as f32))
+            .collect();
+        // Cannot fail, we just made room
+        let _ = self.rounds.push_back(distances);
+    }
+
+    /// Number of buffered rounds.
+    pub fn len(&self) -> usize {
+        self.rounds.len()
+    }
+
+    /// Whether no round is buffered.
+    pub fn is_empty(&self) -> bool {
+        self.rounds.is_empty()
+    }
+
+    /// Sample standard deviation of the distance to each anchor over the buffered rounds, in
+    /// meters.
+    ///
+    /// Over the rounds in which the anchor has a distance. Anchors with fewer than two distances
+    /// yield NaN.
+    pub fn std_per_anchor(&self) -> Vec<f32, 16> {
+        let num_anchors = self.rounds.iter().map(|r| r.len()).max().unwrap_or(0);
+
+        (0..num_anchors)
+            .map(|i| {
+                let mut stats = Welford::default();
+                self.rounds
+                    .iter()
+                    .filter_map(|r| r.get(i).copied().flatten())
+                    .for_each(|distance| stats.push(distance));
+                stats.std()
+            })
+            .collect()
+    }
+}
+
+/// Ranging precision of one anchor over repeated measurements, in meters.
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct PrecisionStat {
+    /// Address of the anchor
+    pub anchor: u16,
+
+    /// Mean distance
+    pub mean: f32,
+
+    /// Sample standard deviation of the distance, NaN with a single measurement
+    pub std: f32,
+
+    /// Largest minus smallest distance
+    pub spread: f32,
+}
+
+/// Per-anchor precision of the rounds of a stationary tag, for acceptance testing.
+///
+/// Every round is a list of (anchor address, distance in meters); an anchor may be missing from
+/// some rounds. The statistics are in order of first appearance of the anchors, and anchors
+/// beyond the 16th are ignored.
+pub fn precision_report(rounds: &[&[(u16, f32)]]) -> Vec<PrecisionStat, 16> {
+    /// Running statistics of one anchor
+    struct Accumulator {
+        anchor: u16,
+        stats: Welford,
+        min: f32,
+        max: f32,
+    }
+
+    let mut accumulators: Vec<Accumulator, 16> = Vec::new();
+    for &(anchor, distance) in rounds.iter().flat_map(|round| round.iter()) {
+        let acc = match accumulators.iter().position(|acc| acc.anchor == anchor) {
+            Some(idx) => &mut accumulators[idx],
+            None => {
+                let acc = Accumulator {
+                    anchor,
+                    stats: Welford::default(),
+                    min: distance,
+                    max: distance,
+                };
+                if accumulators.push(acc).is_err() {
+                    continue;
+                }
+                // Cannot fail, just pushed
+                accumulators.last_mut().unwrap()
+            }
+        };
+
+        acc.stats.push(distance);
+        acc.min = acc.min.min(distance);
+        acc.max = acc.max.max(distance);
+    }
+
+    accumulators
+        .iter()
+        .map(|acc| PrecisionStat {
+            anchor: acc.anchor,
+            mean: acc.stats.mean,
+            std: acc.stats.std(),
+            spread: acc.max - acc.min,
+        })
+        .collect()
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_std_per_anchor() {
+        let mut history = RoundHistory::<4>::new();
+
+        // The oldest round is dropped
+        history.push_distances(&[Some(100.0), Some(9.0), None]);
+        for distance in [4.0, 2.0, 4.0, 6.0] {
+            history.push_distances(&[Some(3.0), Some(distance), Some(1.0)]);
+        }
+        assert_eq!(history.len(), 4);
+
+        let std = history.std_per_anchor();
+        assert!(std[0].abs() < 1e-6);
+        // Mean 4, deviations 0, -2, 0, 2
+        assert!((std[1] - (8.0f32 / 3.0).sqrt()).abs() < 1e-6);
+        assert!(std[2].abs() < 1e-6);
+
+        // Anchor 1 is left with a single distance
+        for _ in 0..3 {
+            history.push_distances(&[Some(3.0), None, Some(1.0)]);
+        }
+        assert!(history.std_per_anchor()[1].is_nan());
+
+        assert!(RoundHistory::<4>::new().std_per_anchor().is_empty());
+    }
+
+    #[test]
+    fn test_push_distances_truncates() {
+        let mut history = RoundHistory::<2>::new();
+        let distances: [Option<f64>; 20] = core::array::from_fn(|i| Some(i as f64));
+        history.push_distances(&distances);
+        history.push_distances(&distances);
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.std_per_anchor().len(), 16);
+    }
+
+    #[test]
+    fn test_precision_report() {
+        // Anchor 0x10 at 5 m with 2 cm of noise, anchor 0x20 at 12 m with 10 cm
+        let noise = |i: usize, level: f32| [level, -level][i % 2];
+        let noisy: [[(u16, f32); 2]; 8] =
+            core::array::from_fn(|i| [(0x10, 5.0 + noise(i, 0.02)), (0x20, 12.0 + noise(i, 0.1))]);
+        let mut rounds: Vec<&[(u16, f32)], 9> = noisy.iter().map(|round| &round[..]).collect();
+        // Anchor 0x30 heard once, in a round without the others
+        rounds.push(&[(0x30, 7.0)]).unwrap();
+
+        let report = precision_report(&rounds);
+        assert_eq!(report.len(), 3);
+
+        assert_eq!(report[0].anchor, 0x10);
+        assert!((report[0].mean - 5.0).abs() < 1e-5);
+        // Sample std over 8 rounds
+        let bessel = (8.0f32 / 7.0).sqrt();
+        assert!((report[0].std - 0.02 * bessel).abs() < 1e-5);
+        assert!((report[0].spread - 0.04).abs() < 1e-5);
+
+        assert_eq!(report[1].anchor, 0x20);
+        assert!((report[1].mean - 12.0).abs() < 1e-5);
+        assert!((report[1].std - 0.1 * bessel).abs() < 1e-5);
+        assert!((report[1].spread - 0.2).abs() < 1e-5);
+
+        assert_eq!(report[2].anchor, 0x30);
+        assert_eq!((report[2].mean, report[2].spread), (7.0, 0.0));
+        assert!(report[2].std.is_nan());
+
+        assert!(precision_report(&[]).is_empty());
+    }
+}
@@ -1,9 +1,103 @@
 #![no_std]
 
 pub mod anchor_state_machine;
+pub mod calibration;
+pub mod channels;
+pub mod driver;
+pub mod histogram;
+pub mod history;
+pub mod link_monitor;
+pub mod localizer;
+pub mod logged;
 pub mod packet;
+pub mod passive_tag;
+pub mod position;
+pub mod schedule;
+pub mod scheduler;
+pub mod survey;
 pub mod tag_state_machine;
 pub mod time_sync;
 pub mod util;
 
 pub mod macros;
+
+/// Protocol phase, shared by the anchor and the tag side.
+///
+/// Maps the side-specific states onto the common three-phase protocol, so a coordinator can
+/// reason about where the whole network is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Phase {
+    /// Anchors send polls to the tags
+    Poll,
+    /// Tags send responses to the anchors
+    Response,
+    /// Anchors send finals to the tags
+    Final,
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// States in which a state machine waits for messages of the current round.
+///
+/// Generic code can abort a round from any of them, see `tag_state_machine::abort` and
+/// `anchor_state_machine::abort`. Sealed, only implemented by the states of this crate.
+pub trait WaitingState: sealed::Sealed {}
+
+/// States in which no round is in progress.
+///
+/// Sealed, only implemented by the states of this crate.
+pub trait TerminalState: sealed::Sealed {}
+
+/// Error when an operation is attempted in a state where it is not valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct WrongPhase;
+
+/// Any error of this crate.
+///
+/// Every module error converts into it, so application code can propagate them all with `?` into
+/// a single `Result<T, magic_loc_protocol::Error>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// A received packet could not be parsed
+    Parse(packet::ParseError),
+    /// A packet could not be encoded
+    Encode(packet::EncodeError),
+    /// An operation was attempted in a state where it is not valid
+    WrongPhase,
+    /// A tag or anchor index was out of range
+    Index(anchor_state_machine::IndexError),
+    /// A schedule does not fit its frame
+    Schedule(schedule::ScheduleError),
+}
+
+impl From<packet::ParseError> for Error {
+    fn from(e: packet::ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<packet::EncodeError> for Error {
+    fn from(e: packet::EncodeError) -> Self {
+        Error::Encode(e)
+    }
+}
+
+impl From<WrongPhase> for Error {
+    fn from(_: WrongPhase) -> Self {
+        Error::WrongPhase
+    }
+}
+
+impl From<anchor_state_machine::IndexError> for Error {
+    fn from(e: anchor_state_machine::IndexError) -> Self {
+        Error::Index(e)
+    }
+}
+
+impl From<schedule::ScheduleError> for Error {
+    fn from(e: schedule::ScheduleError) -> Self {
+        Error::Schedule(e)
+    }
+}
@@ -0,0 +1,90 @@
+// Link quality monitoring
+//
+// A single bad RSSI reading says little, but a steady decline over several rounds points to a
+// degrading link (an obstruction moving in, a tag walking away, a failing antenna). The monitor
+// keeps a short RSSI history per anchor, fed from the RSSI recorded by the tag state machine.
+
+use heapless::Deque;
+
+/// Ring of the last `K` RSSI readings (in dBm) of every anchor.
+#[derive(Debug, Clone)]
+pub struct LinkMonitor<const K: usize> {
+    /// Most recent readings per anchor, oldest first
+    history: [Deque<f32, K>; 16],
+}
+
+impl<const K: usize> Default for LinkMonitor<K> {
+    fn default() -> Self {
+        Self {
+            history: core::array::from_fn(|_| Deque::new()),
+        }
+    }
+}
+
+impl<const K: usize> LinkMonitor<K> {
+    /// Create a new `LinkMonitor` with no readings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a reading for an anchor, dropping its oldest one if the ring is full.
+    ///
+    /// Will panic if `anchor_idx` is not a valid anchor index.
+    pub fn push(&mut self, anchor_idx: usize, rssi: f32) {
+        let history = &mut self.history[anchor_idx];
+        if history.is_full() {
+            history.pop_front();
+        }
+        // Cannot fail, we just made room
+        let _ = history.push_back(rssi);
+    }
+
+    /// Slope of the recent readings of an anchor, in dB per round.
+    ///
+    /// Least-squares fit over the readings in the ring. Negative means the link is degrading.
+    /// Zero with fewer than two readings.
+    pub fn trend(&self, anchor_idx: usize) -> f32 {
+        let history = &self.history[anchor_idx];
+        let n = history.len();
+        if n < 2 {
+            return 0.0;
+        }
+
+        let mean_x = (n - 1) as f32 / 2.0;
+        let mean_y = history.iter().sum::<f32>() / n as f32;
+
+        let (sxy, sxx) = history
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(sxy, sxx), (i, &y)| {
+                let dx = i as f32 - mean_x;
+                (sxy + dx * (y - mean_y), sxx + dx * dx)
+            });
+
+        sxy / sxx
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trend() {
+        let mut monitor = LinkMonitor::<4>::new();
+
+        // Only the last 4 readings count: -80, -82, -84, -86
+        for rssi in [-70.0, -80.0, -82.0, -84.0, -86.0] {
+            monitor.push(1, rssi);
+        }
+        assert!((monitor.trend(1) + 2.0).abs() < 1e-4);
+
+        // Other anchors are unaffected
+        assert_eq!(monitor.trend(0), 0.0);
+
+        monitor.push(0, -75.0);
+        assert_eq!(monitor.trend(0), 0.0);
+    }
+}
@@ -0,0 +1,214 @@
+// Streaming localizer on top of the tag state machine
+//
+// The `Localizer` takes every radio event of the tag as a `ProtocolEvent`, drives the tag state
+// machine through its rounds, and turns every completed round into a `PositionFix` by
+// trilateration against the known anchor positions. It re-arms for the next round by itself.
+
+use heapless::Vec;
+
+use crate::packet::{AnyFinalPacket, PollPacket};
+use crate::position::{pdop, trilaterate, Point};
+use crate::tag_state_machine::{AnyTagSideStateMachine, Idle, TagSideStateMachine};
+use crate::time_sync::TimeSync;
+use crate::util::MAX_TIMESTAMP_INTERVAL;
+
+/// Radio event of the tag, fed to `Localizer::on_event`.
+#[derive(Debug, PartialEq)]
+pub enum ProtocolEvent {
+    /// A poll was received at `rx_ts` (tag time)
+    PollReceived { packet: PollPacket, rx_ts: u64 },
+
+    /// The tag's response was sent at `tx_ts` (tag time)
+    ResponseSent { tx_ts: u64 },
+
+    /// A final from `anchor_addr`, for any number of tags, was received at `rx_ts` (tag time)
+    FinalReceived {
+        anchor_addr: u16,
+        packet: AnyFinalPacket,
+        rx_ts: u64,
+    },
+
+    /// A time sync beacon sent by the root at `root_tx_ts` was received at `local_rx_ts`
+    SyncBeacon { local_rx_ts: u64, root_tx_ts: u64 },
+
+    /// The round timed out, abort it and wait for the next
+    Timeout,
+}
+
+/// Position of the tag at the end of a round.
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct PositionFix {
+    /// Position in meters, in the frame of the anchor positions
+    pub pos: Point,
+
+    /// Time the fix is valid for (see `fix_timestamp`), in network time if `synced` and in tag
+    /// time otherwise
+    pub timestamp: u64,
+
+    /// Whether the clock fit was fresh at the time of the fix, see `Localizer::set_max_sync_age`
+    pub synced: bool,
+
+    /// Position dilution of precision of the anchors used
+    pub pdop: f32,
+}
+
+/// RAM needed by a `Localizer` for `n_anchors` anchors and `n_tags` tags, in bytes.
+///
+/// Covers the tag state machine, the clock fit, the anchor positions and the last fix: their
+/// fixed parts, plus the per-anchor and per-tag entries of their buffers for the given counts
+/// (see `TagSideStateMachine::ANCHOR_FOOTPRINT` and `TAG_FOOTPRINT`). The buffers are allocated
+/// for 16 anchors and 16 tags, so the budget for 16 of each is `size_of::<Localizer>()`, and
+/// smaller counts leave the rest of that capacity unused. Panics for more nodes than that, which
+/// fails the build when evaluated in a const.
+pub const fn memory_budget(n_anchors: usize, n_tags: usize) -> usize {
+    assert!(
+        n_anchors <= 16 && n_tags <= 16,
+        "more nodes than the localizer can hold"
+    );
+
+    let per_anchor = TagSideStateMachine::<Idle>::ANCHOR_FOOTPRINT + core::mem::size_of::<Point>();
+    let per_tag = TagSideStateMachine::<Idle>::TAG_FOOTPRINT;
+
+    core::mem::size_of::<Localizer>() - (16 - n_anchors) * per_anchor - (16 - n_tags) * per_tag
+}
+
+/// Localizer turning the tag's radio events into position fixes.
+#[derive(Debug)]
+pub struct Localizer {
+    /// The tag state machine
+    state_machine: AnyTagSideStateMachine,
+
+    /// Clock fit against the root anchor
+    time_sync: TimeSync,
+
+    /// Age of the last beacon, in device time units, beyond which the clock fit is stale
+    max_sync_age: u64,
+
+    /// Position of each anchor, index-aligned with the anchors of the state machine
+    anchor_positions: Vec<Point, 16>,
+
+    /// Last fix, the starting point of the next trilateration
+    last_fix: Option<PositionFix>,
+}
+
+impl Localizer {
+    /// Create a new `Localizer` from an idle tag state machine and the position of every anchor,
+    /// index-aligned with its anchors. Starts waiting for the polls of the first round.
+    pub fn new(state_machine: TagSideStateMachine<Idle>, anchor_positions: Vec<Point, 16>) -> Self {
+        Self {
+            state_machine: state_machine.waiting_for_anchor_poll().into(),
+            time_sync: TimeSync::new(),
+            max_sync_age: MAX_TIMESTAMP_INTERVAL,
+            anchor_positions,
+            last_fix: None,
+        }
+    }
+
+    /// Get the tag state machine.
+    pub fn state_machine(&self) -> &AnyTagSideStateMachine {
+        &self.state_machine
+    }
+
+    /// Get the clock fit against the root anchor.
+    pub fn time_sync(&self) -> &TimeSync {
+        &self.time_sync
+    }
+
+    /// Timestamp fixes in tag time once the last beacon is older than `max_sync_age` device time
+    /// units, the clock fit having drifted from network time.
+    ///
+    /// Defaults to `MAX_TIMESTAMP_INTERVAL`, the longest interval timestamps can be ordered over.
+    pub fn set_max_sync_age(&mut self, max_sync_age: u64) {
+        self.max_sync_age = max_sync_age;
+    }
+
+    /// The last position fix, if any.
+    pub fn last_fix(&self) -> Option<PositionFix> {
+        self.last_fix
+    }
+
+    /// Advance the protocol with a radio event.
+    ///
+    /// Returns a position fix when the event completes a round and enough anchors took part to
+    /// trilaterate. Events that do not fit the current phase are ignored.
+    pub fn on_event(&mut self, event: ProtocolEvent) -> Option<PositionFix> {
+        match event {
+            ProtocolEvent::PollReceived { packet, rx_ts } => {
+                let state_machine = self.state_machine.as_waiting_for_anchor_poll_mut()?;
+                state_machine.apply_poll_packet(&packet, rx_ts);
+                None
+            }
+            ProtocolEvent::ResponseSent { tx_ts } => {
+                self.state_machine.to_waiting_for_anchor_final().ok()?;
+                self.state_machine.set_response_tx_ts(tx_ts).ok()?;
+                None
+            }
+            ProtocolEvent::FinalReceived {
+                anchor_addr,
+                packet,
+                rx_ts,
+            } => self.on_final(anchor_addr, &packet, rx_ts),
+            ProtocolEvent::SyncBeacon {
+                local_rx_ts,
+                root_tx_ts,
+            } => {
+                self.time_sync.add_beacon(local_rx_ts, root_tx_ts);
+                None
+            }
+            ProtocolEvent::Timeout => {
+                self.restart();
+                None
+            }
+        }
+    }
+
+    /// Record a final, and locate the tag once the round is complete.
+    fn on_final(
+        &mut self,
+        anchor_addr: u16,
+        packet: &AnyFinalPacket,
+        rx_ts: u64,
+    ) -> Option<PositionFix> {
+        let state_machine = self.state_machine.as_waiting_for_anchor_final_mut()?;
+        let anchor_idx = state_machine.anchor_index(anchor_addr)?;
+        state_machine.apply_final_packet(anchor_idx, packet.packet(), rx_ts);
+        if !state_machine.is_complete() {
+            return None;
+        }
+
+        let local_ts = state_machine.fix_timestamp();
+        let (state_machine, results) = core::mem::take(state_machine).idle_with_results();
+        self.state_machine = state_machine.waiting_for_anchor_poll().into();
+
+        let mut anchors: Vec<Point, 16> = Vec::new();
+        let mut distances: Vec<f32, 16> = Vec::new();
+        for (position, distance) in self.anchor_positions.iter().zip(results.distances()) {
+            if let Some(distance) = distance {
+                // Both have room for all 16 anchors
+                let _ = anchors.push(*position);
+                let _ = distances.push(*distance as f32);
+            }
+        }
+
+        let pos = trilaterate(&anchors, &distances, self.last_fix.map(|fix| fix.pos))?;
+        // A stale fit would pass drifted timestamps off as network time
+        let root_ts = self
+            .time_sync
+            .try_local_to_root(local_ts, self.max_sync_age);
+        let fix = PositionFix {
+            pos,
+            timestamp: root_ts.unwrap_or(local_ts),
+            synced: root_ts.is_some(),
+            pdop: pdop(&anchors, pos),
+        };
+        self.last_fix = Some(fix);
+
+        Some(fix)
+    }
+
+    /// Abort the round in progress and wait for the polls of the next.
+    fn restart(&mut self) {
+        let _ = self.state_machine.to_idle();
+        let _ = self.state_machine.to_waiting_for_anchor_poll();
+    }
+}
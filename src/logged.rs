@@ -0,0 +1,206 @@
+// Transition logging for the type-erased state machines
+//
+// `Logged` wraps an `AnyAnchorSideStateMachine` or an `AnyTagSideStateMachine` and offers the same
+// transition methods, reporting every attempt with the protocol phase before and after to a
+// callback. The state machines themselves stay free of logging.
+
+use crate::anchor_state_machine::AnyAnchorSideStateMachine;
+use crate::tag_state_machine::AnyTagSideStateMachine;
+use crate::Phase;
+
+/// Callback reporting a transition attempt: phase before, phase after, and whether it succeeded.
+///
+/// A failed transition leaves the state machine as it was, so both phases are the same.
+pub type TransitionLog = fn(from: Phase, to: Phase, ok: bool);
+
+/// Type-erased state machine that reports every transition to a callback.
+#[derive(Debug)]
+pub struct Logged<M> {
+    inner: M,
+    log: TransitionLog,
+}
+
+impl<M> Logged<M> {
+    /// Wrap a state machine, reporting its transitions to `log`.
+    pub fn new(inner: M, log: TransitionLog) -> Self {
+        Self { inner, log }
+    }
+
+    /// The wrapped state machine.
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// The wrapped state machine, e.g. to record timestamps. Transitions made through it are
+    /// not reported.
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.inner
+    }
+
+    /// Unwrap the state machine.
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// Run a transition and report it.
+    fn logged(
+        &mut self,
+        phase: fn(&M) -> Phase,
+        transition: impl FnOnce(&mut M) -> Result<(), ()>,
+    ) -> Result<(), ()> {
+        let from = phase(&self.inner);
+        let result = transition(&mut self.inner);
+        (self.log)(from, phase(&self.inner), result.is_ok());
+
+        result
+    }
+}
+
+impl Logged<AnyAnchorSideStateMachine> {
+    /// Protocol phase of the current state.
+    pub fn phase(&self) -> Phase {
+        self.inner.phase()
+    }
+
+    /// See `AnyAnchorSideStateMachine::to_waiting_for_response`.
+    pub fn to_waiting_for_response(&mut self, poll_tx_ts: u64) -> Result<(), ()> {
+        self.logged(AnyAnchorSideStateMachine::phase, |m| {
+            m.to_waiting_for_response(poll_tx_ts)
+        })
+    }
+
+    /// See `AnyAnchorSideStateMachine::to_sending_final`.
+    pub fn to_sending_final(&mut self) -> Result<(), ()> {
+        self.logged(AnyAnchorSideStateMachine::phase, |m| m.to_sending_final())
+    }
+
+    /// See `AnyAnchorSideStateMachine::to_idle`.
+    pub fn to_idle(&mut self) -> Result<(), ()> {
+        self.logged(AnyAnchorSideStateMachine::phase, |m| m.to_idle())
+    }
+}
+
+impl Logged<AnyTagSideStateMachine> {
+    /// Protocol phase of the current state.
+    pub fn phase(&self) -> Phase {
+        self.inner.phase()
+    }
+
+    /// See `AnyTagSideStateMachine::to_waiting_for_anchor_poll`.
+    pub fn to_waiting_for_anchor_poll(&mut self) -> Result<(), ()> {
+        self.logged(AnyTagSideStateMachine::phase, |m| {
+            m.to_waiting_for_anchor_poll()
+        })
+    }
+
+    /// See `AnyTagSideStateMachine::to_waiting_for_anchor_final`.
+    pub fn to_waiting_for_anchor_final(&mut self) -> Result<(), ()> {
+        self.logged(AnyTagSideStateMachine::phase, |m| {
+            m.to_waiting_for_anchor_final()
+        })
+    }
+
+    /// See `AnyTagSideStateMachine::to_idle`.
+    pub fn to_idle(&mut self) -> Result<(), ()> {
+        self.logged(AnyTagSideStateMachine::phase, |m| m.to_idle())
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+    use heapless::Vec;
+
+    use crate::anchor_state_machine::AnchorSideStateMachine;
+    use crate::tag_state_machine::TagSideStateMachine;
+
+    /// Transitions reported by a callback, as (from, to, ok) encoded in a byte
+    struct Recorder {
+        entries: [AtomicU8; 8],
+        len: AtomicUsize,
+    }
+
+    impl Recorder {
+        const fn new() -> Self {
+            Self {
+                entries: [const { AtomicU8::new(0) }; 8],
+                len: AtomicUsize::new(0),
+            }
+        }
+
+        fn record(&self, from: Phase, to: Phase, ok: bool) {
+            let idx = self.len.fetch_add(1, Ordering::SeqCst);
+            let entry = (from as u8) << 4 | (to as u8) << 1 | ok as u8;
+            self.entries[idx].store(entry, Ordering::SeqCst);
+        }
+
+        fn entries(&self) -> Vec<(Phase, Phase, bool), 8> {
+            let phase = |bits: u8| [Phase::Poll, Phase::Response, Phase::Final][bits as usize];
+            self.entries[..self.len.load(Ordering::SeqCst)]
+                .iter()
+                .map(|entry| {
+                    let entry = entry.load(Ordering::SeqCst);
+                    (phase(entry >> 4), phase(entry >> 1 & 0b111), entry & 1 == 1)
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_logged_anchor() {
+        static RECORDER: Recorder = Recorder::new();
+
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut logged = Logged::new(state_machine.into(), |from, to, ok| {
+            RECORDER.record(from, to, ok)
+        });
+
+        assert!(logged.to_waiting_for_response(1_000).is_ok());
+        assert!(logged.to_idle().is_err());
+        assert!(logged.to_sending_final().is_ok());
+        assert!(logged.to_idle().is_ok());
+        assert_eq!(logged.phase(), Phase::Poll);
+
+        assert_eq!(
+            RECORDER.entries(),
+            [
+                (Phase::Poll, Phase::Response, true),
+                (Phase::Response, Phase::Response, false),
+                (Phase::Response, Phase::Final, true),
+                (Phase::Final, Phase::Poll, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_logged_tag() {
+        static RECORDER: Recorder = Recorder::new();
+
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut logged = Logged::new(state_machine.into(), |from, to, ok| {
+            RECORDER.record(from, to, ok)
+        });
+
+        assert!(logged.to_waiting_for_anchor_final().is_err());
+        assert!(logged.to_waiting_for_anchor_poll().is_ok());
+        assert!(logged.to_waiting_for_anchor_final().is_ok());
+        assert!(logged.to_idle().is_ok());
+
+        assert_eq!(
+            RECORDER.entries(),
+            [
+                (Phase::Poll, Phase::Poll, false),
+                (Phase::Poll, Phase::Poll, true),
+                (Phase::Poll, Phase::Final, true),
+                (Phase::Final, Phase::Poll, true),
+            ]
+        );
+    }
+}
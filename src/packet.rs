@@ -1,49 +1,126 @@
 use bilge::prelude::*;
 use defmt::Format;
-use zerocopy_derive::{FromBytes, Immutable, IntoBytes, KnownLayout};
+use zerocopy::little_endian::{F64, U16, U32};
+use zerocopy::{ConvertError, FromBytes as _};
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
+
+use crate::schedule::Schedule;
+use crate::time_sync::TimeSync;
+use crate::util::{wrapping_before, wrapping_interval, TIMESTAMP_MASK};
+
+/// Protocol version carried in the upper nibble of every packet header
+///
+/// Bump on every incompatible change of the packet layouts; `parse_packet` rejects packets of
+/// other versions.
+pub const PROTOCOL_VERSION: u4 = u4::new(1);
+
+/// Most tag slots a final packet can carry, one per bit of its validity mask
+pub const MAX_FINAL_SLOTS: usize = 16;
 
 // A poll packet
-#[bitsize(48)]
+#[bitsize(72)]
 #[derive(FromBits, DebugBits, PartialEq)]
 pub struct PollPacket {
     pub packet_type: PacketType,
+    /// Protocol version, see `PROTOCOL_VERSION`
     pub resv: u4,
     pub tx_timestamp: u40,
+    /// Address of the sending anchor
+    pub address: u16,
+    /// Vendor extension byte, free for integrators to carry their own data. This crate never
+    /// interprets it, it only passes it through unchanged.
+    pub extension: u8,
 }
 
 impl Format for PollPacket {
     fn format(&self, f: defmt::Formatter) {
         defmt::write!(
             f,
-            "PollPacket {{ packet_type: {:?}, resv: {:#x}, tx_timestamp: {} }}",
+            "PollPacket {{ packet_type: {:?}, resv: {:#x}, tx_timestamp: {}, address: {}, extension: {:#x} }}",
             self.packet_type(),
             self.resv().value(),
-            self.tx_timestamp().value()
+            self.tx_timestamp().value(),
+            self.address(),
+            self.extension()
         )
     }
 }
 
+impl PollPacket {
+    /// Protocol version of the packet.
+    pub fn version(&self) -> u4 {
+        self.resv()
+    }
+}
+
 // A response packet
-#[bitsize(8)]
+#[bitsize(16)]
 #[derive(FromBits, DebugBits, PartialEq)]
 pub struct ResponsePacket {
     pub packet_type: PacketType,
+    /// Protocol version, see `PROTOCOL_VERSION`
     pub resv: u4,
+    /// Vendor extension byte, passed through uninterpreted (see `PollPacket`)
+    pub extension: u8,
 }
 
 impl Format for ResponsePacket {
     fn format(&self, f: defmt::Formatter) {
         defmt::write!(
             f,
-            "ResponsePacket {{ packet_type: {:?}, resv: {:#x} }}",
+            "ResponsePacket {{ packet_type: {:?}, resv: {:#x}, extension: {:#x} }}",
+            self.packet_type(),
+            self.resv().value(),
+            self.extension(),
+        )
+    }
+}
+
+impl ResponsePacket {
+    /// Protocol version of the packet.
+    pub fn version(&self) -> u4 {
+        self.resv()
+    }
+}
+
+// A priority response, sent instead of the response by a tag with a pending priority request,
+// in the priority slot of the superframe (see `Schedule::reserve_priority_slot`)
+#[bitsize(32)]
+#[derive(FromBits, DebugBits, PartialEq)]
+pub struct PriorityResponsePacket {
+    pub packet_type: PacketType,
+    /// Protocol version, see `PROTOCOL_VERSION`
+    pub resv: u4,
+    /// Address of the sending tag, as the priority slot is not tied to one tag
+    pub address: u16,
+    /// Vendor extension byte, passed through uninterpreted (see `PollPacket`)
+    pub extension: u8,
+}
+
+impl Format for PriorityResponsePacket {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "PriorityResponsePacket {{ packet_type: {:?}, resv: {:#x}, address: {}, extension: {:#x} }}",
             self.packet_type(),
             self.resv().value(),
+            self.address(),
+            self.extension(),
         )
     }
 }
 
+impl PriorityResponsePacket {
+    /// Protocol version of the packet.
+    pub fn version(&self) -> u4 {
+        self.resv()
+    }
+}
+
 // DW3000 40-bit timestamp
-#[derive(Debug, Format, Copy, Clone, PartialEq, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[derive(
+    Debug, Format, Copy, Clone, PartialEq, FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned,
+)]
 #[repr(C)]
 pub struct DeviceTimestamp {
     pub bytes: [u8; 5],
@@ -66,40 +143,580 @@ impl DeviceTimestamp {
 #[derive(FromBits, DebugBits, PartialEq)]
 pub struct PacketHeader {
     pub packet_type: PacketType,
+    /// Protocol version, see `PROTOCOL_VERSION`
     pub resv: u4,
 }
 
-// Final Packet
-#[derive(Debug, Format, Clone, Copy, PartialEq, FromBytes, IntoBytes, Immutable, KnownLayout)]
+impl PacketHeader {
+    /// Protocol version of the packet.
+    pub fn version(&self) -> u4 {
+        self.resv()
+    }
+}
+
+// Final Packet, carrying the response RX timestamps of `N` tags, at most `MAX_FINAL_SLOTS`
+#[derive(Debug, Clone, Copy, PartialEq, FromBytes, IntoBytes, Immutable, KnownLayout)]
 #[repr(C)]
-pub struct FinalPacket {
+pub struct FinalPacket<const N: usize = 3> {
     pub header_byte: u8,
-    pub rx_timestamps: [DeviceTimestamp; 3],
+    /// Bit `i` is set if `rx_timestamps[i]` holds a response the anchor actually received
+    pub valid_mask: U16,
+    pub rx_timestamps: [DeviceTimestamp; N],
     pub tx_timestamp: DeviceTimestamp,
+    /// Vendor extension byte, passed through uninterpreted (see `PollPacket`)
+    pub extension: u8,
+}
+
+impl<const N: usize> Format for FinalPacket<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "FinalPacket {{ header_byte: {:#x}, valid_mask: {:#b}, rx_timestamps: {}, tx_timestamp: {}, extension: {:#x} }}",
+            self.header_byte,
+            self.valid_mask.get(),
+            self.rx_timestamps[..],
+            self.tx_timestamp,
+            self.extension,
+        )
+    }
 }
 
 /// The Final Packet
-impl FinalPacket {
+impl<const N: usize> FinalPacket<N> {
+    /// Fails the build for more slots than the validity mask covers
+    const SLOTS_FIT: () = assert!(
+        N <= MAX_FINAL_SLOTS,
+        "the validity mask covers at most 16 slots"
+    );
+
     pub fn new(
         packet_type: PacketType,
         resv: u4,
-        rx_timestamps: [u40; 3],
+        rx_timestamps: [u40; N],
         tx_timestamp: u40,
     ) -> Self {
+        let () = Self::SLOTS_FIT;
+
         Self {
             header_byte: PacketHeader::new(packet_type, resv).value,
-            rx_timestamps: [
-                DeviceTimestamp::new(rx_timestamps[0]),
-                DeviceTimestamp::new(rx_timestamps[1]),
-                DeviceTimestamp::new(rx_timestamps[2]),
-            ],
+            valid_mask: U16::new((1u32 << N).wrapping_sub(1) as u16),
+            rx_timestamps: rx_timestamps.map(DeviceTimestamp::new),
             tx_timestamp: DeviceTimestamp::new(tx_timestamp),
+            extension: 0,
         }
     }
 
     pub fn header(&self) -> PacketHeader {
         PacketHeader::from(self.header_byte)
     }
+
+    /// Protocol version of the packet.
+    pub fn version(&self) -> u4 {
+        self.header().version()
+    }
+
+    /// Whether slot `slot` carries a response RX timestamp the anchor actually captured.
+    pub fn is_slot_valid(&self, slot: usize) -> bool {
+        let () = Self::SLOTS_FIT;
+
+        slot < N && self.valid_mask.get() & (1 << slot) != 0
+    }
+
+    /// Vendor extension byte, never interpreted by this crate.
+    pub fn extension(&self) -> u8 {
+        self.extension
+    }
+
+    /// Set the vendor extension byte.
+    pub fn set_extension(&mut self, extension: u8) {
+        self.extension = extension;
+    }
+
+    /// Mark slot `slot` as valid or invalid. All slots are valid after `new`.
+    ///
+    /// Slots beyond the `N` of the packet are ignored.
+    pub fn set_slot_valid(&mut self, slot: usize, valid: bool) {
+        let () = Self::SLOTS_FIT;
+        if slot >= N {
+            return;
+        }
+
+        let mask = self.valid_mask.get();
+        self.valid_mask = U16::new(if valid {
+            mask | 1 << slot
+        } else {
+            mask & !(1 << slot)
+        });
+    }
+
+    /// Check that every valid echoed RX timestamp comes after the TX timestamp of `poll`.
+    ///
+    /// All timestamps are in anchor time, and ordered on the wrapping 40-bit counter.
+    pub fn validate_against(&self, poll: &PollPacket) -> bool {
+        let poll_tx = poll.tx_timestamp().value();
+
+        self.rx_timestamps.iter().enumerate().all(|(i, rx)| {
+            if !self.is_slot_valid(i) {
+                return true;
+            }
+
+            wrapping_before(poll_tx, rx.value().value(), 40)
+        })
+    }
+
+    /// Check that the final was sent after every response it echoes.
+    ///
+    /// All timestamps are in anchor time, ordered on the wrapping 40-bit counter. Invalid and
+    /// zero slots are not checked. A cheap sanity check against corrupt or forged finals.
+    pub fn is_temporally_valid(&self) -> bool {
+        let tx = self.tx_timestamp.value().value();
+
+        self.rx_timestamps.iter().enumerate().all(|(i, rx)| {
+            let rx = rx.value().value();
+            if !self.is_slot_valid(i) || rx == 0 {
+                return true;
+            }
+
+            wrapping_before(rx, tx, 40)
+        })
+    }
+}
+
+/// Final packet with any number of tag slots up to `MAX_FINAL_SLOTS`, as parsed from the wire.
+///
+/// Type erasure for `FinalPacket<N>`, whose slot count is only known once a packet arrives. The
+/// slots are held in a `FinalPacket<MAX_FINAL_SLOTS>`, in which the slots beyond `num_slots` are
+/// zero and invalid.
+#[derive(Debug, Format, Clone, Copy, PartialEq)]
+pub struct AnyFinalPacket {
+    packet: FinalPacket<MAX_FINAL_SLOTS>,
+    num_slots: usize,
+}
+
+impl AnyFinalPacket {
+    /// Parse a final packet with as many slots as `buf` holds, checking its type and version.
+    ///
+    /// Validity bits beyond the slots of the packet are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ParseError> {
+        let header = PacketHeader::from(*buf.first().ok_or(ParseError::InvalidLength)?);
+        if header.version() != PROTOCOL_VERSION {
+            return Err(ParseError::VersionMismatch);
+        }
+        if header.packet_type() != PacketType::Final {
+            return Err(ParseError::InvalidPacketType);
+        }
+
+        // Everything but the RX timestamps, see `PacketType::wire_len`
+        let fixed_len = PacketType::Final.wire_len(0);
+        let num_slots = buf.len().saturating_sub(fixed_len) / 5;
+        if buf.len() < fixed_len
+            || buf.len() != PacketType::Final.wire_len(num_slots)
+            || num_slots > MAX_FINAL_SLOTS
+        {
+            return Err(ParseError::InvalidLength);
+        }
+
+        let timestamp = |offset: usize| DeviceTimestamp {
+            bytes: buf[offset..offset + 5].try_into().unwrap(),
+        };
+        let mask =
+            u16::from_le_bytes([buf[1], buf[2]]) & (1u32 << num_slots).wrapping_sub(1) as u16;
+
+        Ok(Self {
+            packet: FinalPacket {
+                header_byte: buf[0],
+                valid_mask: U16::new(mask),
+                rx_timestamps: core::array::from_fn(|slot| match slot < num_slots {
+                    true => timestamp(3 + 5 * slot),
+                    false => DeviceTimestamp { bytes: [0; 5] },
+                }),
+                tx_timestamp: timestamp(3 + 5 * num_slots),
+                extension: buf[buf.len() - 1],
+            },
+            num_slots,
+        })
+    }
+
+    /// Parse a compact final packet with as many slots as `buf` holds, and decode it into the
+    /// full final, checking its type and version.
+    ///
+    /// Validity bits beyond the slots of the packet are ignored.
+    pub fn from_compact_bytes(buf: &[u8]) -> Result<Self, ParseError> {
+        let header = PacketHeader::from(*buf.first().ok_or(ParseError::InvalidLength)?);
+        if header.version() != PROTOCOL_VERSION {
+            return Err(ParseError::VersionMismatch);
+        }
+        if header.packet_type() != PacketType::CompactFinal {
+            return Err(ParseError::InvalidPacketType);
+        }
+
+        // Everything but the RX deltas, see `PacketType::wire_len`
+        let fixed_len = PacketType::CompactFinal.wire_len(0);
+        let num_slots = buf.len().saturating_sub(fixed_len) / 4;
+        if buf.len() < fixed_len
+            || buf.len() != PacketType::CompactFinal.wire_len(num_slots)
+            || num_slots > MAX_FINAL_SLOTS
+        {
+            return Err(ParseError::InvalidLength);
+        }
+
+        let mask =
+            u16::from_le_bytes([buf[1], buf[2]]) & (1u32 << num_slots).wrapping_sub(1) as u16;
+        let compact = CompactFinalPacket::<MAX_FINAL_SLOTS> {
+            header_byte: buf[0],
+            valid_mask: U16::new(mask),
+            tx_timestamp: DeviceTimestamp {
+                bytes: buf[3..8].try_into().unwrap(),
+            },
+            rx_deltas: core::array::from_fn(|slot| match slot < num_slots {
+                true => U32::from_bytes(buf[8 + 4 * slot..12 + 4 * slot].try_into().unwrap()),
+                false => U32::ZERO,
+            }),
+            extension: buf[buf.len() - 1],
+        };
+
+        Ok(Self {
+            packet: compact.decode(),
+            num_slots,
+        })
+    }
+
+    /// Number of tag slots of the packet.
+    pub fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+
+    /// The packet, with the slots beyond `num_slots` zero and invalid.
+    pub fn packet(&self) -> &FinalPacket<MAX_FINAL_SLOTS> {
+        &self.packet
+    }
+
+    /// The packet with its actual slot count, `None` if it does not have `N` slots.
+    pub fn to_fixed<const N: usize>(&self) -> Option<FinalPacket<N>> {
+        if N != self.num_slots {
+            return None;
+        }
+
+        let mut packet = FinalPacket::new(
+            PacketType::Final,
+            u4::new(0),
+            core::array::from_fn(|slot| self.packet.rx_timestamps[slot].value()),
+            self.packet.tx_timestamp.value(),
+        );
+        packet.header_byte = self.packet.header_byte;
+        packet.valid_mask = self.packet.valid_mask;
+        packet.extension = self.packet.extension;
+        Some(packet)
+    }
+}
+
+impl<const N: usize> From<FinalPacket<N>> for AnyFinalPacket {
+    fn from(packet: FinalPacket<N>) -> Self {
+        let () = FinalPacket::<N>::SLOTS_FIT;
+
+        Self {
+            packet: FinalPacket {
+                header_byte: packet.header_byte,
+                valid_mask: packet.valid_mask,
+                rx_timestamps: core::array::from_fn(|slot| match slot < N {
+                    true => packet.rx_timestamps[slot],
+                    false => DeviceTimestamp { bytes: [0; 5] },
+                }),
+                tx_timestamp: packet.tx_timestamp,
+                extension: packet.extension,
+            },
+            num_slots: N,
+        }
+    }
+}
+
+/// Delta-encoded final packet for `N` tags: 4 instead of 5 bytes per tag slot.
+///
+/// Carries the final TX timestamp in full, and each response RX timestamp as its distance
+/// before the TX timestamp, in 32 bits. That spans ~67 ms of device time, longer than any TDMA
+/// frame, so the responses of the whole round fit. Invalid slots must hold zero and are sent as
+/// zero. Parsed by `parse_packet` straight into the full final, see
+/// `AnyFinalPacket::from_compact_bytes`.
+#[derive(
+    Debug, Clone, Copy, PartialEq, FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned,
+)]
+#[repr(C)]
+pub struct CompactFinalPacket<const N: usize = 3> {
+    pub header_byte: u8,
+    /// Bit `i` is set if slot `i` holds a response the anchor actually received
+    pub valid_mask: U16,
+    pub tx_timestamp: DeviceTimestamp,
+    /// Distance of each RX timestamp before the TX timestamp
+    pub rx_deltas: [U32; N],
+    /// Vendor extension byte, passed through uninterpreted (see `PollPacket`)
+    pub extension: u8,
+}
+
+// Manual impl, the little-endian fields do not implement `Format`
+impl<const N: usize> Format for CompactFinalPacket<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "CompactFinalPacket {{ header_byte: {:#x}, valid_mask: {:#b}, tx_timestamp: {}, rx_deltas: [",
+            self.header_byte,
+            self.valid_mask.get(),
+            self.tx_timestamp,
+        );
+        for (i, delta) in self.rx_deltas.iter().enumerate() {
+            match i {
+                0 => defmt::write!(f, "{}", delta.get()),
+                _ => defmt::write!(f, ", {}", delta.get()),
+            }
+        }
+        defmt::write!(f, "], extension: {:#x} }}", self.extension);
+    }
+}
+
+/// Error when a final packet cannot be delta-encoded, see `CompactFinalPacket::encode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum EncodeError {
+    /// The valid RX timestamp of the slot lies more than `u32::MAX` device time units before the
+    /// TX timestamp, or after it
+    DeltaOverflow { slot: usize },
+    /// The invalid slot holds a timestamp other than zero, which would be lost
+    InvalidSlotNotZero { slot: usize },
+}
+
+impl<const N: usize> CompactFinalPacket<N> {
+    /// Encode a final packet losslessly.
+    pub fn encode(packet: &FinalPacket<N>) -> Result<Self, EncodeError> {
+        let tx = packet.tx_timestamp.value().value();
+
+        let mut rx_deltas = [U32::ZERO; N];
+        for (slot, (delta, rx)) in rx_deltas.iter_mut().zip(&packet.rx_timestamps).enumerate() {
+            let rx = rx.value().value();
+            *delta = U32::new(if packet.is_slot_valid(slot) {
+                u32::try_from(wrapping_interval(rx, tx))
+                    .map_err(|_| EncodeError::DeltaOverflow { slot })?
+            } else if rx == 0 {
+                0
+            } else {
+                return Err(EncodeError::InvalidSlotNotZero { slot });
+            });
+        }
+
+        Ok(Self {
+            header_byte: PacketHeader::new(PacketType::CompactFinal, packet.version()).value,
+            valid_mask: packet.valid_mask,
+            tx_timestamp: packet.tx_timestamp,
+            rx_deltas,
+            extension: packet.extension,
+        })
+    }
+
+    /// Decode into the full final packet.
+    pub fn decode(&self) -> FinalPacket<N> {
+        let tx = self.tx_timestamp.value().value();
+        let version = PacketHeader::from(self.header_byte).version();
+
+        FinalPacket {
+            header_byte: PacketHeader::new(PacketType::Final, version).value,
+            valid_mask: self.valid_mask,
+            rx_timestamps: core::array::from_fn(|slot| {
+                if self.valid_mask.get() & (1 << slot) == 0 {
+                    return DeviceTimestamp::new(u40::new(0));
+                }
+
+                let delta = self.rx_deltas[slot].get() as u64;
+                DeviceTimestamp::new(u40::new(tx.wrapping_sub(delta) & TIMESTAMP_MASK))
+            }),
+            tx_timestamp: self.tx_timestamp,
+            extension: self.extension,
+        }
+    }
+
+    /// Vendor extension byte, never interpreted by this crate.
+    pub fn extension(&self) -> u8 {
+        self.extension
+    }
+
+    /// Set the vendor extension byte.
+    pub fn set_extension(&mut self, extension: u8) {
+        self.extension = extension;
+    }
+}
+
+/// Bootstrap packet, handing the TDMA schedule and the clock skew to a joining node in one frame.
+///
+/// All multi-byte fields are little-endian.
+#[derive(
+    Debug, Clone, Copy, PartialEq, FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned,
+)]
+#[repr(C)]
+pub struct BootstrapPacket {
+    pub header_byte: u8,
+    /// Vendor extension byte, passed through uninterpreted (see `PollPacket`)
+    pub extension: u8,
+    pub _reserved: [u8; 2],
+    pub num_anchors: U16,
+    pub num_tags: U16,
+    /// Idle time after every frame in nanoseconds
+    pub inter_frame_idle: U32,
+    /// Duration of the TDMA frame in nanoseconds, zero if unbounded
+    pub frame_duration: U32,
+    /// Length of the beacon slot in nanoseconds, zero without beacons
+    pub beacon_duration: U32,
+    /// Length of the priority contention slot in nanoseconds, zero without priority rounds
+    pub priority_slot_duration: U32,
+    /// Relative frequency error of the root clock against the sender's clock
+    pub skew: F64,
+}
+
+// Manual impl, the little-endian fields do not implement `Format`
+impl Format for BootstrapPacket {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "BootstrapPacket {{ header_byte: {:#x}, extension: {:#x}, num_anchors: {}, num_tags: {}, inter_frame_idle: {}, frame_duration: {}, beacon_duration: {}, priority_slot_duration: {}, skew: {} }}",
+            self.header_byte,
+            self.extension,
+            self.num_anchors.get(),
+            self.num_tags.get(),
+            self.inter_frame_idle.get(),
+            self.frame_duration.get(),
+            self.beacon_duration.get(),
+            self.priority_slot_duration.get(),
+            self.skew.get(),
+        )
+    }
+}
+
+/// Build a bootstrap packet from a node's schedule and sync state.
+pub fn build_bootstrap(schedule: &Schedule, sync: &TimeSync) -> BootstrapPacket {
+    BootstrapPacket {
+        header_byte: PacketHeader::new(PacketType::Bootstrap, PROTOCOL_VERSION).value,
+        extension: 0,
+        _reserved: [0; 2],
+        num_anchors: U16::new(schedule.num_anchors as u16),
+        num_tags: U16::new(schedule.num_tags as u16),
+        inter_frame_idle: U32::new(schedule.inter_frame_idle),
+        frame_duration: U32::new(schedule.frame_duration.unwrap_or(0)),
+        beacon_duration: U32::new(schedule.beacon_duration),
+        priority_slot_duration: U32::new(schedule.priority_slot_duration),
+        skew: F64::new(sync.skew_ppm() * 1e-6),
+    }
+}
+
+impl BootstrapPacket {
+    /// Vendor extension byte, never interpreted by this crate.
+    pub fn extension(&self) -> u8 {
+        self.extension
+    }
+
+    /// Set the vendor extension byte.
+    pub fn set_extension(&mut self, extension: u8) {
+        self.extension = extension;
+    }
+}
+
+/// Schedule and sync state of a node joining from a bootstrap packet.
+///
+/// The sender's offset to the root is of no use on another clock, so the joining node stays
+/// unsynced until its first root beacon. The sender's skew is taken as the prior of the fit
+/// (see `TimeSync::set_skew_prior`), so that first beacon is enough to follow the root clock.
+pub fn join_from_bootstrap(packet: &BootstrapPacket) -> (Schedule, TimeSync) {
+    let mut schedule = Schedule::new(
+        packet.num_anchors.get() as usize,
+        packet.num_tags.get() as usize,
+    );
+    schedule.inter_frame_idle = packet.inter_frame_idle.get();
+    schedule.frame_duration = match packet.frame_duration.get() {
+        0 => None,
+        duration => Some(duration),
+    };
+    schedule.beacon_duration = packet.beacon_duration.get();
+    schedule.priority_slot_duration = packet.priority_slot_duration.get();
+
+    let mut sync = TimeSync::new();
+    sync.set_skew_prior(packet.skew.get());
+
+    (schedule, sync)
+}
+
+/// Error when parsing a packet from a buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ParseError {
+    /// The buffer length does not match the packet size
+    InvalidLength,
+    /// The buffer is not aligned for the packet type
+    Misaligned,
+    /// The header carries an unexpected packet type
+    InvalidPacketType,
+    /// The header carries another protocol version than `PROTOCOL_VERSION`
+    VersionMismatch,
+}
+
+/// A parsed packet of any type
+#[derive(Debug, PartialEq, Format)]
+pub enum Packet {
+    Poll(PollPacket),
+    Response(ResponsePacket),
+    PriorityResponse(PriorityResponsePacket),
+    Final(AnyFinalPacket),
+    Bootstrap(BootstrapPacket),
+}
+
+/// Parse a received packet of any type, checking its protocol version.
+///
+/// Compact finals are decoded into `Packet::Final`, like the finals they encode.
+pub fn parse_packet(buf: &[u8]) -> Result<Packet, ParseError> {
+    let header = PacketHeader::from(*buf.first().ok_or(ParseError::InvalidLength)?);
+    if header.version() != PROTOCOL_VERSION {
+        return Err(ParseError::VersionMismatch);
+    }
+
+    let packet_type = header.packet_type();
+    // Reserved types have no length to check and are rejected below, finals have their own
+    let fixed_len = !matches!(
+        packet_type,
+        PacketType::Reserved | PacketType::Final | PacketType::CompactFinal
+    );
+    if fixed_len && buf.len() != packet_type.wire_len(0) {
+        return Err(ParseError::InvalidLength);
+    }
+
+    match packet_type {
+        PacketType::Poll => Ok(Packet::Poll(PollPacket::from(u72::from_le_bytes(
+            buf.try_into().map_err(|_| ParseError::InvalidLength)?,
+        )))),
+        PacketType::Response => Ok(Packet::Response(ResponsePacket::from(u16::from_le_bytes(
+            buf.try_into().map_err(|_| ParseError::InvalidLength)?,
+        )))),
+        PacketType::PriorityResponse => Ok(Packet::PriorityResponse(PriorityResponsePacket::from(
+            u32::from_le_bytes(buf.try_into().map_err(|_| ParseError::InvalidLength)?),
+        ))),
+        PacketType::Final => AnyFinalPacket::from_bytes(buf).map(Packet::Final),
+        PacketType::CompactFinal => AnyFinalPacket::from_compact_bytes(buf).map(Packet::Final),
+        PacketType::Bootstrap => BootstrapPacket::read_from_bytes(buf)
+            .map(Packet::Bootstrap)
+            .map_err(|_| ParseError::InvalidLength),
+        PacketType::Reserved => Err(ParseError::InvalidPacketType),
+    }
+}
+
+/// Reinterpret `buf` as a `FinalPacket` with `N` slots in place, without copying.
+///
+/// Useful to parse a final directly out of the radio's RX buffer when the number of tags is
+/// known. Otherwise, see `AnyFinalPacket::from_bytes`.
+pub fn final_packet_ref<const N: usize>(buf: &[u8]) -> Result<&FinalPacket<N>, ParseError> {
+    let packet = FinalPacket::ref_from_bytes(buf).map_err(|e| match e {
+        ConvertError::Alignment(_) => ParseError::Misaligned,
+        ConvertError::Size(_) => ParseError::InvalidLength,
+    })?;
+
+    if packet.version() != PROTOCOL_VERSION {
+        return Err(ParseError::VersionMismatch);
+    }
+
+    match packet.header().packet_type() {
+        PacketType::Final => Ok(packet),
+        _ => Err(ParseError::InvalidPacketType),
+    }
 }
 
 /// Packet Type
@@ -109,10 +726,123 @@ pub enum PacketType {
     Poll = 0,
     Response = 1,
     Final = 2,
+    Bootstrap = 3,
+    CompactFinal = 4,
+    PriorityResponse = 5,
     #[fallback]
     Reserved,
 }
 
+impl PacketType {
+    /// Serialized size in bytes of a packet of this type.
+    ///
+    /// `num_tags` is the number of tag slots of a final packet, and ignored for the other types.
+    /// Reserved packet types have no defined layout and report zero.
+    pub fn wire_len(self, num_tags: usize) -> usize {
+        match self {
+            PacketType::Poll => 9,
+            PacketType::Response => 2,
+            PacketType::PriorityResponse => 4,
+            // Header, 16-bit validity mask, one RX timestamp per tag, the TX timestamp, and the
+            // extension byte
+            PacketType::Final => 4 + 5 * (num_tags + 1),
+            // Same with a 32-bit delta instead of each RX timestamp
+            PacketType::CompactFinal => 9 + 4 * num_tags,
+            PacketType::Bootstrap => core::mem::size_of::<BootstrapPacket>(),
+            PacketType::Reserved => 0,
+        }
+    }
+}
+
+/// Canonical (bytes, decoded) pairs of every packet type.
+///
+/// Shared by this crate's tests and by third-party implementations validating their encoders.
+#[cfg(any(test, feature = "test-vectors"))]
+pub mod test_vectors {
+    use super::*;
+
+    /// A poll sent at 0x12356789 by anchor 0x0105
+    pub const POLL_VECTOR: (&[u8], PollPacket) = (
+        &[0x10, 0x89, 0x67, 0x35, 0x12, 0x00, 0x05, 0x01, 0x00],
+        PollPacket {
+            value: u72::new(0x00_0105_0012_3567_8910),
+        },
+    );
+
+    /// A response
+    pub const RESPONSE_VECTOR: (&[u8], ResponsePacket) =
+        (&[0x11, 0x00], ResponsePacket { value: 0x0011 });
+
+    /// A priority response by tag 0x0065
+    pub const PRIORITY_RESPONSE_VECTOR: (&[u8], PriorityResponsePacket) = (
+        &[0x15, 0x65, 0x00, 0x00],
+        PriorityResponsePacket { value: 0x0000_6515 },
+    );
+
+    /// A final for 3 tags, with tag 1 not heard, sent at 0xDEADBEEF
+    pub const FINAL_VECTOR: (&[u8], FinalPacket<3>) = (
+        &[
+            0x12, 0x05, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xbc,
+            0x9a, 0x00, 0x00, 0x00, 0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,
+        ],
+        FinalPacket {
+            header_byte: 0x12,
+            valid_mask: U16::new(0b101),
+            rx_timestamps: [
+                DeviceTimestamp {
+                    bytes: [0x34, 0x12, 0x00, 0x00, 0x00],
+                },
+                DeviceTimestamp { bytes: [0; 5] },
+                DeviceTimestamp {
+                    bytes: [0xbc, 0x9a, 0x00, 0x00, 0x00],
+                },
+            ],
+            tx_timestamp: DeviceTimestamp {
+                bytes: [0xEF, 0xBE, 0xAD, 0xDE, 0x00],
+            },
+            extension: 0,
+        },
+    );
+
+    /// `FINAL_VECTOR` in its compact encoding
+    pub const COMPACT_FINAL_VECTOR: (&[u8], CompactFinalPacket<3>) = (
+        &[
+            0x14, 0x05, 0x00, 0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0xBB, 0xAC, 0xAD, 0xDE, 0x00, 0x00,
+            0x00, 0x00, 0x33, 0x24, 0xAD, 0xDE, 0x00,
+        ],
+        CompactFinalPacket {
+            header_byte: 0x14,
+            valid_mask: U16::new(0b101),
+            tx_timestamp: DeviceTimestamp {
+                bytes: [0xEF, 0xBE, 0xAD, 0xDE, 0x00],
+            },
+            rx_deltas: [U32::new(0xDEAD_ACBB), U32::new(0), U32::new(0xDEAD_2433)],
+            extension: 0,
+        },
+    );
+
+    /// A bootstrap for 3 anchors and 3 tags, with beacon and priority slots and a skew of -5 ppm
+    pub const BOOTSTRAP_VECTOR: (&[u8], BootstrapPacket) = (
+        &[
+            0x13, 0x00, 0x00, 0x00, 0x03, 0x00, 0x03, 0x00, 0xa0, 0x86, 0x01, 0x00, 0x80, 0x96,
+            0x98, 0x00, 0x80, 0x1a, 0x06, 0x00, 0xe0, 0xc8, 0x10, 0x00, 0xf1, 0x68, 0xe3, 0x88,
+            0xb5, 0xf8, 0xd4, 0xbe,
+        ],
+        BootstrapPacket {
+            header_byte: 0x13,
+            extension: 0,
+            _reserved: [0; 2],
+            num_anchors: U16::new(3),
+            num_tags: U16::new(3),
+            inter_frame_idle: U32::new(100_000),
+            frame_duration: U32::new(10_000_000),
+            beacon_duration: U32::new(400_000),
+            priority_slot_duration: U32::new(1_100_000),
+            skew: F64::from_bytes([0xf1, 0x68, 0xe3, 0x88, 0xb5, 0xf8, 0xd4, 0xbe]),
+        },
+    );
+}
+
 // Tests
 #[cfg(test)]
 mod tests {
@@ -122,21 +852,29 @@ mod tests {
 
     #[test]
     fn test_poll_packet() {
-        let poll_packet =
-            PollPacket::new(PacketType::Poll, u4::new(0), u40::new(0x12356789).into());
+        let poll_packet = PollPacket::new(
+            PacketType::Poll,
+            u4::new(0),
+            u40::new(0x12356789).into(),
+            5,
+            0,
+        );
 
         let poll_packet_bytes = poll_packet.value.to_le_bytes();
 
-        assert_eq!(poll_packet_bytes, [0x00, 0x89, 0x67, 0x35, 0x12, 0x00]);
+        assert_eq!(
+            poll_packet_bytes,
+            [0x00, 0x89, 0x67, 0x35, 0x12, 0x00, 0x05, 0x00, 0x00]
+        );
     }
 
     #[test]
     fn test_response_packet() {
-        let response_packet = ResponsePacket::new(PacketType::Response, u4::new(0));
+        let response_packet = ResponsePacket::new(PacketType::Response, u4::new(0), 0);
 
         let response_packet_bytes = response_packet.value.to_le_bytes();
 
-        assert_eq!(response_packet_bytes, [0x1]);
+        assert_eq!(response_packet_bytes, [0x1, 0x0]);
     }
 
     #[test]
@@ -159,14 +897,14 @@ mod tests {
         assert_eq!(
             final_packet_bytes,
             [
-                0x02, 0x89, 0x67, 0x35, 0x12, 0x00, 0x89, 0x67, 0x35, 0x12, 0x00, 0x89, 0x67, 0x35,
-                0x12, 0x00, 0xEF, 0xBE, 0xAD, 0xDE, 0x00
+                0x02, 0x07, 0x00, 0x89, 0x67, 0x35, 0x12, 0x00, 0x89, 0x67, 0x35, 0x12, 0x00, 0x89,
+                0x67, 0x35, 0x12, 0x00, 0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00
             ]
         );
 
-        assert_eq!(final_packet_bytes[1..6], ts_bytes);
+        assert_eq!(final_packet_bytes[3..8], ts_bytes);
 
-        let mut some_bytes: [u8; 21] = [0; 21];
+        let mut some_bytes: [u8; 24] = [0; 24];
 
         // copy from final_packet_bytes
         some_bytes.copy_from_slice(&final_packet_bytes[..]);
@@ -176,6 +914,465 @@ mod tests {
         assert_eq!(transmuted, final_packet);
     }
 
+    #[test]
+    fn test_compact_final_packet() {
+        // Responses across the whole round before the final, which is sent across the 40-bit
+        // rollover
+        let mut packet = FinalPacket::new(
+            PacketType::Final,
+            PROTOCOL_VERSION,
+            [
+                u40::new(0xFF_FFC0_0000),
+                u40::new(0),
+                u40::new(0xFF_FFFF_FF00),
+            ],
+            u40::new(0x0200),
+        );
+        packet.set_slot_valid(1, false);
+
+        let compact = CompactFinalPacket::encode(&packet).unwrap();
+        assert_eq!(
+            compact.as_bytes().len(),
+            PacketType::CompactFinal.wire_len(3)
+        );
+        assert_eq!(compact.as_bytes().len(), 21);
+        assert_eq!(compact.decode(), packet);
+
+        // Parsed as the final it encodes
+        assert_eq!(
+            parse_packet(compact.as_bytes()),
+            Ok(Packet::Final(packet.into()))
+        );
+
+        // A response too long before the final does not fit
+        packet.rx_timestamps[2] = DeviceTimestamp::new(u40::new(0xFE_FFFF_0000));
+        assert_eq!(
+            CompactFinalPacket::encode(&packet),
+            Err(EncodeError::DeltaOverflow { slot: 2 })
+        );
+
+        // Nor does an invalid slot with a timestamp
+        packet.rx_timestamps[2] = DeviceTimestamp::new(u40::new(0xFF_FFFF_FF00));
+        packet.rx_timestamps[1] = DeviceTimestamp::new(u40::new(0x0100));
+        assert_eq!(
+            CompactFinalPacket::encode(&packet),
+            Err(EncodeError::InvalidSlotNotZero { slot: 1 })
+        );
+    }
+
+    #[test]
+    fn test_parse_compact_final_any_slots() {
+        fn check<const N: usize>() {
+            let mut packet = FinalPacket::<N>::new(
+                PacketType::Final,
+                PROTOCOL_VERSION,
+                core::array::from_fn(|i| u40::new(0x10_0000 + 0x1000 * i as u64)),
+                u40::new(0x90_0000),
+            );
+            packet.set_slot_valid(0, false);
+            packet.rx_timestamps[0] = DeviceTimestamp::new(u40::new(0));
+            packet.set_extension(0xa5);
+
+            let compact = CompactFinalPacket::encode(&packet).unwrap();
+            let Ok(Packet::Final(parsed)) = parse_packet(compact.as_bytes()) else {
+                panic!("compact final with {} slots not parsed", N);
+            };
+            assert_eq!(parsed, AnyFinalPacket::from(packet));
+        }
+        check::<1>();
+        check::<5>();
+        check::<16>();
+
+        // Not a whole number of slots
+        let packet = FinalPacket::<3>::new(
+            PacketType::Final,
+            PROTOCOL_VERSION,
+            [u40::new(0); 3],
+            u40::new(0),
+        );
+        let compact = CompactFinalPacket::encode(&packet).unwrap();
+        assert_eq!(
+            parse_packet(&compact.as_bytes()[..20]),
+            Err(ParseError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn test_final_validate_against_poll() {
+        let poll = PollPacket::new(PacketType::Poll, u4::new(0), u40::new(0xFF_FFFF_F000), 0, 0);
+
+        // Response RX timestamps wrap around the 40-bit counter
+        let valid = FinalPacket::new(
+            PacketType::Final,
+            u4::new(0),
+            [u40::new(0xFF_FFFF_F800), u40::new(0x100), u40::new(0x800)],
+            u40::new(0x1000),
+        );
+        assert!(valid.validate_against(&poll));
+
+        let scrambled = FinalPacket::new(
+            PacketType::Final,
+            u4::new(0),
+            [u40::new(0x100), u40::new(0xFF_FFFF_E000), u40::new(0x800)],
+            u40::new(0x1000),
+        );
+        assert!(!scrambled.validate_against(&poll));
+
+        // Invalid slots are not checked
+        let mut partial = scrambled;
+        partial.set_slot_valid(0, false);
+        assert!(!partial.validate_against(&poll));
+        partial.set_slot_valid(1, false);
+        assert!(partial.validate_against(&poll));
+        assert_eq!(partial.valid_mask.get(), 0b100);
+        assert!(!partial.is_slot_valid(3));
+    }
+
+    #[test]
+    fn test_final_packet_16_slots() {
+        let mut packet = FinalPacket::<16>::new(
+            PacketType::Final,
+            PROTOCOL_VERSION,
+            core::array::from_fn(|i| u40::new(0x100 * i as u64)),
+            u40::new(0x2000),
+        );
+        assert_eq!(packet.valid_mask.get(), 0xffff);
+
+        packet.set_slot_valid(15, false);
+        packet.set_slot_valid(9, false);
+        // Beyond the packet, ignored
+        packet.set_slot_valid(16, false);
+        assert_eq!(packet.valid_mask.get(), 0x7dff);
+        assert!(packet.is_slot_valid(8));
+        assert!(!packet.is_slot_valid(15));
+        assert!(!packet.is_slot_valid(16));
+
+        assert_eq!(packet.as_bytes().len(), PacketType::Final.wire_len(16));
+    }
+
+    #[test]
+    fn test_is_temporally_valid() {
+        // Responses on both sides of the 40-bit rollover, final after them
+        let valid = FinalPacket::new(
+            PacketType::Final,
+            u4::new(0),
+            [u40::new(0xFF_FFFF_F800), u40::new(0), u40::new(0x800)],
+            u40::new(0x1000),
+        );
+        assert!(valid.is_temporally_valid());
+
+        let mut early = FinalPacket::new(
+            PacketType::Final,
+            u4::new(0),
+            [u40::new(0x100), u40::new(0x2000), u40::new(0x800)],
+            u40::new(0x1000),
+        );
+        assert!(!early.is_temporally_valid());
+
+        // Unless the late response is marked invalid
+        early.set_slot_valid(1, false);
+        assert!(early.is_temporally_valid());
+    }
+
+    #[test]
+    fn test_final_packet_ref() {
+        let final_packet = FinalPacket::new(
+            PacketType::Final,
+            PROTOCOL_VERSION,
+            [u40::new(0x1234), u40::new(0x5678), u40::new(0x9abc)],
+            u40::new(0xDEADBEEF),
+        );
+
+        let mut buf = [0u8; 25];
+        buf[..24].copy_from_slice(final_packet.as_bytes());
+
+        let transmuted: FinalPacket = transmute!(<[u8; 24]>::try_from(&buf[..24]).unwrap());
+        let borrowed = final_packet_ref(&buf[..24]).unwrap();
+
+        assert_eq!(*borrowed, transmuted);
+        assert_eq!(borrowed.rx_timestamps[2].value(), u40::new(0x9abc));
+        assert_eq!(borrowed.tx_timestamp.value(), u40::new(0xDEADBEEF));
+
+        assert_eq!(final_packet_ref::<3>(&buf), Err(ParseError::InvalidLength));
+        assert_eq!(
+            final_packet_ref::<3>(&buf[..23]),
+            Err(ParseError::InvalidLength)
+        );
+
+        buf[0] = PacketHeader::new(PacketType::Poll, PROTOCOL_VERSION).value;
+        assert_eq!(
+            final_packet_ref::<3>(&buf[..24]),
+            Err(ParseError::InvalidPacketType)
+        );
+    }
+
+    #[test]
+    fn test_wire_len() {
+        let poll = PollPacket::new(PacketType::Poll, u4::new(0), u40::new(0), 0, 0);
+        assert_eq!(PacketType::Poll.wire_len(3), poll.value.to_le_bytes().len());
+
+        let response = ResponsePacket::new(PacketType::Response, u4::new(0), 0);
+        assert_eq!(
+            PacketType::Response.wire_len(3),
+            response.value.to_le_bytes().len()
+        );
+
+        assert_eq!(PacketType::Final.wire_len(3), 24);
+        assert_eq!(
+            PacketType::Final.wire_len(3),
+            core::mem::size_of::<FinalPacket<3>>()
+        );
+        assert_eq!(
+            PacketType::Final.wire_len(8),
+            core::mem::size_of::<FinalPacket<8>>()
+        );
+    }
+
+    #[test]
+    fn test_vectors_round_trip() {
+        use test_vectors::*;
+
+        let (bytes, poll) = POLL_VECTOR;
+        assert_eq!(poll.value.to_le_bytes(), bytes);
+        assert_eq!(
+            PollPacket::from(u72::from_le_bytes(bytes.try_into().unwrap())),
+            poll
+        );
+        assert_eq!(poll.packet_type(), PacketType::Poll);
+        assert_eq!(poll.tx_timestamp(), u40::new(0x12356789));
+        assert_eq!(poll.address(), 0x0105);
+
+        let (bytes, response) = RESPONSE_VECTOR;
+        assert_eq!(response.value.to_le_bytes(), bytes);
+        assert_eq!(
+            ResponsePacket::from(u16::from_le_bytes(bytes.try_into().unwrap())),
+            response
+        );
+        assert_eq!(response.packet_type(), PacketType::Response);
+
+        let (bytes, priority_response) = PRIORITY_RESPONSE_VECTOR;
+        assert_eq!(priority_response.value.to_le_bytes(), bytes);
+        assert_eq!(
+            priority_response.packet_type(),
+            PacketType::PriorityResponse
+        );
+        assert_eq!(priority_response.version(), PROTOCOL_VERSION);
+        assert_eq!(priority_response.address(), 0x0065);
+
+        let (bytes, final_) = FINAL_VECTOR;
+        assert_eq!(final_.as_bytes(), bytes);
+        assert_eq!(*final_packet_ref(bytes).unwrap(), final_);
+        assert_eq!(final_.rx_timestamps[2].value(), u40::new(0x9abc));
+        assert!(!final_.is_slot_valid(1));
+
+        let (bytes, compact) = COMPACT_FINAL_VECTOR;
+        assert_eq!(compact.as_bytes(), bytes);
+        assert_eq!(
+            CompactFinalPacket::<3>::read_from_bytes(bytes).unwrap(),
+            compact
+        );
+        assert_eq!(CompactFinalPacket::encode(&final_), Ok(compact));
+        assert_eq!(compact.decode(), final_);
+
+        let (bytes, bootstrap) = BOOTSTRAP_VECTOR;
+        assert_eq!(bootstrap.as_bytes(), bytes);
+        assert_eq!(BootstrapPacket::read_from_bytes(bytes).unwrap(), bootstrap);
+        assert_eq!(bootstrap.skew.get(), -5e-6);
+    }
+
+    #[test]
+    fn test_parse_packet() {
+        use test_vectors::*;
+
+        assert_eq!(parse_packet(POLL_VECTOR.0), Ok(Packet::Poll(POLL_VECTOR.1)));
+        assert_eq!(
+            parse_packet(RESPONSE_VECTOR.0),
+            Ok(Packet::Response(RESPONSE_VECTOR.1))
+        );
+        assert_eq!(
+            parse_packet(PRIORITY_RESPONSE_VECTOR.0),
+            Ok(Packet::PriorityResponse(PRIORITY_RESPONSE_VECTOR.1))
+        );
+        assert_eq!(
+            parse_packet(FINAL_VECTOR.0),
+            Ok(Packet::Final(FINAL_VECTOR.1.into()))
+        );
+        assert_eq!(
+            parse_packet(COMPACT_FINAL_VECTOR.0),
+            Ok(Packet::Final(FINAL_VECTOR.1.into()))
+        );
+        assert_eq!(
+            parse_packet(BOOTSTRAP_VECTOR.0),
+            Ok(Packet::Bootstrap(BOOTSTRAP_VECTOR.1))
+        );
+
+        assert_eq!(parse_packet(&[]), Err(ParseError::InvalidLength));
+        assert_eq!(
+            parse_packet(&POLL_VECTOR.0[..7]),
+            Err(ParseError::InvalidLength)
+        );
+        assert_eq!(parse_packet(&[0x1f]), Err(ParseError::InvalidPacketType));
+    }
+
+    #[test]
+    fn test_parse_final_any_slots() {
+        // One final per tag count, each with its last slot invalid
+        fn check<const N: usize>() {
+            let mut packet = FinalPacket::<N>::new(
+                PacketType::Final,
+                PROTOCOL_VERSION,
+                core::array::from_fn(|i| u40::new(0x1000 + i as u64)),
+                u40::new(0x9000),
+            );
+            packet.set_slot_valid(N - 1, false);
+            packet.set_extension(0x5a);
+
+            let Ok(Packet::Final(parsed)) = parse_packet(packet.as_bytes()) else {
+                panic!("final with {} slots not parsed", N);
+            };
+            assert_eq!(parsed.num_slots(), N);
+            assert_eq!(parsed.to_fixed::<N>(), Some(packet));
+            assert_eq!(parsed, AnyFinalPacket::from(packet));
+
+            let full = parsed.packet();
+            assert_eq!(full.rx_timestamps[0].value(), u40::new(0x1000));
+            assert_eq!(full.tx_timestamp.value(), u40::new(0x9000));
+            assert!(full.is_slot_valid(N - 2) && !full.is_slot_valid(N - 1));
+            assert!(!full.is_slot_valid(N));
+        }
+        check::<2>();
+        check::<5>();
+        check::<16>();
+
+        // Not a whole number of slots, or more than fit in the mask
+        let packet = FinalPacket::<3>::new(
+            PacketType::Final,
+            PROTOCOL_VERSION,
+            [u40::new(0); 3],
+            u40::new(0),
+        );
+        let bytes = packet.as_bytes();
+        assert_eq!(parse_packet(&bytes[..22]), Err(ParseError::InvalidLength));
+        assert_eq!(parse_packet(&bytes[..8]), Err(ParseError::InvalidLength));
+        let mut long = [0u8; 4 + 5 * 18];
+        long[0] = bytes[0];
+        assert_eq!(parse_packet(&long), Err(ParseError::InvalidLength));
+
+        let parsed = AnyFinalPacket::from_bytes(bytes).unwrap();
+        assert_eq!(parsed.to_fixed::<4>(), None);
+    }
+
+    #[test]
+    fn test_extension_round_trip() {
+        let mut poll = PollPacket::new(PacketType::Poll, PROTOCOL_VERSION, u40::new(0x1234), 5, 0);
+        poll.set_extension(0xa5);
+        let Ok(Packet::Poll(parsed)) = parse_packet(&poll.value.to_le_bytes()) else {
+            panic!("not parsed as a poll");
+        };
+        assert_eq!(parsed.extension(), 0xa5);
+        assert_eq!(parsed.address(), 5);
+
+        let response = ResponsePacket::new(PacketType::Response, PROTOCOL_VERSION, 0x5a);
+        let Ok(Packet::Response(parsed)) = parse_packet(&response.value.to_le_bytes()) else {
+            panic!("not parsed as a response");
+        };
+        assert_eq!(parsed.extension(), 0x5a);
+
+        let mut final_packet = FinalPacket::<3>::new(
+            PacketType::Final,
+            PROTOCOL_VERSION,
+            [u40::new(0x100), u40::new(0x200), u40::new(0x300)],
+            u40::new(0x400),
+        );
+        final_packet.set_extension(0xc3);
+        let Ok(Packet::Final(parsed)) = parse_packet(final_packet.as_bytes()) else {
+            panic!("not parsed as a final");
+        };
+        assert_eq!(parsed.packet().extension(), 0xc3);
+
+        let compact = CompactFinalPacket::encode(&final_packet).unwrap();
+        let compact = CompactFinalPacket::<3>::read_from_bytes(compact.as_bytes()).unwrap();
+        assert_eq!(compact.extension(), 0xc3);
+        assert_eq!(compact.decode().extension(), 0xc3);
+
+        let mut bootstrap = build_bootstrap(&Schedule::new(4, 2), &TimeSync::new());
+        bootstrap.set_extension(0x3c);
+        let Ok(Packet::Bootstrap(parsed)) = parse_packet(bootstrap.as_bytes()) else {
+            panic!("not parsed as a bootstrap packet");
+        };
+        assert_eq!(parsed.extension(), 0x3c);
+    }
+
+    #[test]
+    fn test_bootstrap_packet() {
+        let mut schedule = Schedule::new(8, 3);
+        schedule.inter_frame_idle = 100_000;
+        schedule.frame_duration = Some(10_000_000);
+        schedule.beacon_duration = 300_000;
+        schedule.priority_slot_duration = 800_000;
+
+        let mut sync = TimeSync::new();
+        for i in 0..3u64 {
+            let local = 1_000_000 + i * 64_000_000;
+            sync.add_beacon(local, local + local / 100_000 + 5_000);
+        }
+
+        let packet = build_bootstrap(&schedule, &sync);
+        assert_eq!(PacketType::Bootstrap.wire_len(3), 32);
+        // Little-endian on the wire, whatever the host
+        assert_eq!(packet.as_bytes()[4..6], [8, 0]);
+        assert_eq!(packet.as_bytes()[8..12], 100_000u32.to_le_bytes());
+
+        let Ok(Packet::Bootstrap(received)) = parse_packet(packet.as_bytes()) else {
+            panic!("not parsed as a bootstrap packet");
+        };
+        let (joined_schedule, mut joined_sync) = join_from_bootstrap(&received);
+        assert_eq!(joined_schedule, schedule);
+
+        // Unsynced until the first beacon, but with the sender's skew
+        assert!(!joined_sync.is_valid());
+        assert!(!joined_sync.is_synced(0, u64::MAX));
+        assert_eq!(joined_sync.skew_ppm(), sync.skew_ppm());
+
+        // From which one beacon on its own clock is enough to follow the root
+        joined_sync.add_beacon(7_000_000, 2_000_000);
+        let root = joined_sync.local_to_root(7_000_000 + 100_000_000);
+        let expected = 2_000_000.0 + 100_000_000.0 * (1.0 + sync.skew_ppm() * 1e-6);
+        assert!((root as f64 - expected).abs() <= 1.0);
+
+        // Unbounded frame
+        schedule.frame_duration = None;
+        let (joined_schedule, _) = join_from_bootstrap(&build_bootstrap(&schedule, &sync));
+        assert_eq!(joined_schedule.frame_duration, None);
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let poll = PollPacket::new(PacketType::Poll, u4::new(2), u40::new(0x1234), 5, 0);
+        assert_eq!(poll.version(), u4::new(2));
+        assert_eq!(
+            parse_packet(&poll.value.to_le_bytes()),
+            Err(ParseError::VersionMismatch)
+        );
+
+        let response = ResponsePacket::new(PacketType::Response, u4::new(0xf), 0);
+        assert_eq!(
+            parse_packet(&response.value.to_le_bytes()),
+            Err(ParseError::VersionMismatch)
+        );
+
+        let final_packet =
+            FinalPacket::<3>::new(PacketType::Final, u4::new(2), [u40::new(0); 3], u40::new(0));
+        assert_eq!(
+            parse_packet(final_packet.as_bytes()),
+            Err(ParseError::VersionMismatch)
+        );
+        assert_eq!(
+            final_packet_ref::<3>(final_packet.as_bytes()),
+            Err(ParseError::VersionMismatch)
+        );
+    }
+
     #[test]
     fn test_device_timestamp() {
         let dt = DeviceTimestamp::new(u40::new(0x12356789).into());
@@ -0,0 +1,216 @@
+// Listen-only tag
+//
+// A passive tag never transmits. It overhears the polls and finals of the anchors and compares
+// their arrival times: with the anchors synchronized (see `TimeSync`), the difference between the
+// arrival times of two frames, minus the difference between their TX times, is the difference of
+// the tag's distances to the two anchors (TDoA). The tag's own clock offset cancels out.
+
+use heapless::Vec;
+
+use crate::anchor_state_machine::IndexError;
+use crate::packet::{FinalPacket, PollPacket};
+use crate::time_sync::signed_diff;
+use crate::util::TIMESTAMP_MASK;
+
+/// Listen-only tag, recording the poll and final timestamps of multiple anchors.
+///
+/// The TX timestamps carried by the frames must be in a common time base of all anchors.
+///
+/// All per-anchor vectors are index-aligned with `anchors`.
+#[derive(Debug, Clone, Default)]
+pub struct PassiveTagStateMachine {
+    /// Anchor addresses
+    anchors: Vec<u16, 16>,
+
+    /// Poll TX timestamps (in network time)
+    pub poll_tx_ts: Vec<u64, 16>,
+
+    /// Poll RX timestamps (in tag time)
+    pub poll_rx_ts: Vec<u64, 16>,
+
+    /// Final TX timestamps (in network time)
+    pub final_tx_ts: Vec<u64, 16>,
+
+    /// Final RX timestamps (in tag time)
+    pub final_rx_ts: Vec<u64, 16>,
+
+    /// Anchors whose poll was received in this round (bitmask by anchor index)
+    polls_received: u16,
+
+    /// Anchors whose final was received in this round (bitmask by anchor index)
+    finals_received: u16,
+}
+
+impl PassiveTagStateMachine {
+    /// Create a new `PassiveTagStateMachine` listening to `anchors`.
+    pub fn new(anchors: Vec<u16, 16>) -> Self {
+        Self {
+            poll_tx_ts: Vec::from_iter(core::iter::repeat(0).take(anchors.len())),
+            poll_rx_ts: Vec::from_iter(core::iter::repeat(0).take(anchors.len())),
+            final_tx_ts: Vec::from_iter(core::iter::repeat(0).take(anchors.len())),
+            final_rx_ts: Vec::from_iter(core::iter::repeat(0).take(anchors.len())),
+            polls_received: 0,
+            finals_received: 0,
+            anchors,
+        }
+    }
+
+    /// Get the index of an anchor from its address.
+    pub fn anchor_index(&self, anchor_addr: u16) -> Option<usize> {
+        self.anchors.iter().position(|&addr| addr == anchor_addr)
+    }
+
+    /// Forget the frames of the last round.
+    pub fn reset(&mut self) {
+        self.polls_received = 0;
+        self.finals_received = 0;
+    }
+
+    /// Check that `anchor_idx` is the index of one of the anchors.
+    fn check_index(&self, anchor_idx: usize) -> Result<(), IndexError> {
+        if anchor_idx >= self.anchors.len() {
+            return Err(IndexError {
+                index: anchor_idx,
+                len: self.anchors.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Record an overheard poll message.
+    pub fn set_poll_ts_idx(
+        &mut self,
+        anchor_idx: usize,
+        poll_tx_ts: u64,
+        poll_rx_ts: u64,
+    ) -> Result<(), IndexError> {
+        self.check_index(anchor_idx)?;
+
+        self.poll_tx_ts[anchor_idx] = poll_tx_ts & TIMESTAMP_MASK;
+        self.poll_rx_ts[anchor_idx] = poll_rx_ts & TIMESTAMP_MASK;
+        self.polls_received |= 1 << anchor_idx;
+        Ok(())
+    }
+
+    /// Record an overheard final message.
+    pub fn set_final_ts_idx(
+        &mut self,
+        anchor_idx: usize,
+        final_tx_ts: u64,
+        final_rx_ts: u64,
+    ) -> Result<(), IndexError> {
+        self.check_index(anchor_idx)?;
+
+        self.final_tx_ts[anchor_idx] = final_tx_ts & TIMESTAMP_MASK;
+        self.final_rx_ts[anchor_idx] = final_rx_ts & TIMESTAMP_MASK;
+        self.finals_received |= 1 << anchor_idx;
+        Ok(())
+    }
+
+    /// Ingest a poll packet received at `poll_rx_ts`, routed by the sender address it carries.
+    ///
+    /// Returns the index of the sending anchor, or `None` if the address is not a known anchor.
+    pub fn apply_poll_packet(&mut self, packet: &PollPacket, poll_rx_ts: u64) -> Option<usize> {
+        let anchor_idx = self.anchor_index(packet.address())?;
+
+        self.set_poll_ts_idx(anchor_idx, packet.tx_timestamp().value(), poll_rx_ts)
+            .ok()?;
+        Some(anchor_idx)
+    }
+
+    /// Ingest a final packet from an anchor, received at `final_rx_ts`.
+    ///
+    /// Only the TX timestamp is used, the response slots are for the active tags.
+    pub fn apply_final_packet<const N: usize>(
+        &mut self,
+        anchor_idx: usize,
+        packet: &FinalPacket<N>,
+        final_rx_ts: u64,
+    ) -> Result<(), IndexError> {
+        self.set_final_ts_idx(anchor_idx, packet.tx_timestamp.value().value(), final_rx_ts)
+    }
+
+    /// Time difference of arrival between two anchors in device time units.
+    ///
+    /// Positive if the tag is farther from `anchor_a` than from `anchor_b`. Computed from the
+    /// polls and from the finals, averaged if both were received from both anchors. `None` if
+    /// neither pair was received, or if either index is out of range.
+    pub fn tdoa(&self, anchor_a: usize, anchor_b: usize) -> Option<i64> {
+        self.check_index(anchor_a).ok()?;
+        self.check_index(anchor_b).ok()?;
+
+        let both = (1 << anchor_a) | (1 << anchor_b);
+        let diff = |tx: &[u64], rx: &[u64]| {
+            signed_diff(rx[anchor_a], rx[anchor_b]) - signed_diff(tx[anchor_a], tx[anchor_b])
+        };
+
+        let from_polls =
+            (self.polls_received & both == both).then(|| diff(&self.poll_tx_ts, &self.poll_rx_ts));
+        let from_finals = (self.finals_received & both == both)
+            .then(|| diff(&self.final_tx_ts, &self.final_rx_ts));
+
+        match (from_polls, from_finals) {
+            (Some(p), Some(f)) => Some((p + f).div_euclid(2)),
+            (p, f) => p.or(f),
+        }
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::{DWT_TIME_UNIT, SPEED_OF_LIGHT};
+
+    #[test]
+    fn test_tdoa() {
+        let meters_to_ticks = |d: f64| libm::round(d / (DWT_TIME_UNIT * SPEED_OF_LIGHT)) as u64;
+
+        // Tag 10 m from anchor 0, 4 m from anchor 1, 7 m from anchor 2
+        let tofs = [10.0, 4.0, 7.0].map(meters_to_ticks);
+        // The tag's clock runs far from the network time, and wraps
+        let tag_offset = TIMESTAMP_MASK - 50_000_000;
+
+        let mut tag = PassiveTagStateMachine::new(Vec::from_iter([1, 2, 3]));
+        for (i, tof) in tofs.iter().enumerate() {
+            let poll_tx_ts = 1_000_000 + i as u64 * 200_000;
+            let final_tx_ts = 9_000_000 + i as u64 * 200_000;
+            tag.set_poll_ts_idx(i, poll_tx_ts, poll_tx_ts + tof + tag_offset)
+                .unwrap();
+            if i != 2 {
+                tag.set_final_ts_idx(i, final_tx_ts, final_tx_ts + tof + tag_offset)
+                    .unwrap();
+            }
+        }
+
+        let expected = tofs[0] as i64 - tofs[1] as i64;
+        assert_eq!(tag.tdoa(0, 1), Some(expected));
+        assert_eq!(tag.tdoa(1, 0), Some(-expected));
+        assert_eq!(tag.tdoa(0, 2), Some(tofs[0] as i64 - tofs[2] as i64));
+
+        // About 6 m of path difference
+        let tdoa_m = tag.tdoa(0, 1).unwrap() as f64 * DWT_TIME_UNIT * SPEED_OF_LIGHT;
+        assert!((tdoa_m - 6.0).abs() < 0.01);
+
+        tag.reset();
+        assert_eq!(tag.tdoa(0, 1), None);
+    }
+
+    #[test]
+    fn test_index_out_of_range() {
+        let mut tag = PassiveTagStateMachine::new(Vec::from_iter([1, 2, 3]));
+        tag.set_poll_ts_idx(0, 1_000, 2_000).unwrap();
+
+        // Past the anchors, and past the 16 bits of the masks
+        for index in [3, 16, 100] {
+            let error = IndexError { index, len: 3 };
+            assert_eq!(tag.set_poll_ts_idx(index, 1_000, 2_000), Err(error));
+            assert_eq!(tag.set_final_ts_idx(index, 1_000, 2_000), Err(error));
+            assert_eq!(tag.tdoa(0, index), None);
+            assert_eq!(tag.tdoa(index, 0), None);
+        }
+    }
+}
@@ -0,0 +1,705 @@
+// Positioning on top of the ranging results
+//
+// Positions are in meters in the frame of the anchor coordinates, as (x, y, z) tuples.
+
+use heapless::Vec;
+
+use crate::time_sync::signed_diff;
+use crate::util::DWT_TIME_UNIT;
+
+/// Position (or direction) in meters
+pub type Point = (f32, f32, f32);
+
+/// Position dilution of precision of a fix at `position` from `anchors`.
+///
+/// Square root of the trace of `(HᵀH)⁻¹`, where the rows of `H` are the unit vectors from the
+/// tag to each anchor. Multiplied by the ranging error it gives the expected position error, so
+/// low is good. Degenerate geometries (fewer than three anchors not all in one plane with the
+/// tag, e.g. collinear anchors) yield infinity.
+pub fn pdop(anchors: &[Point], position: Point) -> f32 {
+    // Geometry matrix HᵀH, symmetric
+    let mut g = [[0.0f32; 3]; 3];
+    for &(x, y, z) in anchors {
+        let d = (x - position.0, y - position.1, z - position.2);
+        let norm = libm::sqrtf(d.0 * d.0 + d.1 * d.1 + d.2 * d.2);
+        if norm == 0.0 {
+            continue;
+        }
+
+        let u = [d.0 / norm, d.1 / norm, d.2 / norm];
+        for (i, row) in g.iter_mut().enumerate() {
+            for (j, g_ij) in row.iter_mut().enumerate() {
+                *g_ij += u[i] * u[j];
+            }
+        }
+    }
+
+    // Trace of the inverse: sum of the diagonal cofactors over the determinant
+    let cofactor = |i: usize| {
+        let (a, b) = ((i + 1) % 3, (i + 2) % 3);
+        g[a][a] * g[b][b] - g[a][b] * g[b][a]
+    };
+    let det = g[0][0] * cofactor(0)
+        + g[0][1] * (g[1][2] * g[2][0] - g[1][0] * g[2][2])
+        + g[0][2] * (g[1][0] * g[2][1] - g[1][1] * g[2][0]);
+    if det.abs() < 1e-6 {
+        return f32::INFINITY;
+    }
+
+    libm::sqrtf((cofactor(0) + cofactor(1) + cofactor(2)) / det)
+}
+
+/// Small subset of `anchors` that keeps the PDOP at `approx_pos` within `max_pdop`, as a bitmask
+/// by anchor index, to range against only those.
+///
+/// Greedy backward elimination: starting from all anchors, the anchor whose removal hurts the
+/// PDOP least is dropped as long as the PDOP stays within `max_pdop`. Not guaranteed to find the
+/// smallest subset. If all anchors together already exceed `max_pdop`, all are selected. Only the
+/// first 16 anchors are considered.
+pub fn select_anchors_for_pdop(anchors: &[Point], approx_pos: Point, max_pdop: f32) -> u16 {
+    let anchors = &anchors[..anchors.len().min(16)];
+    let subset = |mask: u16| -> Vec<Point, 16> {
+        (0..anchors.len())
+            .filter(|i| mask & (1 << i) != 0)
+            .map(|i| anchors[i])
+            .collect()
+    };
+
+    let mut selected = (0..anchors.len()).fold(0u16, |mask, i| mask | (1 << i));
+    loop {
+        let best = (0..anchors.len())
+            .filter(|i| selected & (1 << i) != 0)
+            .map(|i| {
+                let mask = selected & !(1 << i);
+                (mask, pdop(&subset(mask), approx_pos))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        match best {
+            Some((mask, dop)) if dop <= max_pdop => selected = mask,
+            _ => return selected,
+        }
+    }
+}
+
+/// Maximum number of Gauss-Newton iterations of `trilaterate`
+const MAX_ITERATIONS: usize = 20;
+
+/// Centroid of the anchors, a cheap and robust initial guess for `trilaterate`.
+///
+/// The origin if there are no anchors.
+pub fn anchor_centroid(anchors: &[Point]) -> Point {
+    if anchors.is_empty() {
+        return (0.0, 0.0, 0.0);
+    }
+
+    let n = anchors.len() as f32;
+    let sum = anchors.iter().fold((0.0, 0.0, 0.0), |acc, a| {
+        (acc.0 + a.0, acc.1 + a.1, acc.2 + a.2)
+    });
+
+    (sum.0 / n, sum.1 / n, sum.2 / n)
+}
+
+/// Position whose distances to `anchors` best match `distances` (in meters), in the least-squares
+/// sense.
+///
+/// Gauss-Newton iterations starting at `seed`, or at the anchor centroid without one. The
+/// iterations are slightly damped, so with all anchors in one plane and the start in that plane
+/// the fix stays in the plane instead of failing. `None` with fewer than three anchors, a length
+/// mismatch, or if the iterations do not converge.
+pub fn trilaterate(anchors: &[Point], distances: &[f32], seed: Option<Point>) -> Option<Point> {
+    if anchors.len() < 3 || anchors.len() != distances.len() {
+        return None;
+    }
+
+    let mut p = seed.unwrap_or_else(|| anchor_centroid(anchors));
+    for _ in 0..MAX_ITERATIONS {
+        // Normal equations JᵀJ δ = -Jᵀr of the range residuals r
+        let mut g = [[0.0f32; 3]; 3];
+        let mut b = [0.0f32; 3];
+        for (&(x, y, z), &distance) in anchors.iter().zip(distances) {
+            let d = (p.0 - x, p.1 - y, p.2 - z);
+            let range = libm::sqrtf(d.0 * d.0 + d.1 * d.1 + d.2 * d.2);
+            if range == 0.0 {
+                continue;
+            }
+
+            let u = [d.0 / range, d.1 / range, d.2 / range];
+            let residual = range - distance;
+            for i in 0..3 {
+                for j in 0..3 {
+                    g[i][j] += u[i] * u[j];
+                }
+                b[i] -= u[i] * residual;
+            }
+        }
+        for (i, row) in g.iter_mut().enumerate() {
+            row[i] += 1e-3;
+        }
+
+        let step = solve(&g, &b)?;
+        p = (p.0 + step[0], p.1 + step[1], p.2 + step[2]);
+        if !(p.0.is_finite() && p.1.is_finite() && p.2.is_finite()) {
+            return None;
+        }
+
+        if step[0] * step[0] + step[1] * step[1] + step[2] * step[2] < 1e-8 {
+            return Some(p);
+        }
+    }
+
+    None
+}
+
+/// Predicted minus measured range (in meters) of every anchor, for a fix at `position`.
+///
+/// A large residual flags a bad range, e.g. a non-line-of-sight measurement. Only the first 16
+/// anchors are covered.
+pub fn range_residuals(position: Point, anchors: &[Point], measured: &[f32]) -> Vec<f32, 16> {
+    anchors
+        .iter()
+        .zip(measured)
+        .take(16)
+        .map(|(&(x, y, z), &distance)| {
+            let d = (position.0 - x, position.1 - y, position.2 - z);
+            libm::sqrtf(d.0 * d.0 + d.1 * d.1 + d.2 * d.2) - distance
+        })
+        .collect()
+}
+
+/// `trilaterate` with outlier rejection: while a residual exceeds `threshold_m`, the anchor with
+/// the largest residual is dropped and the fix re-solved.
+///
+/// Needs redundant anchors around the tag to work well: with poor geometry, the fit can spread
+/// an outlier's error so that a good anchor shows the largest residual.
+///
+/// Returns the fix and the bitmask (by anchor index) of the rejected anchors. `None` if the
+/// residuals do not get within the threshold before fewer than three anchors remain, or if
+/// `trilaterate` fails.
+pub fn solve_with_rejection(
+    anchors: &[Point],
+    measured: &[f32],
+    threshold_m: f32,
+    seed: Option<Point>,
+) -> Option<(Point, u16)> {
+    if anchors.len() > 16 || anchors.len() != measured.len() {
+        return None;
+    }
+
+    let mut rejected = 0u16;
+    loop {
+        let kept = |i: &usize| rejected & (1 << i) == 0;
+        let indices: Vec<usize, 16> = (0..anchors.len()).filter(kept).collect();
+        let kept_anchors: Vec<Point, 16> = indices.iter().map(|&i| anchors[i]).collect();
+        let kept_measured: Vec<f32, 16> = indices.iter().map(|&i| measured[i]).collect();
+
+        let fix = trilaterate(&kept_anchors, &kept_measured, seed)?;
+        let residuals = range_residuals(fix, &kept_anchors, &kept_measured);
+        let (worst, residual) = residuals
+            .iter()
+            .map(|r| r.abs())
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        if residual <= threshold_m {
+            return Some((fix, rejected));
+        }
+        if indices.len() <= 3 {
+            return None;
+        }
+
+        rejected |= 1 << indices[worst];
+    }
+}
+
+/// Solve the linear system `a x = b` by Gaussian elimination, `None` if `a` is singular.
+fn solve<const N: usize>(a: &[[f32; N]; N], b: &[f32; N]) -> Option<[f32; N]> {
+    let (mut a, mut b) = (*a, *b);
+
+    for col in 0..N {
+        // Partial pivoting
+        let pivot = (col..N).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let pivot_row = a[col];
+        for row in col + 1..N {
+            let factor = a[row][col] / pivot_row[col];
+            for (a_rk, a_ck) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *a_rk -= factor * a_ck;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = [0.0f32; N];
+    for row in (0..N).rev() {
+        let tail: f32 = (row + 1..N).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - tail) / a[row][row];
+    }
+
+    Some(x)
+}
+
+/// Maximum number of Jacobi sweeps of `symmetric_eigen`
+const MAX_SWEEPS: usize = 50;
+
+/// Anchor coordinates from the matrix of inter-anchor distances in meters, by classical
+/// multidimensional scaling.
+///
+/// Double-centers the squared distances into the Gram matrix of the positions, `B = -J D² J / 2`
+/// with `J = I - 1/N`, and takes the coordinates along its three largest eigenvectors, each
+/// scaled by the square root of its eigenvalue. The positions are recovered up to a rigid
+/// transform (rotation, reflection and translation) and centered on their centroid. Coordinates
+/// of a planar layout come out with zero in the third component.
+pub fn mds_localize<const N: usize>(matrix: &[[f32; N]; N]) -> [[f32; 3]; N] {
+    // Double centering of the squared distances
+    let squared = matrix.map(|row| row.map(|d| d * d));
+    let row_means = squared.map(|row| row.iter().sum::<f32>() / N as f32);
+    let mean = row_means.iter().sum::<f32>() / N as f32;
+    let mut gram = [[0.0f32; N]; N];
+    for (i, row) in gram.iter_mut().enumerate() {
+        for (j, b_ij) in row.iter_mut().enumerate() {
+            *b_ij = -0.5 * (squared[i][j] - row_means[i] - row_means[j] + mean);
+        }
+    }
+
+    let (eigenvalues, eigenvectors) = symmetric_eigen(gram);
+    let mut order: [usize; N] = core::array::from_fn(|i| i);
+    order.sort_unstable_by(|&a, &b| eigenvalues[b].total_cmp(&eigenvalues[a]));
+
+    // Eigenvalues that are negative or tiny against the largest are noise, e.g. the third one of
+    // a planar layout
+    let floor = order
+        .first()
+        .map_or(0.0, |&k| 1e-6 * eigenvalues[k].max(0.0));
+
+    let mut coords = [[0.0f32; 3]; N];
+    for (axis, &k) in order.iter().take(3).enumerate() {
+        let scale = match eigenvalues[k] {
+            lambda if lambda > floor => libm::sqrtf(lambda),
+            _ => 0.0,
+        };
+        for (coord, row) in coords.iter_mut().zip(&eigenvectors) {
+            coord[axis] = row[k] * scale;
+        }
+    }
+
+    coords
+}
+
+/// Eigenvalues and eigenvectors (as columns) of a symmetric matrix, by cyclic Jacobi rotations.
+fn symmetric_eigen<const N: usize>(mut a: [[f32; N]; N]) -> ([f32; N], [[f32; N]; N]) {
+    let mut v: [[f32; N]; N] =
+        core::array::from_fn(|i| core::array::from_fn(|j| (i == j) as u8 as f32));
+    let scale = a.iter().flatten().map(|x| x * x).sum::<f32>();
+
+    for _ in 0..MAX_SWEEPS {
+        let off_diagonal = (0..N)
+            .flat_map(|p| (0..N).filter(move |&q| q != p).map(move |q| (p, q)))
+            .map(|(p, q)| a[p][q] * a[p][q])
+            .sum::<f32>();
+        if off_diagonal <= 1e-12 * scale {
+            break;
+        }
+
+        for p in 0..N {
+            for q in p + 1..N {
+                if a[p][q] == 0.0 {
+                    continue;
+                }
+
+                // Rotation in the (p, q) plane that zeroes a[p][q]
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = 1.0f32.copysign(theta) / (theta.abs() + libm::sqrtf(theta * theta + 1.0));
+                let c = 1.0 / libm::sqrtf(t * t + 1.0);
+                let s = t * c;
+
+                let rotate = |x: f32, y: f32| (c * x - s * y, s * x + c * y);
+                for row in a.iter_mut() {
+                    (row[p], row[q]) = rotate(row[p], row[q]);
+                }
+                let (row_p, row_q) = (a[p], a[q]);
+                a[p] = core::array::from_fn(|k| rotate(row_p[k], row_q[k]).0);
+                a[q] = core::array::from_fn(|k| rotate(row_p[k], row_q[k]).1);
+                for row in v.iter_mut() {
+                    (row[p], row[q]) = rotate(row[p], row[q]);
+                }
+            }
+        }
+    }
+
+    (core::array::from_fn(|i| a[i][i]), v)
+}
+
+/// Position and heading of a rigid body carrying several tags
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pose {
+    /// Position of the body origin in meters
+    pub position: Point,
+
+    /// Rotation about the z axis in radians, counter-clockwise seen from above
+    pub yaw: f32,
+}
+
+/// Pose of a rigid body from the ranges of all its tags, solved jointly.
+///
+/// `tag_offsets[i]` is the position of tag `i` in the body frame, and `ranges[i]` its measured
+/// `(anchor index, distance in meters)` pairs, indices into `anchors`. Damped Gauss-Newton
+/// iterations over the position and the yaw, starting at the anchor centroid with zero yaw, so
+/// the body should be roughly level and not turned by more than about a quarter turn.
+///
+/// `None` with fewer than four ranges in total, a length mismatch, an anchor index out of range,
+/// or if the iterations do not converge.
+pub fn solve_rigid_body(
+    tag_offsets: &[Point],
+    ranges: &[&[(u16, f32)]],
+    anchors: &[Point],
+) -> Option<Pose> {
+    let num_ranges: usize = ranges.iter().map(|r| r.len()).sum();
+    if tag_offsets.len() != ranges.len()
+        || num_ranges < 4
+        || ranges
+            .iter()
+            .flat_map(|r| r.iter())
+            .any(|&(anchor_idx, _)| anchor_idx as usize >= anchors.len())
+    {
+        return None;
+    }
+
+    let (mut p, mut yaw) = (anchor_centroid(anchors), 0.0f32);
+    for _ in 0..MAX_ITERATIONS {
+        let (sin, cos) = (libm::sinf(yaw), libm::cosf(yaw));
+
+        // Normal equations over (x, y, z, yaw)
+        let mut g = [[0.0f32; 4]; 4];
+        let mut b = [0.0f32; 4];
+        for (&(ox, oy, oz), tag_ranges) in tag_offsets.iter().zip(ranges) {
+            let tag = (
+                p.0 + cos * ox - sin * oy,
+                p.1 + sin * ox + cos * oy,
+                p.2 + oz,
+            );
+            // Derivative of the tag position with respect to the yaw
+            let dyaw = (-sin * ox - cos * oy, cos * ox - sin * oy);
+
+            for &(anchor_idx, distance) in tag_ranges.iter() {
+                let (x, y, z) = anchors[anchor_idx as usize];
+                let d = (tag.0 - x, tag.1 - y, tag.2 - z);
+                let range = libm::sqrtf(d.0 * d.0 + d.1 * d.1 + d.2 * d.2);
+                if range == 0.0 {
+                    continue;
+                }
+
+                let u = (d.0 / range, d.1 / range, d.2 / range);
+                let jacobian = [u.0, u.1, u.2, u.0 * dyaw.0 + u.1 * dyaw.1];
+                let residual = range - distance;
+                for i in 0..4 {
+                    for j in 0..4 {
+                        g[i][j] += jacobian[i] * jacobian[j];
+                    }
+                    b[i] -= jacobian[i] * residual;
+                }
+            }
+        }
+        for (i, row) in g.iter_mut().enumerate() {
+            row[i] += 1e-3;
+        }
+
+        let step = solve(&g, &b)?;
+        p = (p.0 + step[0], p.1 + step[1], p.2 + step[2]);
+        yaw += step[3];
+        if !(p.0.is_finite() && p.1.is_finite() && p.2.is_finite() && yaw.is_finite()) {
+            return None;
+        }
+
+        if step.iter().map(|s| s * s).sum::<f32>() < 1e-8 {
+            return Some(Pose { position: p, yaw });
+        }
+    }
+
+    None
+}
+
+/// Velocity in m/s between two fixes, taken at `prev_t` and `cur_t` in network time.
+///
+/// The timestamps are 40-bit device time, so the fixes may straddle a rollover but must be less
+/// than half the counter range (~8.6 s) apart, in either order. Fixes at the same time yield zero
+/// velocity.
+pub fn velocity(prev: Point, prev_t: u64, cur: Point, cur_t: u64) -> Point {
+    let dt = signed_diff(cur_t, prev_t) as f64 * DWT_TIME_UNIT;
+    if dt == 0.0 {
+        return (0.0, 0.0, 0.0);
+    }
+
+    let dt = dt as f32;
+    (
+        (cur.0 - prev.0) / dt,
+        (cur.1 - prev.1) / dt,
+        (cur.2 - prev.2) / dt,
+    )
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::TIMESTAMP_MASK;
+
+    #[test]
+    fn test_pdop() {
+        // Anchors on the axes around the tag, plus one above
+        let spread = [
+            (5.0, 0.0, 0.0),
+            (-5.0, 0.0, 0.0),
+            (0.0, 5.0, 0.0),
+            (0.0, -5.0, 0.0),
+            (0.0, 0.0, 5.0),
+            (0.0, 0.0, -5.0),
+        ];
+        // Six orthogonal unit vectors: HᵀH = 2 I
+        assert!((pdop(&spread, (0.0, 0.0, 0.0)) - libm::sqrtf(1.5)).abs() < 1e-5);
+
+        let collinear = [(0.0, 0.0, 0.0), (5.0, 0.0, 0.0), (10.0, 0.0, 0.0)];
+        assert!(pdop(&collinear, (3.0, 4.0, 0.0)) > 100.0);
+
+        // Almost collinear, finite but bad
+        let skewed = [(0.0, 0.0, 0.0), (5.0, 0.1, 0.0), (10.0, 0.0, 0.2)];
+        let bad = pdop(&skewed, (5.0, 20.0, 0.0));
+        assert!(bad > 10.0 && bad < f32::INFINITY);
+    }
+
+    #[test]
+    fn test_select_anchors_for_pdop() {
+        // Corners of a box around the tag, plus two more anchors on one side
+        let anchors = [
+            (0.0, 0.0, 0.0),
+            (10.0, 0.0, 0.0),
+            (0.0, 10.0, 0.0),
+            (10.0, 10.0, 0.0),
+            (0.0, 0.0, 3.0),
+            (10.0, 0.0, 3.0),
+            (0.0, 10.0, 3.0),
+            (10.0, 10.0, 3.0),
+            (1.0, 0.0, 1.5),
+            (2.0, 0.0, 1.5),
+        ];
+        let position = (5.0, 5.0, 1.0);
+        let max_pdop = 2.5;
+        assert!(pdop(&anchors, position) <= max_pdop);
+
+        let selected = select_anchors_for_pdop(&anchors, position, max_pdop);
+        let subset: Vec<Point, 16> = (0..anchors.len())
+            .filter(|i| selected & (1 << i) != 0)
+            .map(|i| anchors[i])
+            .collect();
+        assert!(subset.len() >= 3 && subset.len() < anchors.len());
+        assert!(pdop(&subset, position) <= max_pdop);
+
+        // Unreachable threshold: all anchors
+        assert_eq!(select_anchors_for_pdop(&anchors, position, 0.1), 0x3ff);
+    }
+
+    #[test]
+    fn test_mds_localize() {
+        let distance = |a: [f32; 3], b: [f32; 3]| {
+            libm::sqrtf((0..3).map(|k| (a[k] - b[k]) * (a[k] - b[k])).sum())
+        };
+        let matrix_of = |points: &[[f32; 3]; 5]| points.map(|a| points.map(|b| distance(a, b)));
+
+        // Square of 8 m anchors on the floor, plus one 3 m up in the middle
+        let anchors = [
+            [0.0, 0.0, 0.0],
+            [8.0, 0.0, 0.0],
+            [8.0, 8.0, 0.0],
+            [0.0, 8.0, 0.0],
+            [4.0, 4.0, 3.0],
+        ];
+        let matrix = matrix_of(&anchors);
+
+        // Recovered up to a rigid transform: the same distances, around the centroid
+        let coords = mds_localize(&matrix);
+        let recovered = matrix_of(&coords);
+        for (row, expected) in recovered.iter().zip(&matrix) {
+            for (d, expected) in row.iter().zip(expected) {
+                assert!((d - expected).abs() < 1e-3);
+            }
+        }
+        for k in 0..3 {
+            assert!(coords.iter().map(|c| c[k]).sum::<f32>().abs() < 1e-3);
+        }
+
+        // Planar layout
+        let square = [[0.0, 0.0], [8.0, 0.0], [8.0, 8.0], [0.0, 8.0]];
+        let matrix = square
+            .map(|a: [f32; 2]| square.map(|b| distance([a[0], a[1], 0.0], [b[0], b[1], 0.0])));
+        let coords = mds_localize(&matrix);
+        assert!(coords.iter().all(|c| c[2] == 0.0));
+        assert!((distance(coords[0], coords[2]) - libm::sqrtf(128.0)).abs() < 1e-3);
+        assert!((distance(coords[0], coords[1]) - 8.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_anchor_centroid() {
+        let anchors = [
+            (0.0, 0.0, 0.0),
+            (4.0, 0.0, 0.0),
+            (4.0, 6.0, 0.0),
+            (0.0, 6.0, 0.0),
+        ];
+        assert_eq!(anchor_centroid(&anchors), (2.0, 3.0, 0.0));
+        assert_eq!(anchor_centroid(&[]), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_trilaterate() {
+        let anchors = [
+            (0.0, 0.0, 0.0),
+            (10.0, 0.0, 3.0),
+            (10.0, 8.0, 0.0),
+            (0.0, 8.0, 3.0),
+        ];
+        let tag: Point = (3.0, 5.0, 1.2);
+        let distances: heapless::Vec<f32, 4> = anchors
+            .iter()
+            .map(|a| {
+                let d = (tag.0 - a.0, tag.1 - a.1, tag.2 - a.2);
+                libm::sqrtf(d.0 * d.0 + d.1 * d.1 + d.2 * d.2)
+            })
+            .collect();
+
+        // Starting at the centroid, or at a seed
+        for seed in [None, Some((8.0, 1.0, 0.5))] {
+            let fix = trilaterate(&anchors, &distances, seed).unwrap();
+            assert!((fix.0 - tag.0).abs() < 1e-3);
+            assert!((fix.1 - tag.1).abs() < 1e-3);
+            assert!((fix.2 - tag.2).abs() < 1e-3);
+        }
+
+        assert_eq!(trilaterate(&anchors[..2], &distances[..2], None), None);
+        assert_eq!(trilaterate(&anchors, &distances[..3], None), None);
+    }
+
+    #[test]
+    fn test_solve_with_rejection() {
+        let anchors = [
+            (0.0, 0.0, 0.0),
+            (10.0, 0.0, 0.0),
+            (10.0, 8.0, 0.0),
+            (0.0, 8.0, 0.0),
+            (0.0, 0.0, 3.0),
+            (10.0, 0.0, 3.0),
+            (10.0, 8.0, 3.0),
+            (0.0, 8.0, 3.0),
+        ];
+        let tag: Point = (3.0, 5.0, 1.2);
+        let mut measured: heapless::Vec<f32, 8> = anchors
+            .iter()
+            .map(|a| {
+                let d = (tag.0 - a.0, tag.1 - a.1, tag.2 - a.2);
+                libm::sqrtf(d.0 * d.0 + d.1 * d.1 + d.2 * d.2)
+            })
+            .collect();
+        let error = |p: Point| {
+            let d = (p.0 - tag.0, p.1 - tag.1, p.2 - tag.2);
+            libm::sqrtf(d.0 * d.0 + d.1 * d.1 + d.2 * d.2)
+        };
+
+        // Exact ranges have no residuals
+        let exact = trilaterate(&anchors, &measured, None).unwrap();
+        assert!(range_residuals(exact, &anchors, &measured)
+            .iter()
+            .all(|r| r.abs() < 1e-3));
+
+        // More anchors than the residuals hold
+        let many = [anchors[0]; 17];
+        assert_eq!(range_residuals(exact, &many, &[0.0; 17]).len(), 16);
+
+        // A non-line-of-sight range, 2 m long
+        measured[2] += 2.0;
+        let plain = trilaterate(&anchors, &measured, None).unwrap();
+        let (robust, rejected) = solve_with_rejection(&anchors, &measured, 0.1, None).unwrap();
+
+        assert_eq!(rejected, 1 << 2);
+        assert!(error(robust) < 1e-2);
+        assert!(error(robust) < error(plain));
+    }
+
+    #[test]
+    fn test_solve_rigid_body() {
+        let anchors = [
+            (0.0, 0.0, 0.0),
+            (10.0, 0.0, 3.0),
+            (10.0, 8.0, 0.0),
+            (0.0, 8.0, 3.0),
+        ];
+        let range = |a: Point, b: Point| {
+            let d = (a.0 - b.0, a.1 - b.1, a.2 - b.2);
+            libm::sqrtf(d.0 * d.0 + d.1 * d.1 + d.2 * d.2)
+        };
+
+        // Two tags 1 m apart on a body at (3, 5, 1.2), turned by 0.4 rad
+        let tag_offsets = [(-0.5, 0.0, 0.0), (0.5, 0.0, 0.0)];
+        let (position, yaw) = ((3.0, 5.0, 1.2), 0.4f32);
+        let tags = tag_offsets.map(|(ox, oy, oz): Point| {
+            (
+                position.0 + libm::cosf(yaw) * ox - libm::sinf(yaw) * oy,
+                position.1 + libm::sinf(yaw) * ox + libm::cosf(yaw) * oy,
+                position.2 + oz,
+            )
+        });
+
+        // Each tag only hears three of the anchors
+        let first: heapless::Vec<(u16, f32), 4> = [0u16, 1, 2]
+            .iter()
+            .map(|&i| (i, range(tags[0], anchors[i as usize])))
+            .collect();
+        let second: heapless::Vec<(u16, f32), 4> = [1u16, 2, 3]
+            .iter()
+            .map(|&i| (i, range(tags[1], anchors[i as usize])))
+            .collect();
+
+        let pose = solve_rigid_body(&tag_offsets, &[&first, &second], &anchors).unwrap();
+        assert!((pose.position.0 - position.0).abs() < 1e-3);
+        assert!((pose.position.1 - position.1).abs() < 1e-3);
+        assert!((pose.position.2 - position.2).abs() < 1e-3);
+        assert!((pose.yaw - yaw).abs() < 1e-3);
+
+        assert_eq!(solve_rigid_body(&tag_offsets, &[&first], &anchors), None);
+        assert_eq!(
+            solve_rigid_body(&tag_offsets, &[&first[..1], &second[..2]], &anchors),
+            None
+        );
+    }
+
+    #[test]
+    fn test_velocity() {
+        // 0.1 s in device time units
+        let dt = (0.1 / DWT_TIME_UNIT) as u64;
+
+        // 0.3 m along x and 0.4 m down over 0.1 s, across the 40-bit rollover
+        let prev_t = TIMESTAMP_MASK - dt / 2;
+        let cur_t = (prev_t + dt) & TIMESTAMP_MASK;
+        let (vx, vy, vz) = velocity((1.0, 2.0, 1.5), prev_t, (1.3, 2.0, 1.1), cur_t);
+
+        assert!((vx - 3.0).abs() < 1e-3);
+        assert_eq!(vy, 0.0);
+        assert!((vz + 4.0).abs() < 1e-3);
+        assert!((libm::sqrtf(vx * vx + vy * vy + vz * vz) - 5.0).abs() < 1e-3);
+
+        // Fixes out of order give the same velocity
+        let (vx, _, vz) = velocity((1.3, 2.0, 1.1), cur_t, (1.0, 2.0, 1.5), prev_t);
+        assert!((vx - 3.0).abs() < 1e-3);
+        assert!((vz + 4.0).abs() < 1e-3);
+
+        assert_eq!(
+            velocity((1.0, 0.0, 0.0), 7, (2.0, 0.0, 0.0), 7),
+            (0.0, 0.0, 0.0)
+        );
+    }
+}
@@ -0,0 +1,579 @@
+// TDMA schedule of a ranging network
+//
+// One ranging round transmits a poll and a final per anchor and a response per tag, each
+// followed by an idle gap so the radios can turn around.
+//
+// A superframe optionally starts with a slot for the root anchor's sync beacon and a contention
+// slot for priority rounds, followed by the ranging round. The priority slot holds one exchange:
+// the root polls, a tag with a pending priority request sends a priority response, and the root
+// sends the final.
+use dw3000_ng::Config;
+
+use crate::packet::PacketType;
+use crate::util::{
+    packet_tx_time, round_air_time, wrapping_before, wrapping_interval, DWT_TIME_UNIT,
+    PHASE_GUARD_TIME, TIMESTAMP_MASK,
+};
+
+/// Radio events of a single node in one ranging round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MessageCounts {
+    /// Frames sent by each anchor: its poll and its final
+    pub anchor_tx: usize,
+
+    /// Frames received by each anchor: the responses of all tags
+    pub anchor_rx: usize,
+
+    /// Frames sent by each tag: its response
+    pub tag_tx: usize,
+
+    /// Frames received by each tag: the polls and finals of all anchors
+    pub tag_rx: usize,
+}
+
+/// Number of frames each anchor and each tag sends and receives in one round.
+///
+/// For energy budgeting. Without anchors or tags no ranging takes place and all counts are zero.
+pub fn message_counts(num_anchors: usize, num_tags: usize) -> MessageCounts {
+    if num_anchors == 0 || num_tags == 0 {
+        return MessageCounts {
+            anchor_tx: 0,
+            anchor_rx: 0,
+            tag_tx: 0,
+            tag_rx: 0,
+        };
+    }
+
+    MessageCounts {
+        anchor_tx: 2,
+        anchor_rx: num_tags,
+        tag_tx: 1,
+        tag_rx: 2 * num_anchors,
+    }
+}
+
+/// Frames of the exchange in the priority slot, in order
+const PRIORITY_EXCHANGE: [PacketType; 3] = [
+    PacketType::Poll,
+    PacketType::PriorityResponse,
+    PacketType::Final,
+];
+
+/// TDMA schedule of a ranging network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Schedule {
+    /// Number of anchors in the network
+    pub num_anchors: usize,
+
+    /// Number of tags in the network
+    pub num_tags: usize,
+
+    /// Idle time after every frame in nanoseconds
+    pub inter_frame_idle: u32,
+
+    /// Duration of the TDMA frame a round must fit in, in nanoseconds, `None` if unbounded
+    pub frame_duration: Option<u32>,
+
+    /// Length of the sync beacon slot at the start of every superframe in nanoseconds, zero
+    /// without beacons
+    pub beacon_duration: u32,
+
+    /// Length of the priority contention slot after the beacon slot in nanoseconds, zero without
+    /// priority rounds
+    pub priority_slot_duration: u32,
+}
+
+/// Error when a schedule does not work out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ScheduleError {
+    /// A round takes longer than the frame, by `excess` nanoseconds
+    FrameOverflow { excess: u32 },
+}
+
+impl Schedule {
+    /// Create a new schedule without idle time between frames, without a frame duration, and
+    /// without beacon or priority slot.
+    pub fn new(num_anchors: usize, num_tags: usize) -> Self {
+        Self {
+            num_anchors,
+            num_tags,
+            inter_frame_idle: 0,
+            frame_duration: None,
+            beacon_duration: 0,
+            priority_slot_duration: 0,
+        }
+    }
+
+    /// Reserve the beacon slot at the start of every superframe, in which the root anchor sends
+    /// its sync beacon: a poll, which carries the root's TX timestamp, plus the idle time.
+    ///
+    /// The root's scheduler emits the beacon, see `PipelinedScheduler::with_beacon`.
+    pub fn reserve_beacon_slot(&mut self, config: &Config) {
+        self.beacon_duration =
+            packet_tx_time(PacketType::Poll, self.num_tags, config) + self.inter_frame_idle;
+    }
+
+    /// Start and end of the beacon slot in nanoseconds from the superframe start.
+    ///
+    /// Empty without a reserved beacon slot.
+    pub fn beacon_slot(&self) -> (u64, u64) {
+        (0, self.beacon_duration as u64)
+    }
+
+    /// Reserve the priority contention slot after the beacon slot, with room for one poll,
+    /// priority response and final exchange plus the idle times.
+    ///
+    /// Only a node with a pending priority request (e.g. a man-down alert) transmits in it, see
+    /// `tx_slot`; all others keep to their regular slots.
+    pub fn reserve_priority_slot(&mut self, config: &Config) {
+        self.priority_slot_duration = PRIORITY_EXCHANGE
+            .into_iter()
+            .map(|packet_type| self.frame_slot_len(packet_type, config) as u32)
+            .sum();
+    }
+
+    /// Start and end of the priority contention slot in nanoseconds from the superframe start.
+    ///
+    /// Empty without a reserved priority slot.
+    pub fn priority_slot(&self) -> (u64, u64) {
+        let start = self.beacon_duration as u64;
+        (start, start + self.priority_slot_duration as u64)
+    }
+
+    /// Start and end of the slot of the `frame_idx`-th frame of the priority exchange in
+    /// nanoseconds from the superframe start: the root's poll, the priority response and the
+    /// root's final. `None` past the last frame or without a reserved priority slot.
+    pub fn priority_frame_slot(&self, frame_idx: usize, config: &Config) -> Option<(u64, u64)> {
+        if frame_idx >= PRIORITY_EXCHANGE.len() || self.priority_slot_duration == 0 {
+            return None;
+        }
+
+        let start = PRIORITY_EXCHANGE[..frame_idx]
+            .iter()
+            .map(|&packet_type| self.frame_slot_len(packet_type, config))
+            .sum::<u64>()
+            + self.priority_slot().0;
+        Some((
+            start,
+            start + self.frame_slot_len(PRIORITY_EXCHANGE[frame_idx], config),
+        ))
+    }
+
+    /// Slot in which tag `tag_idx` transmits, in nanoseconds from the superframe start: the
+    /// priority response slot for a `priority` round if a priority slot is reserved, its
+    /// response slot otherwise.
+    ///
+    /// `None` if `tag_idx` is not a valid tag index.
+    pub fn tx_slot(&self, tag_idx: usize, priority: bool, config: &Config) -> Option<(u64, u64)> {
+        if tag_idx >= self.num_tags {
+            return None;
+        }
+        if priority && self.priority_slot_duration > 0 {
+            return self.priority_frame_slot(1, config);
+        }
+
+        self.ranging_slot(self.num_anchors + tag_idx, config)
+    }
+
+    /// Start and end of the slot of the `frame_idx`-th frame of the ranging round in nanoseconds
+    /// from the superframe start, `None` past the last frame.
+    ///
+    /// The polls of all anchors come first, then the responses of all tags, then the finals of
+    /// all anchors, each followed by the idle time, and each phase by a `PHASE_GUARD_TIME`. The
+    /// round starts after the beacon and priority slots.
+    pub fn ranging_slot(&self, frame_idx: usize, config: &Config) -> Option<(u64, u64)> {
+        if frame_idx >= self.frames_per_round() {
+            return None;
+        }
+
+        let (poll, response, final_) = (
+            self.frame_slot_len(PacketType::Poll, config),
+            self.frame_slot_len(PacketType::Response, config),
+            self.frame_slot_len(PacketType::Final, config),
+        );
+
+        let (anchors, tags) = (self.num_anchors, self.num_tags);
+        let guard = PHASE_GUARD_TIME as u64;
+        let (offset, len) = if frame_idx < anchors {
+            (frame_idx as u64 * poll, poll)
+        } else if frame_idx < anchors + tags {
+            let idx = (frame_idx - anchors) as u64;
+            (anchors as u64 * poll + guard + idx * response, response)
+        } else {
+            let idx = (frame_idx - anchors - tags) as u64;
+            let finals_start = anchors as u64 * poll + tags as u64 * response + 2 * guard;
+            (finals_start + idx * final_, final_)
+        };
+
+        let start = self.priority_slot().1 + offset;
+        Some((start, start + len))
+    }
+
+    /// Length of the slot of one frame in nanoseconds: its air time plus the idle time.
+    fn frame_slot_len(&self, packet_type: PacketType, config: &Config) -> u64 {
+        (packet_tx_time(packet_type, self.num_tags, config) + self.inter_frame_idle) as u64
+    }
+
+    /// Number of frames transmitted in one round.
+    pub fn frames_per_round(&self) -> usize {
+        2 * self.num_anchors + self.num_tags
+    }
+
+    /// Lowest free slot for a joining node, `None` if all slots are taken.
+    ///
+    /// `occupied` is the bitmask of taken slots. There are 16 slots per node kind, the capacity
+    /// of the per-node vectors of the state machines.
+    pub fn free_slot(&self, occupied: u16) -> Option<u16> {
+        match (!occupied).trailing_zeros() {
+            16 => None,
+            slot => Some(slot as u16),
+        }
+    }
+
+    /// Index of the TDMA frame the device timestamp `ts` falls in, counting from the frame that
+    /// starts at `frame_start`, with frames `frame_duration` device time units long.
+    ///
+    /// Across 40-bit rollovers, as long as `ts` is less than one counter period after
+    /// `frame_start`. Will panic if `frame_duration` is zero.
+    pub fn frame_number(&self, ts: u64, frame_start: u64, frame_duration: u64) -> u64 {
+        wrapping_interval(frame_start, ts) / frame_duration
+    }
+
+    /// Device time to start the next round, `target_period` device time units after the start
+    /// of the previous one, across 40-bit rollovers.
+    ///
+    /// Keeps the rounds evenly spaced whatever their durations, for a constant update rate.
+    pub fn next_round_start(&self, prev_round_start: u64, target_period: u64) -> u64 {
+        prev_round_start.wrapping_add(target_period) & TIMESTAMP_MASK
+    }
+
+    /// Like `next_round_start`, but skips periods while the previous round, which ended at
+    /// `prev_round_end`, overran the start.
+    ///
+    /// The returned start stays on the grid of the previous starts, so the update rate recovers
+    /// once the rounds fit again. Will panic if `target_period` is zero.
+    pub fn next_round_start_after(
+        &self,
+        prev_round_start: u64,
+        target_period: u64,
+        prev_round_end: u64,
+    ) -> u64 {
+        let next = self.next_round_start(prev_round_start, target_period);
+        if wrapping_before(prev_round_end, next, 40) {
+            return next;
+        }
+
+        let periods = wrapping_interval(prev_round_start, prev_round_end) / target_period + 1;
+        prev_round_start.wrapping_add(periods * target_period) & TIMESTAMP_MASK
+    }
+
+    /// Duration of one full ranging round in nanoseconds, air time plus idle time.
+    ///
+    /// Without anchors or tags no ranging takes place and the duration is zero.
+    pub fn round_duration(&self, config: &Config) -> u32 {
+        if self.num_anchors == 0 || self.num_tags == 0 {
+            return 0;
+        }
+
+        round_air_time(self.num_anchors, self.num_tags, config)
+            + self.frames_per_round() as u32 * self.inter_frame_idle
+    }
+
+    /// Duration of a superframe in nanoseconds: the beacon and priority slots and the ranging
+    /// round, including the guard time after every phase (see `round_budget`).
+    pub fn superframe_duration(&self, config: &Config) -> u32 {
+        self.beacon_duration + self.priority_slot_duration + self.round_budget(config)
+    }
+
+    /// Check that a superframe fits in the frame: the beacon and priority slots, the air time of
+    /// the round, the idle time after every frame, and the guard time after every phase (see
+    /// `superframe_duration`).
+    ///
+    /// Always passes without a frame duration.
+    pub fn validate_fits(&self, config: &Config) -> Result<(), ScheduleError> {
+        let Some(frame_duration) = self.frame_duration else {
+            return Ok(());
+        };
+
+        match self.superframe_duration(config).checked_sub(frame_duration) {
+            Some(excess) if excess > 0 => Err(ScheduleError::FrameOverflow { excess }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Measured minus expected duration of a round that started at device time `start` and
+    /// ended at `end`, in device time units.
+    ///
+    /// Expected is the round budget: air time, idle time after every frame, and the guard time
+    /// after every phase. A persistently large positive error means the node is falling behind
+    /// its TDMA schedule, e.g. through slow event handling or a drifting clock.
+    pub fn round_latency_error(&self, start: u64, end: u64, config: &Config) -> i64 {
+        let expected = libm::round(self.round_budget(config) as f64 * 1e-9 / DWT_TIME_UNIT) as i64;
+
+        wrapping_interval(start, end) as i64 - expected
+    }
+
+    /// Worst-case duration of one round in nanoseconds, `round_duration` plus the guard time
+    /// after every phase. Zero without anchors or tags.
+    fn round_budget(&self, config: &Config) -> u32 {
+        match self.round_duration(config) {
+            0 => 0,
+            duration => duration + 3 * PHASE_GUARD_TIME,
+        }
+    }
+
+    /// Number of full ranging rounds per second this schedule supports, one per superframe (see
+    /// `superframe_duration`).
+    ///
+    /// Zero if the schedule has no anchors or no tags.
+    pub fn update_rate_hz(&self, config: &Config) -> f32 {
+        if self.round_duration(config) == 0 {
+            return 0.0;
+        }
+
+        (1e9 / self.superframe_duration(config) as f64) as f32
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dw3000_ng::configs::{BitRate, PreambleLength, PulseRepetitionFrequency};
+
+    fn config_6m8() -> Config {
+        Config {
+            bitrate: BitRate::Kbps6800,
+            pulse_repetition_frequency: PulseRepetitionFrequency::Mhz64,
+            preamble_length: PreambleLength::Symbols128,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_update_rate_hz() {
+        let config = config_6m8();
+        let mut schedule = Schedule::new(8, 3);
+        schedule.inter_frame_idle = 100_000;
+
+        // 3_722_296 ns of air time (see `util::round_budget`) plus 19 idle gaps, and 3 phase
+        // guards of 500 us per superframe
+        assert_eq!(schedule.frames_per_round(), 19);
+        assert_eq!(schedule.round_duration(&config), 5_622_296);
+        assert_eq!(schedule.superframe_duration(&config), 7_122_296);
+        assert!((schedule.update_rate_hz(&config) - 140.40).abs() < 0.01);
+
+        // The beacon slot takes its share too
+        schedule.reserve_beacon_slot(&config);
+        let superframe = schedule.superframe_duration(&config) as f32;
+        assert!((schedule.update_rate_hz(&config) - 1e9 / superframe).abs() < 0.01);
+        assert!(schedule.update_rate_hz(&config) < 140.40);
+
+        assert_eq!(Schedule::new(8, 0).update_rate_hz(&config), 0.0);
+    }
+
+    #[test]
+    fn test_validate_fits() {
+        let config = config_6m8();
+        let mut schedule = Schedule::new(8, 3);
+        schedule.inter_frame_idle = 100_000;
+        assert_eq!(schedule.validate_fits(&config), Ok(()));
+
+        // 5_622_296 ns per round plus 3 phase guards of 500 us
+        schedule.frame_duration = Some(6_000_000);
+        assert_eq!(
+            schedule.validate_fits(&config),
+            Err(ScheduleError::FrameOverflow { excess: 1_122_296 })
+        );
+
+        schedule.frame_duration = Some(7_122_296);
+        assert_eq!(schedule.validate_fits(&config), Ok(()));
+    }
+
+    #[test]
+    fn test_beacon_slot() {
+        let config = config_6m8();
+        let mut schedule = Schedule::new(4, 2);
+        schedule.inter_frame_idle = 100_000;
+        assert_eq!(schedule.beacon_slot(), (0, 0));
+        // Poll air time of 189_793 ns plus the idle time
+        assert_eq!(schedule.ranging_slot(0, &config), Some((0, 289_793)));
+
+        schedule.reserve_beacon_slot(&config);
+        let (beacon_start, beacon_end) = schedule.beacon_slot();
+        assert_eq!((beacon_start, beacon_end), (0, 289_793));
+
+        // Back to back after the beacon, without overlaps, with a guard time after the polls and
+        // after the responses
+        let guard = PHASE_GUARD_TIME as u64;
+        let mut end = beacon_end;
+        for frame_idx in 0..schedule.frames_per_round() {
+            let (start, slot_end) = schedule.ranging_slot(frame_idx, &config).unwrap();
+            match frame_idx {
+                4 | 6 => assert_eq!(start, end + guard),
+                _ => assert_eq!(start, end),
+            }
+            assert!(slot_end > start);
+            end = slot_end;
+        }
+        assert_eq!(schedule.ranging_slot(10, &config), None);
+
+        // And after the finals
+        assert_eq!(
+            schedule.superframe_duration(&config),
+            beacon_end as u32 + schedule.round_duration(&config) + 3 * PHASE_GUARD_TIME
+        );
+        assert_eq!(end + guard, schedule.superframe_duration(&config) as u64);
+
+        // A frame of exactly one superframe fits
+        schedule.frame_duration = Some(schedule.superframe_duration(&config));
+        assert_eq!(schedule.validate_fits(&config), Ok(()));
+    }
+
+    #[test]
+    fn test_priority_slot() {
+        let config = config_6m8();
+        let mut schedule = Schedule::new(4, 2);
+        schedule.inter_frame_idle = 100_000;
+        schedule.reserve_beacon_slot(&config);
+
+        // Without a reserved slot, priority rounds use the regular slot
+        let regular = schedule.tx_slot(1, false, &config).unwrap();
+        assert_eq!(schedule.tx_slot(1, true, &config), Some(regular));
+        assert_eq!(regular, schedule.ranging_slot(5, &config).unwrap());
+
+        assert_eq!(schedule.priority_frame_slot(0, &config), None);
+
+        schedule.reserve_priority_slot(&config);
+        let priority = schedule.priority_slot();
+        assert_eq!(priority.0, schedule.beacon_slot().1);
+
+        // Poll, priority response and final back to back, filling the slot
+        let frames: [_; 3] =
+            core::array::from_fn(|i| schedule.priority_frame_slot(i, &config).unwrap());
+        assert_eq!(frames[0].0, priority.0);
+        assert_eq!(frames[0].1, frames[1].0);
+        assert_eq!(frames[1].1, frames[2].0);
+        assert_eq!(frames[2].1, priority.1);
+        assert_eq!(schedule.priority_frame_slot(3, &config), None);
+
+        // The tag sends its priority response there
+        assert_eq!(schedule.tx_slot(1, true, &config), Some(frames[1]));
+
+        // The ranging round moves behind it
+        let regular = schedule.tx_slot(1, false, &config).unwrap();
+        assert!(regular.0 > priority.1);
+        assert_eq!(schedule.ranging_slot(0, &config).unwrap().0, priority.1);
+        assert_eq!(
+            schedule.superframe_duration(&config),
+            priority.1 as u32 + schedule.round_duration(&config) + 3 * PHASE_GUARD_TIME
+        );
+
+        assert_eq!(schedule.tx_slot(2, true, &config), None);
+    }
+
+    #[test]
+    fn test_round_latency_error() {
+        let config = config_6m8();
+        let mut schedule = Schedule::new(8, 3);
+        schedule.inter_frame_idle = 100_000;
+
+        // 7_122_296 ns of budget are ~455_097_621 ticks
+        let expected = libm::round(7_122_296e-9 / DWT_TIME_UNIT) as u64;
+        let start = TIMESTAMP_MASK - 1_000;
+        let on_time = (start + expected) & TIMESTAMP_MASK;
+        assert_eq!(schedule.round_latency_error(start, on_time, &config), 0);
+
+        // 100 us late
+        let late = (on_time + 6_389_760) & TIMESTAMP_MASK;
+        assert_eq!(
+            schedule.round_latency_error(start, late, &config),
+            6_389_760
+        );
+        assert!(schedule.round_latency_error(start, on_time - 5_000, &config) < 0);
+    }
+
+    #[test]
+    fn test_next_round_start() {
+        let schedule = Schedule::new(4, 2);
+        let period = 1_000_000;
+
+        // Evenly spaced across the rollover
+        let mut start = TIMESTAMP_MASK - 2_500_000;
+        let mut starts = [0; 4];
+        for s in starts.iter_mut() {
+            start = schedule.next_round_start(start, period);
+            *s = start;
+        }
+        for pair in starts.windows(2) {
+            assert_eq!(wrapping_interval(pair[0], pair[1]), period);
+        }
+        assert_eq!(starts[3], 1_499_999);
+
+        // A round that ends in time keeps the grid
+        let start = TIMESTAMP_MASK - 500_000;
+        assert_eq!(
+            schedule.next_round_start_after(start, period, 400_000),
+            499_999
+        );
+        // Overrunning by one or two periods skips as many
+        assert_eq!(
+            schedule.next_round_start_after(start, period, 499_999),
+            1_499_999
+        );
+        assert_eq!(
+            schedule.next_round_start_after(start, period, 1_700_000),
+            2_499_999
+        );
+    }
+
+    #[test]
+    fn test_free_slot() {
+        let schedule = Schedule::new(4, 2);
+
+        assert_eq!(schedule.free_slot(0), Some(0));
+        assert_eq!(schedule.free_slot(0b0001_0111), Some(3));
+        assert_eq!(schedule.free_slot(0x7fff), Some(15));
+        assert_eq!(schedule.free_slot(u16::MAX), None);
+    }
+
+    #[test]
+    fn test_frame_number() {
+        let schedule = Schedule::new(4, 2);
+        let frame_start = TIMESTAMP_MASK - 1_500;
+
+        // The first frame straddles the rollover
+        assert_eq!(schedule.frame_number(frame_start, frame_start, 1_000), 0);
+        assert_eq!(
+            schedule.frame_number(frame_start + 999, frame_start, 1_000),
+            0
+        );
+        assert_eq!(schedule.frame_number(200, frame_start, 1_000), 1);
+        assert_eq!(schedule.frame_number(498, frame_start, 1_000), 1);
+        assert_eq!(schedule.frame_number(499, frame_start, 1_000), 2);
+    }
+
+    #[test]
+    fn test_message_counts() {
+        let counts = message_counts(8, 3);
+        assert_eq!(
+            counts,
+            MessageCounts {
+                anchor_tx: 2,
+                anchor_rx: 3,
+                tag_tx: 1,
+                tag_rx: 16,
+            }
+        );
+
+        // Every frame sent is one frame of the schedule
+        assert_eq!(
+            8 * counts.anchor_tx + 3 * counts.tag_tx,
+            Schedule::new(8, 3).frames_per_round()
+        );
+
+        assert_eq!(message_counts(0, 3).tag_rx, 0);
+    }
+}
@@ -0,0 +1,404 @@
+// Pipelined driver for the anchor side of the protocol
+//
+// To increase throughput, consecutive rounds are allowed to overlap: while an anchor is still
+// sending the final for round N, the polls for round N + 1 may already go out. The scheduler
+// keeps one state machine per in-flight round so the timestamps of the two rounds never mix.
+//
+// All times are 40-bit device timestamps and compared on the wrapping counter, so the schedule
+// runs on across rollovers.
+//
+// The root anchor additionally sends the sync beacon ahead of every round, in the beacon slot of
+// the superframe (see `Schedule::reserve_beacon_slot`).
+use crate::anchor_state_machine::{AnchorSideStateMachine, AnyAnchorSideStateMachine, Idle};
+use crate::util::{wrapping_before, wrapping_interval, TIMESTAMP_MASK};
+use crate::Phase;
+
+/// Whether `deadline` is reached at `now`, on the wrapping 40-bit counter.
+fn is_due(now: u64, deadline: u64) -> bool {
+    !wrapping_before(now, deadline, 40)
+}
+
+/// Timing of a pipelined round, all values in device time units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineTiming {
+    /// Time between the starts of two consecutive rounds
+    pub period: u64,
+
+    /// Offset from the round start to the start of the response window
+    pub response_offset: u64,
+
+    /// Offset from the round start to the final TX (end of the response window)
+    pub final_offset: u64,
+}
+
+/// Next action the driver should perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Transmit the sync beacon ahead of the next round: a poll carrying its TX timestamp.
+    SendBeacon,
+
+    /// Transmit the poll for the round in `slot`.
+    SendPoll { slot: usize },
+
+    /// Transmit the final for the round in `slot`.
+    SendFinal { slot: usize },
+}
+
+/// Scheduler tracking two in-flight rounds of an anchor.
+#[derive(Debug)]
+pub struct PipelinedScheduler {
+    /// One state machine per in-flight round
+    rounds: [AnyAnchorSideStateMachine; 2],
+
+    /// Start time of the round in each slot, `None` if the slot is free
+    round_start: [Option<u64>; 2],
+
+    /// Round timing
+    timing: PipelineTiming,
+
+    /// Start time of the next round
+    next_start: u64,
+
+    /// Slot the next round will use
+    next_slot: usize,
+
+    /// Time between the beacon and the start of the round it precedes, `None` if this anchor
+    /// sends no beacons
+    beacon_lead: Option<u64>,
+
+    /// Whether the beacon ahead of the next round was sent
+    beacon_sent: bool,
+}
+
+impl PipelinedScheduler {
+    /// Create a new scheduler, with the first round starting at `first_start`.
+    ///
+    /// Will panic if the response windows of two consecutive rounds would overlap, since the
+    /// responses could then not be attributed to a round.
+    pub fn new(
+        state_machine: AnchorSideStateMachine<Idle>,
+        timing: PipelineTiming,
+        first_start: u64,
+    ) -> Self {
+        assert!(timing.response_offset <= timing.final_offset);
+        assert!(timing.period >= timing.final_offset - timing.response_offset);
+
+        Self {
+            rounds: [state_machine.clone().into(), state_machine.into()],
+            round_start: [None, None],
+            timing,
+            next_start: first_start,
+            next_slot: 0,
+            beacon_lead: None,
+            beacon_sent: false,
+        }
+    }
+
+    /// Create a new scheduler for the root anchor, which sends a sync beacon `beacon_lead`
+    /// before the start of every round, the first one starting at `first_start`.
+    ///
+    /// `beacon_lead` is the length of the beacon slot (and priority slot, if any) in device time
+    /// units. The timing must leave room for it between the final of a round and the poll of
+    /// the next one, or the beacon waits for the final.
+    pub fn with_beacon(
+        state_machine: AnchorSideStateMachine<Idle>,
+        timing: PipelineTiming,
+        first_start: u64,
+        beacon_lead: u64,
+    ) -> Self {
+        Self {
+            beacon_lead: Some(beacon_lead),
+            ..Self::new(state_machine, timing, first_start)
+        }
+    }
+
+    /// Get the state machine of the round in `slot`.
+    ///
+    /// Will panic if `slot` is not 0 or 1.
+    pub fn round(&self, slot: usize) -> &AnyAnchorSideStateMachine {
+        &self.rounds[slot]
+    }
+
+    /// Get a mutable reference to the state machine of the round in `slot`.
+    ///
+    /// Will panic if `slot` is not 0 or 1.
+    pub fn round_mut(&mut self, slot: usize) -> &mut AnyAnchorSideStateMachine {
+        &mut self.rounds[slot]
+    }
+
+    /// Get the next action due at `now`, if any.
+    ///
+    /// Finals of the older round take priority over the beacon and the poll of the next round.
+    /// A round whose poll was never sent is dropped once its final would be due, freeing its
+    /// slot.
+    pub fn advance(&mut self, now: u64) -> Option<Action> {
+        for slot in 0..2 {
+            let Some(start) = self.round_start[slot] else {
+                continue;
+            };
+
+            if !is_due(now, (start + self.timing.final_offset) & TIMESTAMP_MASK) {
+                continue;
+            }
+            if self.rounds[slot].to_sending_final().is_ok() {
+                return Some(Action::SendFinal { slot });
+            }
+            if self.rounds[slot].phase() == Phase::Poll {
+                self.round_start[slot] = None;
+            }
+        }
+
+        let slot = self.next_slot;
+        if self.round_start[slot].is_some() {
+            return None;
+        }
+
+        if let Some(lead) = self.beacon_lead {
+            let beacon_start = self.next_start.wrapping_sub(lead) & TIMESTAMP_MASK;
+            if !self.beacon_sent && is_due(now, beacon_start) {
+                self.beacon_sent = true;
+                return Some(Action::SendBeacon);
+            }
+        }
+
+        if is_due(now, self.next_start) {
+            self.round_start[slot] = Some(self.next_start);
+            self.next_start = (self.next_start + self.timing.period) & TIMESTAMP_MASK;
+            self.next_slot = 1 - slot;
+            self.beacon_sent = false;
+
+            return Some(Action::SendPoll { slot });
+        }
+
+        None
+    }
+
+    /// Record that the poll of the round in `slot` was sent.
+    ///
+    /// Error if `slot` is not 0 or 1, or its round is not waiting to poll.
+    pub fn poll_sent(&mut self, slot: usize, poll_tx_ts: u64) -> Result<(), ()> {
+        self.rounds
+            .get_mut(slot)
+            .ok_or(())?
+            .to_waiting_for_response(poll_tx_ts)
+    }
+
+    /// Record a response, attributing it to the round whose response window contains `rx_ts`.
+    ///
+    /// Returns the slot of the round, error if no round is waiting for a response at `rx_ts` or
+    /// `tag_idx` is not a valid tag index.
+    pub fn response_received(&mut self, tag_idx: usize, rx_ts: u64) -> Result<usize, ()> {
+        for slot in 0..2 {
+            let Some(start) = self.round_start[slot] else {
+                continue;
+            };
+
+            let window_start = (start + self.timing.response_offset) & TIMESTAMP_MASK;
+            let window_len = self.timing.final_offset - self.timing.response_offset;
+            if wrapping_interval(window_start, rx_ts) >= window_len {
+                continue;
+            }
+
+            if let Some(state_machine) = self.rounds[slot].as_waiting_for_response_mut() {
+                return state_machine
+                    .set_response_rx_ts_checked(tag_idx, rx_ts)
+                    .map(|()| slot)
+                    .map_err(|_| ());
+            }
+        }
+
+        Err(())
+    }
+
+    /// Record that the final of the round in `slot` was sent, freeing the slot.
+    ///
+    /// Error if `slot` is not 0 or 1, or its round is not sending the final.
+    pub fn final_sent(&mut self, slot: usize) -> Result<(), ()> {
+        self.rounds.get_mut(slot).ok_or(())?.to_idle()?;
+        self.round_start[slot] = None;
+
+        Ok(())
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::anchor_state_machine::SendingFinal;
+    use heapless::Vec;
+
+    #[test]
+    fn test_overlapping_rounds() {
+        let timing = PipelineTiming {
+            period: 1000,
+            response_offset: 600,
+            final_offset: 1300,
+        };
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100, 101]));
+        let mut scheduler = PipelinedScheduler::new(state_machine, timing, 0);
+
+        // Round 0
+        assert_eq!(scheduler.advance(0), Some(Action::SendPoll { slot: 0 }));
+        scheduler.poll_sent(0, 0).unwrap();
+        assert_eq!(scheduler.advance(100), None);
+        assert_eq!(scheduler.response_received(0, 700), Ok(0));
+        assert_eq!(scheduler.response_received(1, 800), Ok(0));
+
+        // Round 1 polls before round 0 sends its final
+        assert_eq!(scheduler.advance(1000), Some(Action::SendPoll { slot: 1 }));
+        scheduler.poll_sent(1, 1000).unwrap();
+
+        assert_eq!(scheduler.advance(1300), Some(Action::SendFinal { slot: 0 }));
+        {
+            let round_0: &AnchorSideStateMachine<SendingFinal> =
+                scheduler.round(0).try_into().unwrap();
+            assert_eq!(round_0.poll_tx_ts, Some(0));
+            assert_eq!(round_0.get_response_rx_ts(0), Some(700));
+            assert_eq!(round_0.get_response_rx_ts(1), Some(800));
+        }
+        scheduler.final_sent(0).unwrap();
+
+        // Response outside of any window
+        assert!(scheduler.response_received(0, 1400).is_err());
+
+        assert_eq!(scheduler.response_received(0, 1650), Ok(1));
+        assert_eq!(scheduler.advance(2000), Some(Action::SendPoll { slot: 0 }));
+        scheduler.poll_sent(0, 2000).unwrap();
+        assert_eq!(scheduler.advance(2300), Some(Action::SendFinal { slot: 1 }));
+
+        let round_1: &AnchorSideStateMachine<SendingFinal> = scheduler.round(1).try_into().unwrap();
+        assert_eq!(round_1.poll_tx_ts, Some(1000));
+        assert_eq!(round_1.get_response_rx_ts(0), Some(1650));
+        assert_eq!(round_1.get_response_rx_ts(1), None);
+
+        // Round 2 reuses slot 0 without inheriting the responses of round 0
+        assert_eq!(scheduler.response_received(1, 2700), Ok(0));
+        assert_eq!(scheduler.advance(3300), Some(Action::SendFinal { slot: 0 }));
+
+        let round_2: &AnchorSideStateMachine<SendingFinal> = scheduler.round(0).try_into().unwrap();
+        assert_eq!(round_2.get_response_rx_ts(0), None);
+        assert_eq!(round_2.get_response_rx_ts(1), Some(2700));
+    }
+
+    #[test]
+    fn test_rollover() {
+        let timing = PipelineTiming {
+            period: 1000,
+            response_offset: 600,
+            final_offset: 1300,
+        };
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100]));
+        // Round 0 starts 800 ticks before the 40-bit rollover
+        let start = TIMESTAMP_MASK - 799;
+        let mut scheduler = PipelinedScheduler::new(state_machine, timing, start);
+
+        assert_eq!(scheduler.advance(start), Some(Action::SendPoll { slot: 0 }));
+        scheduler.poll_sent(0, start).unwrap();
+        // Before the rollover the final is not due yet
+        assert_eq!(scheduler.advance(TIMESTAMP_MASK), None);
+
+        // The response window of round 0 spans the rollover
+        assert_eq!(scheduler.response_received(0, 100), Ok(0));
+        assert!(scheduler
+            .response_received(0, TIMESTAMP_MASK - 300)
+            .is_err());
+
+        assert_eq!(scheduler.advance(200), Some(Action::SendPoll { slot: 1 }));
+        scheduler.poll_sent(1, 200).unwrap();
+        assert_eq!(scheduler.advance(400), None);
+        assert_eq!(scheduler.advance(500), Some(Action::SendFinal { slot: 0 }));
+
+        let round_0: &AnchorSideStateMachine<SendingFinal> = scheduler.round(0).try_into().unwrap();
+        assert_eq!(round_0.get_response_rx_ts(0), Some(100));
+    }
+
+    #[test]
+    fn test_root_beacon() {
+        let timing = PipelineTiming {
+            period: 1000,
+            response_offset: 300,
+            final_offset: 600,
+        };
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut scheduler = PipelinedScheduler::with_beacon(state_machine, timing, 200, 200);
+
+        // Every round is preceded by the beacon, in the slot before the round start
+        for round_start in [200, 1200, 2200] {
+            assert_eq!(
+                scheduler.advance(round_start - 200),
+                Some(Action::SendBeacon)
+            );
+            assert_eq!(scheduler.advance(round_start - 100), None);
+            let Some(Action::SendPoll { slot }) = scheduler.advance(round_start) else {
+                panic!("no poll at {}", round_start);
+            };
+            scheduler.poll_sent(slot, round_start).unwrap();
+
+            assert_eq!(
+                scheduler.advance(round_start + 600),
+                Some(Action::SendFinal { slot })
+            );
+            scheduler.final_sent(slot).unwrap();
+        }
+
+        // Anchors other than the root send no beacon
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut scheduler = PipelinedScheduler::new(state_machine, timing, 200);
+        assert_eq!(scheduler.advance(0), None);
+        assert_eq!(scheduler.advance(200), Some(Action::SendPoll { slot: 0 }));
+    }
+
+    #[test]
+    fn test_invalid_indices() {
+        let timing = PipelineTiming {
+            period: 1000,
+            response_offset: 600,
+            final_offset: 1300,
+        };
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut scheduler = PipelinedScheduler::new(state_machine, timing, 0);
+
+        assert_eq!(scheduler.advance(0), Some(Action::SendPoll { slot: 0 }));
+        assert!(scheduler.poll_sent(2, 0).is_err());
+        scheduler.poll_sent(0, 0).unwrap();
+
+        // A tag index off the air does not abort the anchor
+        assert!(scheduler.response_received(1, 700).is_err());
+        assert_eq!(scheduler.response_received(0, 700), Ok(0));
+
+        assert_eq!(scheduler.advance(1000), Some(Action::SendPoll { slot: 1 }));
+        assert_eq!(scheduler.advance(1300), Some(Action::SendFinal { slot: 0 }));
+        assert!(scheduler.final_sent(2).is_err());
+        scheduler.final_sent(0).unwrap();
+    }
+
+    #[test]
+    fn test_unsent_poll_frees_slot() {
+        let timing = PipelineTiming {
+            period: 1000,
+            response_offset: 600,
+            final_offset: 1300,
+        };
+        let state_machine =
+            AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut scheduler = PipelinedScheduler::new(state_machine, timing, 0);
+
+        // The poll of round 0 is never sent
+        assert_eq!(scheduler.advance(0), Some(Action::SendPoll { slot: 0 }));
+        assert_eq!(scheduler.advance(1000), Some(Action::SendPoll { slot: 1 }));
+        scheduler.poll_sent(1, 1000).unwrap();
+
+        // No final for round 0, and slot 0 is free again for round 2
+        assert_eq!(scheduler.advance(1300), None);
+        assert_eq!(scheduler.advance(2000), Some(Action::SendPoll { slot: 0 }));
+        assert_eq!(scheduler.round(0).phase(), Phase::Poll);
+    }
+}
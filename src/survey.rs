@@ -0,0 +1,138 @@
+// Survey of the anchor network
+//
+// Before tags can be located, the anchors need to know about each other. An anchor ranges
+// against each of its peers with a two-way exchange: it polls, the peer responds. Besides the
+// distance, each exchange reveals the offset of the peer's clock against this anchor's clock,
+// which is what `TimeSync` needs to bootstrap.
+
+use heapless::Vec;
+
+use crate::time_sync::signed_diff;
+use crate::util::{wrapping_interval, DWT_TIME_UNIT, SPEED_OF_LIGHT};
+
+/// Timestamps of one poll/response exchange with a peer anchor, in device time units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SurveyExchange {
+    /// Poll TX timestamp (in this anchor's time)
+    pub poll_tx_ts: u64,
+
+    /// Poll RX timestamp (in the peer's time)
+    pub poll_rx_ts: u64,
+
+    /// Response TX timestamp (in the peer's time)
+    pub response_tx_ts: u64,
+
+    /// Response RX timestamp (in this anchor's time)
+    pub response_rx_ts: u64,
+}
+
+impl SurveyExchange {
+    /// Time of flight in device time units, single-sided.
+    pub fn tof_ticks(&self) -> f64 {
+        let round = wrapping_interval(self.poll_tx_ts, self.response_rx_ts) as f64;
+        let reply = wrapping_interval(self.poll_rx_ts, self.response_tx_ts) as f64;
+
+        (round - reply) / 2.0
+    }
+
+    /// Offset of the peer's clock against this anchor's clock, in device time units.
+    ///
+    /// The poll arrived one time of flight after it was sent, so whatever else separates the
+    /// two timestamps is the offset between the clocks.
+    pub fn clock_offset(&self) -> i64 {
+        signed_diff(self.poll_rx_ts, self.poll_tx_ts) - libm::round(self.tof_ticks()) as i64
+    }
+}
+
+/// Survey of the peer anchors of one anchor.
+#[derive(Debug, Clone, Default)]
+pub struct AnchorSurvey {
+    /// Peer anchor addresses
+    peers: Vec<u16, 16>,
+
+    /// Last exchange with each peer, index-aligned with `peers`
+    exchanges: Vec<Option<SurveyExchange>, 16>,
+}
+
+impl AnchorSurvey {
+    /// Create a new `AnchorSurvey` of the anchors `peers`, without exchanges.
+    pub fn new(peers: Vec<u16, 16>) -> Self {
+        Self {
+            exchanges: Vec::from_iter(peers.iter().map(|_| None)),
+            peers,
+        }
+    }
+
+    /// Get the index of a peer anchor from its address.
+    pub fn peer_index(&self, peer_addr: u16) -> Option<usize> {
+        self.peers.iter().position(|&addr| addr == peer_addr)
+    }
+
+    /// Record an exchange with a peer, replacing the previous one.
+    ///
+    /// Will panic if `peer_idx` is not a valid peer index.
+    pub fn record_exchange(&mut self, peer_idx: usize, exchange: SurveyExchange) {
+        self.exchanges[peer_idx] = Some(exchange);
+    }
+
+    /// Distance to a peer in meters, `None` without an exchange.
+    pub fn distance_m(&self, peer_idx: usize) -> Option<f64> {
+        let exchange = self.exchanges.get(peer_idx).copied().flatten()?;
+
+        Some(exchange.tof_ticks() * DWT_TIME_UNIT * SPEED_OF_LIGHT)
+    }
+
+    /// `(peer address, clock offset)` of every peer with an exchange, the offset being the
+    /// peer's clock minus this anchor's clock in device time units.
+    pub fn clock_offset_table(&self) -> Vec<(u16, i64), 16> {
+        self.peers
+            .iter()
+            .zip(&self.exchanges)
+            .filter_map(|(&addr, exchange)| Some((addr, exchange.as_ref()?.clock_offset())))
+            .collect()
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::TIMESTAMP_MASK;
+
+    #[test]
+    fn test_clock_offset_table() {
+        const TOF: u64 = 2_000;
+
+        let mut survey = AnchorSurvey::new(Vec::from_iter([1, 2, 3, 4]));
+
+        // Peer clocks offset against ours, one of them wrapping around the 40-bit counter
+        let offsets: [i64; 3] = [5_000_000, -123_456, 1 << 38];
+        for (peer_idx, &offset) in offsets.iter().enumerate() {
+            let poll_tx_ts = TIMESTAMP_MASK - 1_000;
+            let to_peer = |ts: u64| ts.wrapping_add_signed(offset) & TIMESTAMP_MASK;
+            let poll_rx_ts = to_peer(poll_tx_ts + TOF);
+            let response_tx_ts = (poll_rx_ts + 300_000) & TIMESTAMP_MASK;
+            let response_rx_ts = (poll_tx_ts + TOF + 300_000 + TOF) & TIMESTAMP_MASK;
+
+            survey.record_exchange(
+                peer_idx,
+                SurveyExchange {
+                    poll_tx_ts,
+                    poll_rx_ts,
+                    response_tx_ts,
+                    response_rx_ts,
+                },
+            );
+        }
+
+        let table = survey.clock_offset_table();
+        assert_eq!(table, [(1, offsets[0]), (2, offsets[1]), (3, offsets[2])]);
+
+        let expected = TOF as f64 * DWT_TIME_UNIT * SPEED_OF_LIGHT;
+        assert!((survey.distance_m(1).unwrap() - expected).abs() < 1e-9);
+        assert_eq!(survey.distance_m(3), None);
+        assert_eq!(survey.peer_index(4), Some(3));
+    }
+}
@@ -1,4 +1,15 @@
 use heapless::Vec;
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::anchor_state_machine::IndexError;
+use crate::calibration::AntennaDelays;
+use crate::packet::{FinalPacket, PollPacket};
+use crate::time_sync::{signed_diff, TimeSync};
+use crate::util::{
+    check_disjoint, wrapping_interval, RxMetadata, DWT_TIME_UNIT, MAX_TIMESTAMP_INTERVAL,
+    SPEED_OF_LIGHT, TIMESTAMP_MASK,
+};
+use crate::{sealed, Phase, TerminalState, WaitingState, WrongPhase};
 
 /// Type-state state machine for the multi-anchor AltDS-TWR protocol, tag side.
 ///
@@ -10,7 +21,13 @@ use heapless::Vec;
 /// 3. All anchors send a final message to all tags.
 ///
 /// At the end of the protocol, the tags will have the distance to all anchors.
-#[derive(Debug, Default)]
+///
+/// All per-anchor vectors are index-aligned with `anchors`: entry `i` of `poll_tx_ts`,
+/// `poll_rx_ts`, `response_rx_ts`, `final_tx_ts`, `final_rx_ts`, `rssi` (and of the clock offset
+/// ratios) belongs to the anchor `anchors[i]`, and every one of them has exactly one entry per
+/// anchor. The public vectors must only be written in place, never resized. Debug builds check
+/// this after every mutation.
+#[derive(Debug)]
 pub struct TagSideStateMachine<STATE> {
     /// My address
     address: u16,
@@ -30,6 +47,9 @@ pub struct TagSideStateMachine<STATE> {
     /// Response TX timestamp (in tag time)
     pub response_tx_ts: u64,
 
+    /// Whether `response_tx_ts` was set in this round, zero being a valid timestamp
+    response_tx_recorded: bool,
+
     /// Response RX timestamps (in anchor time)
     pub response_rx_ts: Vec<u64, 16>,
 
@@ -39,10 +59,96 @@ pub struct TagSideStateMachine<STATE> {
     /// Final RX timestamps (in tag time)
     pub final_rx_ts: Vec<u64, 16>,
 
+    /// RSSI of the last message received from each anchor (in dBm)
+    pub rssi: Vec<f32, 16>,
+
+    /// Clock offset of each anchor against the tag as a ratio, 0 if unknown
+    clock_offset_ratios: Vec<f64, 16>,
+
+    /// Source address of the poll recorded for each anchor, `None` if unknown
+    poll_sources: Vec<Option<u16>, 16>,
+
+    /// Source address of the final recorded for each anchor, `None` if unknown
+    final_sources: Vec<Option<u16>, 16>,
+
+    /// Maximum plausible distance to an anchor in meters, `None` to accept any distance
+    max_range_m: Option<f64>,
+
+    /// Duration of one device time unit in picoseconds
+    tick_period_ps: f64,
+
+    /// Propagation speed of the signal in the medium, in m/s
+    propagation_speed_m_per_s: f64,
+
+    /// Antenna delays of the tag's radio, applied to its own timestamps in the TOF math
+    antenna_delays: AntennaDelays,
+
+    /// Weight of a new distance in the per-anchor smoothing, `None` to disable smoothing
+    smoothing_alpha: Option<f32>,
+
+    /// Smoothed distance (in meters) of every anchor across rounds, NaN where there is none.
+    ///
+    /// Empty until the first round with smoothing enabled is finalized.
+    smoothed_distances: Vec<f32, 16>,
+
+    /// Anchors the tag ranges against (bitmask by anchor index), others are ignored
+    active_anchors: u16,
+
+    /// Anchors excluded until `clear_blacklist`, on top of `active_anchors` (bitmask by index)
+    blacklisted_anchors: u16,
+
+    /// Anchors whose poll was received in this round (bitmask by anchor index)
+    polls_received: u16,
+
+    /// Anchors whose final was received in this round (bitmask by anchor index)
+    finals_received: u16,
+
+    /// Double-sided distances (in meters) from the last `compute_and_cache`, `None` when the
+    /// cache is invalid
+    cached_distances: Option<Vec<Option<f64>, 16>>,
+
     /// The current state of the state machine.
     _state: STATE,
 }
 
+/// A state machine without anchors or tags, with the same configuration as `new`.
+///
+/// The type-erased wrappers `mem::take` the machine out of their state on every transition, so
+/// this must not leave the tick period or the propagation speed at zero.
+impl<STATE: Default> Default for TagSideStateMachine<STATE> {
+    fn default() -> Self {
+        Self {
+            address: 0,
+            anchors: Vec::new(),
+            tags: Vec::new(),
+            poll_tx_ts: Vec::new(),
+            poll_rx_ts: Vec::new(),
+            response_tx_ts: 0,
+            response_tx_recorded: false,
+            response_rx_ts: Vec::new(),
+            final_tx_ts: Vec::new(),
+            final_rx_ts: Vec::new(),
+            rssi: Vec::new(),
+            clock_offset_ratios: Vec::new(),
+            poll_sources: Vec::new(),
+            final_sources: Vec::new(),
+            max_range_m: None,
+            tick_period_ps: DWT_TIME_UNIT * 1e12,
+            propagation_speed_m_per_s: SPEED_OF_LIGHT,
+            antenna_delays: AntennaDelays::default(),
+            smoothing_alpha: None,
+            smoothed_distances: Vec::new(),
+            active_anchors: u16::MAX,
+            blacklisted_anchors: 0,
+            polls_received: 0,
+            finals_received: 0,
+            cached_distances: None,
+
+            _state: STATE::default(),
+        }
+    }
+}
+
 /// The `Idle` state, where there is no ranging in progress.
 #[derive(Debug, Default)]
 pub struct Idle;
@@ -55,10 +161,473 @@ pub struct WaitingForAnchorPoll;
 #[derive(Debug, Default)]
 pub struct WaitingForAnchorFinal;
 
+impl sealed::Sealed for Idle {}
+impl sealed::Sealed for WaitingForAnchorPoll {}
+impl sealed::Sealed for WaitingForAnchorFinal {}
+
+impl TerminalState for Idle {}
+impl WaitingState for WaitingForAnchorPoll {}
+impl WaitingState for WaitingForAnchorFinal {}
+
+/// Abort the round from any waiting state, and transition back to the `Idle` state.
+///
+/// Same as the state-specific `idle()`, for code generic over the waiting states.
+pub fn abort<S: WaitingState>(state_machine: TagSideStateMachine<S>) -> TagSideStateMachine<Idle> {
+    state_machine.debug_assert_aligned();
+
+    TagSideStateMachine {
+        address: state_machine.address,
+        anchors: state_machine.anchors,
+        tags: state_machine.tags,
+        poll_tx_ts: state_machine.poll_tx_ts,
+        poll_rx_ts: state_machine.poll_rx_ts,
+        response_tx_ts: state_machine.response_tx_ts,
+        response_tx_recorded: state_machine.response_tx_recorded,
+        response_rx_ts: state_machine.response_rx_ts,
+        final_tx_ts: state_machine.final_tx_ts,
+        final_rx_ts: state_machine.final_rx_ts,
+        rssi: state_machine.rssi,
+        clock_offset_ratios: state_machine.clock_offset_ratios,
+        poll_sources: state_machine.poll_sources,
+        final_sources: state_machine.final_sources,
+        max_range_m: state_machine.max_range_m,
+        tick_period_ps: state_machine.tick_period_ps,
+        propagation_speed_m_per_s: state_machine.propagation_speed_m_per_s,
+        antenna_delays: state_machine.antenna_delays,
+        smoothing_alpha: state_machine.smoothing_alpha,
+        smoothed_distances: state_machine.smoothed_distances,
+        active_anchors: state_machine.active_anchors,
+        blacklisted_anchors: state_machine.blacklisted_anchors,
+        polls_received: state_machine.polls_received,
+        finals_received: state_machine.finals_received,
+        cached_distances: state_machine.cached_distances,
+
+        _state: Idle,
+    }
+}
+
+// The per-node footprints must account for every entry of the per-node vectors: what remains of
+// the state machine is its scalar fields and the lengths of the 13 vectors, up to padding. A
+// vector missing from `ANCHOR_FOOTPRINT` or `TAG_FOOTPRINT` fails this.
+const _: () = {
+    use core::mem::{align_of, size_of};
+
+    type Tag = TagSideStateMachine<Idle>;
+    let rest = Tag::FOOTPRINT - 16 * (Tag::ANCHOR_FOOTPRINT + Tag::TAG_FOOTPRINT);
+    let scalars = 5 * size_of::<u16>()
+        + size_of::<u64>()
+        + size_of::<bool>()
+        + size_of::<Option<f64>>()
+        + 2 * size_of::<f64>()
+        + size_of::<AntennaDelays>()
+        + size_of::<Option<f32>>()
+        + 13 * size_of::<usize>()
+        + size_of::<Option<Vec<Option<f64>, 16>>>()
+        - size_of::<Vec<Option<f64>, 16>>();
+    assert!(scalars <= rest && rest < scalars + align_of::<Tag>());
+};
+
+/// Configuration shared by all states of `TagSideStateMachine`.
+impl<STATE> TagSideStateMachine<STATE> {
+    /// Size of the state machine in bytes.
+    pub const FOOTPRINT: usize = core::mem::size_of::<Self>();
+
+    /// Bytes of the per-anchor vectors taken by each anchor: its address, the five timestamps,
+    /// the RSSI, the clock offset ratio, the poll and final sources, and the smoothed and cached
+    /// distances.
+    pub const ANCHOR_FOOTPRINT: usize = core::mem::size_of::<u16>()
+        + 5 * core::mem::size_of::<u64>()
+        + core::mem::size_of::<f32>()
+        + core::mem::size_of::<f64>()
+        + 2 * core::mem::size_of::<Option<u16>>()
+        + core::mem::size_of::<f32>()
+        + core::mem::size_of::<Option<f64>>();
+
+    /// Bytes of the per-tag vectors taken by each tag: its address.
+    pub const TAG_FOOTPRINT: usize = core::mem::size_of::<u16>();
+
+    /// Address of this tag.
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// Check that all per-anchor vectors are index-aligned with `anchors` (debug builds only).
+    fn debug_assert_aligned(&self) {
+        let len = self.anchors.len();
+        debug_assert!(
+            self.poll_tx_ts.len() == len
+                && self.poll_rx_ts.len() == len
+                && self.response_rx_ts.len() == len
+                && self.final_tx_ts.len() == len
+                && self.final_rx_ts.len() == len
+                && self.rssi.len() == len
+                && self.clock_offset_ratios.len() == len
+                && self.poll_sources.len() == len
+                && self.final_sources.len() == len,
+            "per-anchor vectors are not aligned with the {} anchors",
+            len
+        );
+        debug_assert!(self
+            .cached_distances
+            .as_ref()
+            .is_none_or(|distances| distances.len() == len));
+        debug_assert!(self.smoothed_distances.is_empty() || self.smoothed_distances.len() == len);
+    }
+
+    /// Whether messages from an anchor are recorded: it is known, active and not blacklisted.
+    pub fn listens_to(&self, anchor_idx: usize) -> bool {
+        anchor_idx < self.anchors.len()
+            && (self.active_anchors & !self.blacklisted_anchors) & (1 << anchor_idx) != 0
+    }
+
+    /// Exclude an anchor, e.g. one that keeps timing out, until `clear_blacklist`.
+    ///
+    /// Its polls and finals are ignored, and do not hold back `is_complete`. Messages already
+    /// received from it in this round are dropped. Unlike `set_active_anchors`, the blacklist
+    /// survives changes of the active set.
+    pub fn blacklist_anchor(&mut self, anchor_idx: usize) -> Result<(), IndexError> {
+        if anchor_idx >= self.anchors.len() {
+            return Err(IndexError {
+                index: anchor_idx,
+                len: self.anchors.len(),
+            });
+        }
+
+        self.blacklisted_anchors |= 1 << anchor_idx;
+        self.polls_received &= !self.blacklisted_anchors;
+        self.finals_received &= !self.blacklisted_anchors;
+        self.cached_distances = None;
+        Ok(())
+    }
+
+    /// Lift the exclusion of all blacklisted anchors.
+    pub fn clear_blacklist(&mut self) {
+        self.blacklisted_anchors = 0;
+    }
+
+    /// Change the anchor addresses, e.g. after an anchor joined or left, and move every
+    /// per-anchor value to the new index of its anchor.
+    ///
+    /// Anchor indices are positions in the anchor list. They are stable until the next
+    /// `reindex`, which moves them, so indices held across it must be re-derived with
+    /// `anchor_index`. Anchors that remain keep their timestamps, RSSI, smoothed distance and
+    /// whether they are active, blacklisted or were heard this round. New anchors start out
+    /// active with zero timestamps; the data of dropped anchors is discarded.
+    ///
+    /// In debug builds, panics if an address is both an anchor and a tag.
+    pub fn reindex(&mut self, anchors: Vec<u16, 16>) {
+        debug_assert!(
+            check_disjoint(&anchors, &self.tags).is_ok(),
+            "anchor and tag addresses overlap"
+        );
+
+        let old_idx: Vec<Option<usize>, 16> = anchors
+            .iter()
+            .map(|&addr| self.anchor_index(addr))
+            .collect();
+        fn moved<T: Copy, const N: usize>(
+            old: &Vec<T, N>,
+            old_idx: &[Option<usize>],
+            default: T,
+        ) -> Vec<T, N> {
+            old_idx
+                .iter()
+                .map(|i| i.and_then(|i| old.get(i).copied()).unwrap_or(default))
+                .collect()
+        }
+        let moved_mask = |mask: u16| {
+            old_idx
+                .iter()
+                .enumerate()
+                .filter(|(_, i)| matches!(i, Some(i) if mask & (1 << i) != 0))
+                .fold(0u16, |acc, (j, _)| acc | (1 << j))
+        };
+
+        self.poll_tx_ts = moved(&self.poll_tx_ts, &old_idx, 0);
+        self.poll_rx_ts = moved(&self.poll_rx_ts, &old_idx, 0);
+        self.response_rx_ts = moved(&self.response_rx_ts, &old_idx, 0);
+        self.final_tx_ts = moved(&self.final_tx_ts, &old_idx, 0);
+        self.final_rx_ts = moved(&self.final_rx_ts, &old_idx, 0);
+        self.rssi = moved(&self.rssi, &old_idx, 0.0);
+        self.clock_offset_ratios = moved(&self.clock_offset_ratios, &old_idx, 0.0);
+        self.poll_sources = moved(&self.poll_sources, &old_idx, None);
+        self.final_sources = moved(&self.final_sources, &old_idx, None);
+        if !self.smoothed_distances.is_empty() {
+            self.smoothed_distances = moved(&self.smoothed_distances, &old_idx, f32::NAN);
+        }
+
+        let known = moved_mask(u16::MAX);
+        self.active_anchors = !known | moved_mask(self.active_anchors);
+        self.blacklisted_anchors = moved_mask(self.blacklisted_anchors);
+        self.polls_received = moved_mask(self.polls_received);
+        self.finals_received = moved_mask(self.finals_received);
+        self.cached_distances = None;
+        self.anchors = anchors;
+        self.debug_assert_aligned();
+    }
+
+    /// Get the index of an anchor from its address.
+    pub fn anchor_index(&self, anchor_addr: u16) -> Option<usize> {
+        self.anchors.iter().position(|&addr| addr == anchor_addr)
+    }
+
+    /// The anchor addresses as an array, `None` if there are not exactly `N` anchors.
+    pub fn anchors_array<const N: usize>(&self) -> Option<[u16; N]> {
+        self.anchors.as_slice().try_into().ok()
+    }
+
+    /// Convert the tag's local RX timestamps to network time using the `sync` clock fit.
+    ///
+    /// With all tags synchronized to the root, their timestamps can be fused directly.
+    pub fn to_network_time(&self, sync: &TimeSync) -> NetworkTimestamps {
+        NetworkTimestamps {
+            poll_rx_ts: self
+                .poll_rx_ts
+                .iter()
+                .map(|&ts| sync.local_to_root(ts))
+                .collect(),
+            final_rx_ts: self
+                .final_rx_ts
+                .iter()
+                .map(|&ts| sync.local_to_root(ts))
+                .collect(),
+        }
+    }
+
+    /// Set the maximum plausible distance to an anchor in meters.
+    ///
+    /// Distances beyond it are reported as invalid by `compute_distances`.
+    pub fn set_max_range(&mut self, max_range_m: Option<f64>) {
+        self.max_range_m = max_range_m;
+        self.cached_distances = None;
+    }
+
+    /// Set the duration of one device time unit in picoseconds.
+    ///
+    /// The effective tick period shifts with the crystal temperature and trim, a compensation
+    /// loop can update it here. Defaults to the nominal 1 / (128 * 499.2 MHz) ~ 15.65 ps.
+    pub fn set_tick_period_ps(&mut self, tick_period_ps: f64) {
+        self.tick_period_ps = tick_period_ps;
+        self.cached_distances = None;
+    }
+
+    /// Set the propagation speed of the signal in m/s, for ranging through a medium other than
+    /// air. Defaults to `SPEED_OF_LIGHT`.
+    pub fn set_propagation_speed(&mut self, propagation_speed_m_per_s: f64) {
+        self.propagation_speed_m_per_s = propagation_speed_m_per_s;
+        self.cached_distances = None;
+    }
+
+    /// Convert a time of flight in device time units to meters, with the tick period and the
+    /// propagation speed.
+    fn tof_ticks_to_meters(&self, tof_ticks: f64) -> f64 {
+        tof_ticks * self.tick_period_ps * 1e-12 * self.propagation_speed_m_per_s
+    }
+
+    /// Enable exponential smoothing of the distances across rounds, with weight `alpha` (in
+    /// `(0, 1]`) for the new distance, or disable it with `None`.
+    ///
+    /// `smoothed_distances` then blends every new distance with the anchor's smoothed distance,
+    /// and `idle_with_results` stores the result for the next round. `compute_distances` is not
+    /// affected. Changing the setting drops the smoothed distances.
+    pub fn set_smoothing_alpha(&mut self, alpha: Option<f32>) {
+        self.smoothing_alpha = alpha;
+        self.smoothed_distances.clear();
+        self.cached_distances = None;
+    }
+
+    /// Smoothed distance (in meters) to an anchor, as of the last finalized round.
+    ///
+    /// `None` if smoothing is disabled or the anchor has no distance yet.
+    pub fn smoothed_distance(&self, anchor_idx: usize) -> Option<f32> {
+        self.smoothed_distances
+            .get(anchor_idx)
+            .copied()
+            .filter(|d| !d.is_nan())
+    }
+
+    /// Set the antenna delays of the tag's radio.
+    ///
+    /// Only needed if the radio does not already compensate its timestamps, see `AntennaDelays`.
+    pub fn set_antenna_delays(&mut self, antenna_delays: AntennaDelays) {
+        self.antenna_delays = antenna_delays;
+        self.cached_distances = None;
+    }
+
+    /// Set the clock offset of an anchor against the tag, as a ratio positive if the anchor's
+    /// clock runs fast.
+    ///
+    /// Typically `calibration::clock_offset_ratio` of the carrier integrator of a message from the
+    /// anchor. The time of flight puts the intervals the anchor measured on the tag's clock with
+    /// it, which matters for the single-sided estimates. Ignored if the anchor is out of range.
+    pub fn set_clock_offset_ratio_idx(&mut self, anchor_idx: usize, ratio: f64) {
+        if let Some(entry) = self.clock_offset_ratios.get_mut(anchor_idx) {
+            *entry = ratio;
+            self.cached_distances = None;
+        }
+    }
+
+    /// Zero the timestamps and RSSI of the current round, and forget which anchors took part.
+    ///
+    /// The addresses and the configuration (range limit, tick period, antenna delays, smoothing)
+    /// are kept, and so are the smoothed distances, which span rounds by design.
+    pub fn clear_round_data(&mut self) {
+        self.poll_tx_ts.iter_mut().for_each(|ts| *ts = 0);
+        self.poll_rx_ts.iter_mut().for_each(|ts| *ts = 0);
+        self.response_tx_ts = 0;
+        self.response_tx_recorded = false;
+        self.response_rx_ts.iter_mut().for_each(|ts| *ts = 0);
+        self.final_tx_ts.iter_mut().for_each(|ts| *ts = 0);
+        self.final_rx_ts.iter_mut().for_each(|ts| *ts = 0);
+        self.rssi.iter_mut().for_each(|rssi| *rssi = 0.0);
+        self.clock_offset_ratios
+            .iter_mut()
+            .for_each(|ratio| *ratio = 0.0);
+        self.poll_sources.iter_mut().for_each(|src| *src = None);
+        self.final_sources.iter_mut().for_each(|src| *src = None);
+        self.polls_received = 0;
+        self.finals_received = 0;
+        self.cached_distances = None;
+        self.debug_assert_aligned();
+    }
+}
+
+/// Tag RX timestamps converted to network (root) time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkTimestamps {
+    /// Poll RX timestamps (in network time)
+    pub poll_rx_ts: Vec<u64, 16>,
+
+    /// Final RX timestamps (in network time)
+    pub final_rx_ts: Vec<u64, 16>,
+}
+
+/// Intervals of one AltDS-TWR exchange, in device time units.
+///
+/// `ra` and `da` are measured by the anchor, `rb` and `db` by the tag. All are computed on the
+/// wrapping 40-bit counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct TwrIntervals {
+    /// Anchor round time: poll TX to response RX
+    pub ra: u64,
+
+    /// Tag round time: response TX to final RX
+    pub rb: u64,
+
+    /// Anchor reply time: response RX to final TX
+    pub da: u64,
+
+    /// Tag reply time: poll RX to response TX
+    pub db: u64,
+}
+
+impl TwrIntervals {
+    /// Whether the timestamps behind the intervals are in protocol order.
+    ///
+    /// Every interval, and the span from poll to final on each side, must be at most
+    /// `MAX_TIMESTAMP_INTERVAL`. A timestamp out of order wraps its interval to close to the full
+    /// 40-bit range, which no single rollover explains.
+    pub fn is_consistent(&self) -> bool {
+        [
+            self.ra,
+            self.rb,
+            self.da,
+            self.db,
+            self.ra + self.da,
+            self.rb + self.db,
+        ]
+        .iter()
+        .all(|&interval| interval <= MAX_TIMESTAMP_INTERVAL)
+    }
+}
+
+/// Everything the tag knows about one anchor in a round, for a single log line while debugging.
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct AnchorTimingReport {
+    /// Anchor address
+    pub address: u16,
+
+    /// Poll TX timestamp (in anchor time)
+    pub poll_tx_ts: u64,
+
+    /// Poll RX timestamp (in tag time)
+    pub poll_rx_ts: u64,
+
+    /// Response TX timestamp (in tag time)
+    pub response_tx_ts: u64,
+
+    /// Response RX timestamp (in anchor time)
+    pub response_rx_ts: u64,
+
+    /// Final TX timestamp (in anchor time)
+    pub final_tx_ts: u64,
+
+    /// Final RX timestamp (in tag time)
+    pub final_rx_ts: u64,
+
+    /// AltDS-TWR distance in meters, `None` if rejected (e.g. beyond the maximum range)
+    pub distance_m: Option<f64>,
+
+    /// Measurement quality, see `quality`
+    pub quality: f32,
+}
+
+/// Outcome of ranging against a participating anchor, see `range_result`.
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum RangeResult {
+    /// Distance in meters
+    Valid(f64),
+    /// Distance in meters, beyond the maximum range
+    OutOfRange(f64),
+    /// The timestamps are out of order even allowing for a counter rollover
+    Corrupt,
+}
+
+/// Two-way ranging variant used to compute distances.
+///
+/// Single-sided TWR only uses the poll and the response, so the error grows with the clock
+/// drift between anchor and tag times the tag's reply time (~1.5 m for 20 ppm and 500 us).
+/// AltDS-TWR also uses the final, which cancels the first-order drift error at the cost of an
+/// extra message per anchor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RangingMode {
+    /// SS-TWR, from the poll TX/RX and response TX/RX timestamps only
+    SingleSided,
+
+    /// AltDS-TWR, from all six timestamps
+    #[default]
+    DoubleSided,
+
+    /// Symmetric DS-TWR: one SS-TWR estimate per round trip (poll/response and
+    /// response/final), combined with the given strategy
+    Combined(CombineStrategy),
+}
+
+/// How the two round-trip estimates of `RangingMode::Combined` are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CombineStrategy {
+    /// Mean of both estimates, which cancels the drift error if the reply times are equal
+    #[default]
+    Average,
+
+    /// The estimate whose round and reply times are more balanced (closest ratio to one)
+    ///
+    /// A single bad timestamp unbalances one round trip, so its estimate is discarded.
+    BestConsistency,
+}
+
 /// Implement `TagSideStateMachine` for `Idle`.
 impl TagSideStateMachine<Idle> {
     /// Create a new `TagSideStateMachine` in the `Idle` state.
+    ///
+    /// In debug builds, panics if an address is both in `anchors` and in `tags`.
     pub fn new(address: u16, anchors: Vec<u16, 16>, tags: Vec<u16, 16>) -> Self {
+        debug_assert!(
+            check_disjoint(&anchors, &tags).is_ok(),
+            "anchor and tag addresses overlap"
+        );
+
+        // The configuration is the default one, only the per-anchor vectors are sized here
         Self {
             address,
             poll_tx_ts: Vec::from_iter(core::iter::repeat(0).take(anchors.len())),
@@ -66,16 +635,35 @@ impl TagSideStateMachine<Idle> {
             response_rx_ts: Vec::from_iter(core::iter::repeat(0).take(anchors.len())),
             final_tx_ts: Vec::from_iter(core::iter::repeat(0).take(anchors.len())),
             final_rx_ts: Vec::from_iter(core::iter::repeat(0).take(anchors.len())),
-            response_tx_ts: 0,
+            rssi: Vec::from_iter(core::iter::repeat(0.0).take(anchors.len())),
+            clock_offset_ratios: Vec::from_iter(core::iter::repeat(0.0).take(anchors.len())),
+            poll_sources: Vec::from_iter(core::iter::repeat(None).take(anchors.len())),
+            final_sources: Vec::from_iter(core::iter::repeat(None).take(anchors.len())),
             anchors,
             tags,
-
-            _state: Idle,
+            ..Self::default()
         }
     }
 
+    /// Create a new `TagSideStateMachine` in the `Idle` state from address tables, e.g. arrays.
+    ///
+    /// Will panic if there are more than 16 anchors or tags. See `new`.
+    pub fn from_addresses(
+        address: u16,
+        anchors: impl AsRef<[u16]>,
+        tags: impl AsRef<[u16]>,
+    ) -> Self {
+        Self::new(
+            address,
+            Vec::from_slice(anchors.as_ref()).expect("more than 16 anchors"),
+            Vec::from_slice(tags.as_ref()).expect("more than 16 tags"),
+        )
+    }
+
     /// Transition to the `WaitingForAnchorPoll` state.
     pub fn waiting_for_anchor_poll(self) -> TagSideStateMachine<WaitingForAnchorPoll> {
+        self.debug_assert_aligned();
+
         TagSideStateMachine {
             address: self.address,
             anchors: self.anchors,
@@ -83,13 +671,36 @@ impl TagSideStateMachine<Idle> {
             poll_tx_ts: self.poll_tx_ts,
             poll_rx_ts: self.poll_rx_ts,
             response_tx_ts: self.response_tx_ts,
+            response_tx_recorded: false,
             response_rx_ts: self.response_rx_ts,
             final_tx_ts: self.final_tx_ts,
             final_rx_ts: self.final_rx_ts,
+            rssi: self.rssi,
+            clock_offset_ratios: self.clock_offset_ratios,
+            poll_sources: self.poll_sources.iter().map(|_| None).collect(),
+            final_sources: self.final_sources.iter().map(|_| None).collect(),
+            max_range_m: self.max_range_m,
+            tick_period_ps: self.tick_period_ps,
+            propagation_speed_m_per_s: self.propagation_speed_m_per_s,
+            antenna_delays: self.antenna_delays,
+            smoothing_alpha: self.smoothing_alpha,
+            smoothed_distances: self.smoothed_distances,
+            active_anchors: self.active_anchors,
+            blacklisted_anchors: self.blacklisted_anchors,
+            polls_received: 0,
+            finals_received: 0,
+            cached_distances: None,
 
             _state: WaitingForAnchorPoll,
         }
     }
+
+    /// Only range against the anchors in `mask` (bitmask by anchor index).
+    ///
+    /// Polls and finals from other anchors are ignored, and do not hold back `is_complete`.
+    pub fn set_active_anchors(&mut self, mask: u16) {
+        self.active_anchors = mask;
+    }
 }
 
 /// Implement `TagSideStateMachine` for `WaitingForAnchorPoll`.
@@ -97,6 +708,7 @@ impl TagSideStateMachine<WaitingForAnchorPoll> {
     /// Set the TX timestamp for a poll message.
     pub fn set_poll_tx_ts_idx(&mut self, anchor_idx: usize, poll_tx_ts: u64) {
         self.poll_tx_ts[anchor_idx] = poll_tx_ts;
+        self.debug_assert_aligned();
     }
 
     /// Set the TX timestamp for a poll message.
@@ -108,12 +720,29 @@ impl TagSideStateMachine<WaitingForAnchorPoll> {
             .iter()
             .position(|&addr| addr == anchor_addr)
             .unwrap();
-        self.poll_tx_ts[anchor_idx] = poll_tx_ts;
+        self.set_poll_tx_ts_idx(anchor_idx, poll_tx_ts);
+    }
+
+    /// Only range against the anchors in `mask` (bitmask by anchor index).
+    ///
+    /// Polls and finals from other anchors are ignored, and do not hold back `is_complete`.
+    /// Polls already received from anchors outside `mask` are dropped.
+    pub fn set_active_anchors(&mut self, mask: u16) {
+        self.active_anchors = mask;
+        self.polls_received &= mask;
     }
 
     /// Set the RX timestamp for a poll message.
+    ///
+    /// Ignored if the anchor is out of range or not active.
     pub fn set_poll_rx_ts_idx(&mut self, anchor_idx: usize, poll_rx_ts: u64) {
+        if !self.listens_to(anchor_idx) {
+            return;
+        }
+
         self.poll_rx_ts[anchor_idx] = poll_rx_ts;
+        self.polls_received |= 1 << anchor_idx;
+        self.debug_assert_aligned();
     }
 
     /// Set the RX timestamp for a poll message.
@@ -125,11 +754,76 @@ impl TagSideStateMachine<WaitingForAnchorPoll> {
             .iter()
             .position(|&addr| addr == anchor_addr)
             .unwrap();
-        self.poll_rx_ts[anchor_idx] = poll_rx_ts;
+        self.set_poll_rx_ts_idx(anchor_idx, poll_rx_ts);
+    }
+
+    /// Ingest a poll packet received at `poll_rx_ts`, routed by the sender address it carries.
+    ///
+    /// Returns the index of the sending anchor, or `None` if the address is not a known anchor or
+    /// the tag does not listen to it (see `listens_to`), in which case nothing is recorded.
+    pub fn apply_poll_packet(&mut self, packet: &PollPacket, poll_rx_ts: u64) -> Option<usize> {
+        let anchor_idx = self
+            .anchor_index(packet.address())
+            .filter(|&idx| self.listens_to(idx))?;
+
+        self.set_poll_tx_ts_idx(anchor_idx, packet.tx_timestamp().value());
+        self.set_poll_rx_ts_idx(anchor_idx, poll_rx_ts & TIMESTAMP_MASK);
+        self.poll_sources[anchor_idx] = Some(packet.address());
+        Some(anchor_idx)
+    }
+
+    /// Record a received poll message from the radio's RX metadata.
+    ///
+    /// Stores the RX timestamp as the poll RX timestamp and the RSSI for the anchor. Ignored if
+    /// the anchor is out of range or not active.
+    pub fn apply_rx(&mut self, anchor_idx: usize, rx: &impl RxMetadata) {
+        if !self.listens_to(anchor_idx) {
+            return;
+        }
+
+        self.set_poll_rx_ts_idx(anchor_idx, rx.rx_timestamp() & TIMESTAMP_MASK);
+        self.rssi[anchor_idx] = rx.rssi();
+    }
+
+    /// Abort the round and transition back to the `Idle` state.
+    pub fn idle(self) -> TagSideStateMachine<Idle> {
+        self.debug_assert_aligned();
+
+        TagSideStateMachine {
+            address: self.address,
+            anchors: self.anchors,
+            tags: self.tags,
+            poll_tx_ts: self.poll_tx_ts,
+            poll_rx_ts: self.poll_rx_ts,
+            response_tx_ts: self.response_tx_ts,
+            response_tx_recorded: self.response_tx_recorded,
+            response_rx_ts: self.response_rx_ts,
+            final_tx_ts: self.final_tx_ts,
+            final_rx_ts: self.final_rx_ts,
+            rssi: self.rssi,
+            clock_offset_ratios: self.clock_offset_ratios,
+            poll_sources: self.poll_sources,
+            final_sources: self.final_sources,
+            max_range_m: self.max_range_m,
+            tick_period_ps: self.tick_period_ps,
+            propagation_speed_m_per_s: self.propagation_speed_m_per_s,
+            antenna_delays: self.antenna_delays,
+            smoothing_alpha: self.smoothing_alpha,
+            smoothed_distances: self.smoothed_distances,
+            active_anchors: self.active_anchors,
+            blacklisted_anchors: self.blacklisted_anchors,
+            polls_received: self.polls_received,
+            finals_received: self.finals_received,
+            cached_distances: self.cached_distances,
+
+            _state: Idle,
+        }
     }
 
     /// Transition to the `WaitingForAnchorFinal` state.
     pub fn waiting_for_anchor_final(self) -> TagSideStateMachine<WaitingForAnchorFinal> {
+        self.debug_assert_aligned();
+
         TagSideStateMachine {
             address: self.address,
             anchors: self.anchors,
@@ -137,9 +831,25 @@ impl TagSideStateMachine<WaitingForAnchorPoll> {
             poll_tx_ts: self.poll_tx_ts,
             poll_rx_ts: self.poll_rx_ts,
             response_tx_ts: self.response_tx_ts,
+            response_tx_recorded: self.response_tx_recorded,
             response_rx_ts: self.response_rx_ts,
             final_tx_ts: self.final_tx_ts,
             final_rx_ts: self.final_rx_ts,
+            rssi: self.rssi,
+            clock_offset_ratios: self.clock_offset_ratios,
+            poll_sources: self.poll_sources,
+            final_sources: self.final_sources,
+            max_range_m: self.max_range_m,
+            tick_period_ps: self.tick_period_ps,
+            propagation_speed_m_per_s: self.propagation_speed_m_per_s,
+            antenna_delays: self.antenna_delays,
+            smoothing_alpha: self.smoothing_alpha,
+            smoothed_distances: self.smoothed_distances,
+            active_anchors: self.active_anchors,
+            blacklisted_anchors: self.blacklisted_anchors,
+            polls_received: self.polls_received,
+            finals_received: self.finals_received,
+            cached_distances: self.cached_distances,
 
             _state: WaitingForAnchorFinal,
         }
@@ -151,11 +861,16 @@ impl TagSideStateMachine<WaitingForAnchorFinal> {
     /// Set the TX timestamp for a response message.
     pub fn set_response_tx_ts(&mut self, response_tx_ts: u64) {
         self.response_tx_ts = response_tx_ts;
+        self.response_tx_recorded = true;
+        self.cached_distances = None;
+        self.debug_assert_aligned();
     }
 
     /// Set the RX timestamp for a response message.
     pub fn set_response_rx_ts_idx(&mut self, anchor_idx: usize, response_rx_ts: u64) {
         self.response_rx_ts[anchor_idx] = response_rx_ts;
+        self.cached_distances = None;
+        self.debug_assert_aligned();
     }
 
     /// Set the RX timestamp for a response message.
@@ -167,12 +882,14 @@ impl TagSideStateMachine<WaitingForAnchorFinal> {
             .iter()
             .position(|&addr| addr == anchor_addr)
             .unwrap();
-        self.response_rx_ts[anchor_idx] = response_rx_ts;
+        self.set_response_rx_ts_idx(anchor_idx, response_rx_ts);
     }
 
     /// Set the TX timestamp for a final message. (parsed from the final message)
     pub fn set_final_tx_ts_idx(&mut self, anchor_idx: usize, final_tx_ts: u64) {
         self.final_tx_ts[anchor_idx] = final_tx_ts;
+        self.cached_distances = None;
+        self.debug_assert_aligned();
     }
 
     /// Set the TX timestamp for a final message. (parsed from the final message)
@@ -184,12 +901,21 @@ impl TagSideStateMachine<WaitingForAnchorFinal> {
             .iter()
             .position(|&addr| addr == anchor_addr)
             .unwrap();
-        self.final_tx_ts[anchor_idx] = final_tx_ts;
+        self.set_final_tx_ts_idx(anchor_idx, final_tx_ts);
     }
 
     /// Set the RX timestamp for a final message. (retrieved from the RX timestamp register)
+    ///
+    /// Ignored if the anchor is out of range or not active.
     pub fn set_final_rx_ts_idx(&mut self, anchor_idx: usize, final_rx_ts: u64) {
+        if !self.listens_to(anchor_idx) {
+            return;
+        }
+
         self.final_rx_ts[anchor_idx] = final_rx_ts;
+        self.finals_received |= 1 << anchor_idx;
+        self.cached_distances = None;
+        self.debug_assert_aligned();
     }
 
     /// Set the RX timestamp for a final message. (retrieved from the RX timestamp register)
@@ -201,83 +927,710 @@ impl TagSideStateMachine<WaitingForAnchorFinal> {
             .iter()
             .position(|&addr| addr == anchor_addr)
             .unwrap();
-        self.final_rx_ts[anchor_idx] = final_rx_ts;
+        self.set_final_rx_ts_idx(anchor_idx, final_rx_ts);
     }
 
-    /// Transition to the `Idle` state.
+    /// Record a received final message from the radio's RX metadata.
     ///
-    /// This is the end of the protocol.
-    pub fn idle(self) -> TagSideStateMachine<Idle> {
-        TagSideStateMachine {
-            address: self.address,
-            anchors: self.anchors,
-            tags: self.tags,
-            poll_tx_ts: self.poll_tx_ts,
-            poll_rx_ts: self.poll_rx_ts,
-            response_tx_ts: self.response_tx_ts,
-            response_rx_ts: self.response_rx_ts,
-            final_tx_ts: self.final_tx_ts,
-            final_rx_ts: self.final_rx_ts,
-
-            _state: Idle,
+    /// Stores the RX timestamp as the final RX timestamp and the RSSI for the anchor. Ignored if
+    /// the anchor is out of range or not active.
+    pub fn apply_rx(&mut self, anchor_idx: usize, rx: &impl RxMetadata) {
+        if !self.listens_to(anchor_idx) {
+            return;
         }
-    }
-}
-
-// Type erasure for `TagSideStateMachine`.
-
-/// Type erasure for `TagSideStateMachine`.
-#[derive(Debug)]
-pub enum AnyTagSideStateMachineErased {
-    /// The `Idle` state.
-    Idle(TagSideStateMachine<Idle>),
 
-    /// The `WaitingForAnchorPoll` state.
-    WaitingForAnchorPoll(TagSideStateMachine<WaitingForAnchorPoll>),
+        self.set_final_rx_ts_idx(anchor_idx, rx.rx_timestamp() & TIMESTAMP_MASK);
+        self.rssi[anchor_idx] = rx.rssi();
+    }
 
-    /// The `WaitingForAnchorFinal` state.
-    WaitingForAnchorFinal(TagSideStateMachine<WaitingForAnchorFinal>),
-}
+    /// Ingest a final packet from an anchor, received at `final_rx_ts`.
+    ///
+    /// Reads this tag's slot (by its index in the tag list) for the response RX timestamp. If the
+    /// anchor marked the slot invalid, the final is ignored and the anchor does not take part in
+    /// this round. Finals from inactive anchors are ignored as well. Returns whether the final was
+    /// recorded.
+    pub fn apply_final_packet<const N: usize>(
+        &mut self,
+        anchor_idx: usize,
+        packet: &FinalPacket<N>,
+        final_rx_ts: u64,
+    ) -> bool {
+        let Some(slot) = self.tags.iter().position(|&addr| addr == self.address) else {
+            return false;
+        };
+        if !packet.is_slot_valid(slot) || !self.listens_to(anchor_idx) {
+            return false;
+        }
 
-/// Type erasure for `TagSideStateMachine`.
-#[derive(Debug)]
-pub struct AnyTagSideStateMachine {
-    /// The type-erased state machine.
-    state_machine: AnyTagSideStateMachineErased,
-}
+        self.set_response_rx_ts_idx(anchor_idx, packet.rx_timestamps[slot].value().value());
+        self.set_final_tx_ts_idx(anchor_idx, packet.tx_timestamp.value().value());
+        self.set_final_rx_ts_idx(anchor_idx, final_rx_ts & TIMESTAMP_MASK);
+        true
+    }
 
-/// Implement mutation methods for `AnyTagSideStateMachine`.
-impl AnyTagSideStateMachine {
-    /// Extract the underlying state machine type.
-    pub fn as_idle_mut(&mut self) -> Option<&mut TagSideStateMachine<Idle>> {
-        match &mut self.state_machine {
-            AnyTagSideStateMachineErased::Idle(state_machine) => Some(state_machine),
-            _ => None,
+    /// Record the source address (from the MAC header) of the final recorded for an anchor, for
+    /// `verify_anchor_consistency`. Ignored if the anchor index is out of range.
+    pub fn set_final_source_idx(&mut self, anchor_idx: usize, src_addr: u16) {
+        if let Some(entry) = self.final_sources.get_mut(anchor_idx) {
+            *entry = Some(src_addr);
         }
     }
 
-    /// Extract the underlying state machine type.
-    pub fn as_waiting_for_anchor_poll_mut(
-        &mut self,
-    ) -> Option<&mut TagSideStateMachine<WaitingForAnchorPoll>> {
-        match &mut self.state_machine {
-            AnyTagSideStateMachineErased::WaitingForAnchorPoll(state_machine) => {
-                Some(state_machine)
+    /// Check that the poll and the final recorded for every participating anchor came from the
+    /// same address.
+    ///
+    /// Finals carry no sender address and are matched to anchors by the caller, so a missed poll
+    /// can shift a final onto the wrong anchor. Poll sources are recorded by
+    /// `apply_poll_packet`, final sources by `set_final_source_idx`; anchors where either is
+    /// unknown pass. Returns the address of the first anchor whose messages disagree.
+    pub fn verify_anchor_consistency(&self) -> Result<(), u16> {
+        let participation = self.participation();
+
+        for (i, (poll_src, final_src)) in self
+            .poll_sources
+            .iter()
+            .zip(&self.final_sources)
+            .enumerate()
+        {
+            if participation & (1 << i) == 0 {
+                continue;
+            }
+
+            if let (Some(poll_src), Some(final_src)) = (poll_src, final_src) {
+                if poll_src != final_src {
+                    return Err(self.anchors[i]);
+                }
             }
-            _ => None,
         }
+
+        Ok(())
     }
 
-    /// Extract the underlying state machine type.
-    pub fn as_waiting_for_anchor_final_mut(
-        &mut self,
-    ) -> Option<&mut TagSideStateMachine<WaitingForAnchorFinal>> {
-        match &mut self.state_machine {
-            AnyTagSideStateMachineErased::WaitingForAnchorFinal(state_machine) => {
-                Some(state_machine)
+    /// Merge a partial snapshot of the same round, e.g. from a second RX window.
+    ///
+    /// For every anchor whose poll (or final) `self` has not received but `other` has, the
+    /// timestamps carried by that message are copied over. Values already in `self` are kept.
+    /// The response TX timestamp is copied if only `other` had it set with
+    /// `set_response_tx_ts`; zero is a valid timestamp and does not count as missing.
+    ///
+    /// Both snapshots must have the same anchors (checked in debug builds). Anchors beyond those of
+    /// `self` are never merged.
+    pub fn merge(&mut self, other: &TagSideStateMachine<WaitingForAnchorFinal>) {
+        debug_assert_eq!(
+            self.anchors, other.anchors,
+            "merging rounds of other anchors"
+        );
+
+        let len = self.anchors.len().min(other.anchors.len());
+        let known = (1u32 << len).wrapping_sub(1) as u16;
+        let listening = self.active_anchors & !self.blacklisted_anchors & known;
+        let polls = other.polls_received & !self.polls_received & listening;
+        let finals = other.finals_received & !self.finals_received & listening;
+
+        for i in 0..len {
+            if polls & (1 << i) != 0 {
+                self.poll_tx_ts[i] = other.poll_tx_ts[i];
+                self.poll_rx_ts[i] = other.poll_rx_ts[i];
+                self.poll_sources[i] = other.poll_sources[i];
             }
-            _ => None,
+
+            if finals & (1 << i) != 0 {
+                self.response_rx_ts[i] = other.response_rx_ts[i];
+                self.final_tx_ts[i] = other.final_tx_ts[i];
+                self.final_rx_ts[i] = other.final_rx_ts[i];
+                self.rssi[i] = other.rssi[i];
+                self.clock_offset_ratios[i] = other.clock_offset_ratios[i];
+                self.final_sources[i] = other.final_sources[i];
+            }
+        }
+
+        if !self.response_tx_recorded && other.response_tx_recorded {
+            self.response_tx_ts = other.response_tx_ts;
+            self.response_tx_recorded = true;
         }
+
+        self.polls_received |= polls;
+        self.finals_received |= finals;
+        self.cached_distances = None;
+        self.debug_assert_aligned();
+    }
+
+    /// Whether the final of every anchor whose poll was received has arrived.
+    pub fn is_complete(&self) -> bool {
+        self.polls_received != 0
+            && self.finals_received & self.polls_received == self.polls_received
+    }
+
+    /// Bitmask of anchors (by index) for which both the poll and the final were received.
+    pub fn participation(&self) -> u16 {
+        self.polls_received & self.finals_received
+    }
+
+    /// Whether an anchor participated in this round, `false` for an index out of range.
+    fn participated(&self, anchor_idx: usize) -> bool {
+        anchor_idx < self.anchors.len() && self.participation() & (1 << anchor_idx) != 0
+    }
+
+    /// Timestamps, distance and quality of an anchor, `None` if it did not participate.
+    pub fn timing_report(&self, anchor_idx: usize) -> Option<AnchorTimingReport> {
+        let quality = self.quality(anchor_idx)?;
+
+        Some(AnchorTimingReport {
+            address: self.anchors[anchor_idx],
+            poll_tx_ts: self.poll_tx_ts[anchor_idx],
+            poll_rx_ts: self.poll_rx_ts[anchor_idx],
+            response_tx_ts: self.response_tx_ts,
+            response_rx_ts: self.response_rx_ts[anchor_idx],
+            final_tx_ts: self.final_tx_ts[anchor_idx],
+            final_rx_ts: self.final_rx_ts[anchor_idx],
+            distance_m: self.compute_distances(RangingMode::DoubleSided)[anchor_idx],
+            quality,
+        })
+    }
+
+    /// The distances of this round as a `RangingResultsPod`.
+    pub fn results_pod(&self) -> RangingResultsPod {
+        let mut pod = RangingResultsPod {
+            address: self.address,
+            valid_mask: 0,
+            distances_mm: [0; 16],
+        };
+
+        let distances = self.compute_distances(RangingMode::DoubleSided);
+        for (i, distance) in distances.iter().enumerate() {
+            if let Some(distance) = distance {
+                pod.distances_mm[i] = libm::round(distance * 1000.0) as i32;
+                pod.valid_mask |= 1 << i;
+            }
+        }
+
+        pod
+    }
+
+    /// Device time (in tag time) the fix of this round is valid for, for temporal alignment.
+    ///
+    /// The mean of the final RX timestamps of the participating anchors, across 40-bit
+    /// rollovers. Without participating anchors, the response TX timestamp, the middle of the
+    /// exchange.
+    pub fn fix_timestamp(&self) -> u64 {
+        let participation = self.participation();
+        let mut final_rx_ts = self
+            .final_rx_ts
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| participation & (1 << i) != 0)
+            .map(|(_, &ts)| ts);
+
+        let Some(reference) = final_rx_ts.next() else {
+            return self.response_tx_ts;
+        };
+        let (sum, count) = final_rx_ts.fold((0, 1), |(sum, count), ts| {
+            (sum + signed_diff(ts, reference), count + 1)
+        });
+
+        reference.wrapping_add_signed(sum.div_euclid(count)) & TIMESTAMP_MASK
+    }
+
+    /// The four AltDS-TWR intervals of an anchor, `None` if it did not participate in this round.
+    pub fn twr_intervals(&self, anchor_idx: usize) -> Option<TwrIntervals> {
+        if !self.participated(anchor_idx) {
+            return None;
+        }
+
+        Some(self.raw_intervals(anchor_idx))
+    }
+
+    /// The four AltDS-TWR intervals of an anchor, whether or not it participated.
+    ///
+    /// The tag's antenna delays are applied here: the TX delay is added to the response TX
+    /// timestamp, the RX delay subtracted from the poll and final RX timestamps.
+    fn raw_intervals(&self, anchor_idx: usize) -> TwrIntervals {
+        let delays = &self.antenna_delays;
+        let response_tx_ts = delays.antenna_tx_ts(self.response_tx_ts);
+        let poll_rx_ts = delays.antenna_rx_ts(self.poll_rx_ts[anchor_idx]);
+        let final_rx_ts = delays.antenna_rx_ts(self.final_rx_ts[anchor_idx]);
+
+        TwrIntervals {
+            ra: wrapping_interval(self.poll_tx_ts[anchor_idx], self.response_rx_ts[anchor_idx]),
+            rb: wrapping_interval(response_tx_ts, final_rx_ts),
+            da: wrapping_interval(
+                self.response_rx_ts[anchor_idx],
+                self.final_tx_ts[anchor_idx],
+            ),
+            db: wrapping_interval(poll_rx_ts, response_tx_ts),
+        }
+    }
+
+    /// Time of flight to an anchor in device time units.
+    ///
+    /// The intervals measured by the anchor are first put on the tag's clock, with the clock
+    /// offset ratio of the anchor (see `set_clock_offset_ratio_idx`).
+    fn tof_ticks(&self, anchor_idx: usize, mode: RangingMode) -> f64 {
+        let intervals = self.raw_intervals(anchor_idx);
+        let to_tag_clock = 1.0 / (1.0 + self.clock_offset_ratios[anchor_idx]);
+        let (ra, rb, da, db) = (
+            intervals.ra as f64 * to_tag_clock,
+            intervals.rb as f64,
+            intervals.da as f64 * to_tag_clock,
+            intervals.db as f64,
+        );
+
+        match mode {
+            RangingMode::SingleSided => (ra - db) / 2.0,
+            RangingMode::DoubleSided => (ra * rb - da * db) / (ra + rb + da + db),
+            RangingMode::Combined(strategy) => {
+                let first = (ra - db) / 2.0;
+                let second = (rb - da) / 2.0;
+
+                match strategy {
+                    CombineStrategy::Average => (first + second) / 2.0,
+                    CombineStrategy::BestConsistency => {
+                        let imbalance = |round: f64, reply: f64| (round / reply - 1.0).abs();
+                        if imbalance(ra, db) <= imbalance(rb, da) {
+                            first
+                        } else {
+                            second
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Clock offset of an anchor against the tag in ppm, positive if the anchor's clock runs
+    /// fast, `None` if the anchor did not participate in this round.
+    ///
+    /// From the span from poll to final as measured by both sides, `(ra + da) / (rb + db)`. The
+    /// clocks and the carriers derive from the same crystals, so this is also the carrier
+    /// frequency offset. Far beyond the crystal tolerance (a few tens of ppm), it points to a bad
+    /// link or a mistimed frame rather than a real offset.
+    pub fn cfo_ppm(&self, anchor_idx: usize) -> Option<f32> {
+        let intervals = self.twr_intervals(anchor_idx)?;
+        let anchor_span = (intervals.ra + intervals.da) as f64;
+        let tag_span = (intervals.rb + intervals.db) as f64;
+
+        Some(((anchor_span / tag_span - 1.0) * 1e6) as f32)
+    }
+
+    /// Measurement quality of an anchor in this round, in `(0, 1]`, higher is better.
+    ///
+    /// `1 / (1 + e)`, where `e` is the disagreement in meters between the two single-sided
+    /// estimates of the time of flight. Multipath, a mistimed frame or a drifting clock drive
+    /// them apart. `None` if the anchor did not participate in this round.
+    pub fn quality(&self, anchor_idx: usize) -> Option<f32> {
+        if !self.participated(anchor_idx) {
+            return None;
+        }
+
+        let intervals = self.raw_intervals(anchor_idx);
+        let first = (intervals.ra as f64 - intervals.db as f64) / 2.0;
+        let second = (intervals.rb as f64 - intervals.da as f64) / 2.0;
+        let disagreement_m = self.tof_ticks_to_meters((first - second).abs());
+
+        Some((1.0 / (1.0 + disagreement_m)) as f32)
+    }
+
+    /// Unsmoothed distance to an anchor, `None` if it did not participate in this round.
+    ///
+    /// Tells a distance beyond the maximum range, and timestamps too far out of order to be a
+    /// counter rollover, apart from a valid distance.
+    pub fn range_result(&self, anchor_idx: usize, mode: RangingMode) -> Option<RangeResult> {
+        let intervals = self.twr_intervals(anchor_idx)?;
+        if !intervals.is_consistent() {
+            return Some(RangeResult::Corrupt);
+        }
+
+        let distance = self.tof_ticks_to_meters(self.tof_ticks(anchor_idx, mode));
+        match self.max_range_m {
+            Some(max_range_m) if distance > max_range_m => Some(RangeResult::OutOfRange(distance)),
+            _ => Some(RangeResult::Valid(distance)),
+        }
+    }
+
+    /// Compute the distance (in meters) to every anchor.
+    ///
+    /// Anchors that did not participate in this round, whose distance exceeds the maximum range,
+    /// or whose timestamps are corrupt (see `range_result`) yield `None`. These are the distances
+    /// of this round alone, see `smoothed_distances` for the smoothed ones.
+    ///
+    /// Served from the cache for `RangingMode::DoubleSided` while it is valid, see
+    /// `compute_and_cache`.
+    pub fn compute_distances(&self, mode: RangingMode) -> Vec<Option<f64>, 16> {
+        if let (RangingMode::DoubleSided, Some(distances)) = (mode, &self.cached_distances) {
+            return distances.clone();
+        }
+
+        (0..self.anchors.len())
+            .map(|i| match self.range_result(i, mode)? {
+                RangeResult::Valid(distance) => Some(distance),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The distances of `compute_distances`, blended with the smoothed distances of the previous
+    /// rounds (see `set_smoothing_alpha`).
+    ///
+    /// Same as `compute_distances` with smoothing disabled, or for anchors without a smoothed
+    /// distance yet.
+    pub fn smoothed_distances(&self, mode: RangingMode) -> Vec<Option<f64>, 16> {
+        let mut distances = self.compute_distances(mode);
+        let Some(alpha) = self.smoothing_alpha else {
+            return distances;
+        };
+
+        for (i, distance) in distances.iter_mut().enumerate() {
+            if let (Some(distance), Some(previous)) = (distance.as_mut(), self.smoothed_distance(i))
+            {
+                let previous = previous as f64;
+                *distance = previous + alpha as f64 * (*distance - previous);
+            }
+        }
+        distances
+    }
+
+    /// Compute the AltDS-TWR distances once and cache them.
+    ///
+    /// While the cache is valid, the double-sided distances of `compute_distances` and of
+    /// everything built on it (`smoothed_distances`, `finalize`, `results_pod`, ...) are served
+    /// from it. The cache is invalidated by every timestamp setter of this state, and by changing
+    /// the maximum range or the tick period. Writing the public timestamp vectors directly
+    /// bypasses the invalidation.
+    pub fn compute_and_cache(&mut self) {
+        self.cached_distances = None;
+        self.cached_distances = Some(self.compute_distances(RangingMode::DoubleSided));
+    }
+
+    /// Cached distances (in meters) to every anchor, as of `compute_distances`.
+    ///
+    /// `None` if the cache is invalid, e.g. after a timestamp update.
+    pub fn cached_distances(&self) -> Option<&[Option<f64>]> {
+        self.cached_distances.as_deref()
+    }
+
+    /// Mean distance (in meters) over the anchors of this round, weighted by received power.
+    ///
+    /// A coarse fallback when the geometry is too poor to trilaterate. The weight of an anchor is
+    /// its RSSI on a linear scale, so a link 10 dB stronger counts ten times as much. `None` if no
+    /// anchor has a distance.
+    pub fn weighted_mean_distance(&self) -> Option<f32> {
+        let (sum, total_weight) = self
+            .compute_distances(RangingMode::DoubleSided)
+            .iter()
+            .zip(&self.rssi)
+            .filter_map(|(distance, &rssi)| Some((distance.as_ref()?, rssi)))
+            .fold((0.0, 0.0), |(sum, total_weight), (&distance, rssi)| {
+                let weight = libm::pow(10.0, rssi as f64 / 10.0);
+                (sum + weight * distance, total_weight + weight)
+            });
+
+        (total_weight > 0.0).then(|| (sum / total_weight) as f32)
+    }
+
+    /// Freeze the round into an immutable `RoundResults` snapshot, consuming the machine.
+    ///
+    /// Distances are computed with AltDS-TWR and smoothed if enabled (see `smoothed_distances`).
+    /// To reuse the machine for the next round, see `idle_with_results`.
+    pub fn finalize(self) -> RoundResults {
+        self.idle_with_results().1
+    }
+
+    /// Freeze the round into a `RoundResults` snapshot and transition to the `Idle` state.
+    ///
+    /// Same as `finalize`, but keeps the machine. The snapshot owns its data, so the returned
+    /// `Idle` machine can be reused for the next round right away.
+    pub fn idle_with_results(mut self) -> (TagSideStateMachine<Idle>, RoundResults) {
+        let distances = self.smoothed_distances(RangingMode::DoubleSided);
+        if self.smoothing_alpha.is_some() {
+            if self.smoothed_distances.is_empty() {
+                self.smoothed_distances = Vec::from_iter(distances.iter().map(|_| f32::NAN));
+            }
+            for (smoothed, distance) in self.smoothed_distances.iter_mut().zip(&distances) {
+                if let Some(distance) = distance {
+                    *smoothed = *distance as f32;
+                }
+            }
+        }
+
+        let results = RoundResults {
+            address: self.address,
+            anchors: self.anchors.clone(),
+            distances,
+            quality: (0..self.anchors.len()).map(|i| self.quality(i)).collect(),
+            participation: self.participation(),
+        };
+
+        (self.idle(), results)
+    }
+
+    /// Transition to the `Idle` state.
+    ///
+    /// This is the end of the protocol.
+    pub fn idle(self) -> TagSideStateMachine<Idle> {
+        self.debug_assert_aligned();
+
+        TagSideStateMachine {
+            address: self.address,
+            anchors: self.anchors,
+            tags: self.tags,
+            poll_tx_ts: self.poll_tx_ts,
+            poll_rx_ts: self.poll_rx_ts,
+            response_tx_ts: self.response_tx_ts,
+            response_tx_recorded: self.response_tx_recorded,
+            response_rx_ts: self.response_rx_ts,
+            final_tx_ts: self.final_tx_ts,
+            final_rx_ts: self.final_rx_ts,
+            rssi: self.rssi,
+            clock_offset_ratios: self.clock_offset_ratios,
+            poll_sources: self.poll_sources,
+            final_sources: self.final_sources,
+            max_range_m: self.max_range_m,
+            tick_period_ps: self.tick_period_ps,
+            propagation_speed_m_per_s: self.propagation_speed_m_per_s,
+            antenna_delays: self.antenna_delays,
+            smoothing_alpha: self.smoothing_alpha,
+            smoothed_distances: self.smoothed_distances,
+            active_anchors: self.active_anchors,
+            blacklisted_anchors: self.blacklisted_anchors,
+            polls_received: self.polls_received,
+            finals_received: self.finals_received,
+            cached_distances: self.cached_distances,
+
+            _state: Idle,
+        }
+    }
+}
+
+/// Results of a ranging round as plain old data, to be copied across a shared-memory boundary.
+///
+/// Fixed layout without padding, see `results_pod`.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, FromBytes, IntoBytes, Immutable, KnownLayout, defmt::Format,
+)]
+#[repr(C)]
+pub struct RangingResultsPod {
+    /// Address of the tag that ran the round
+    pub address: u16,
+
+    /// Bitmask of anchors (by index) with a valid distance
+    pub valid_mask: u16,
+
+    /// Distance to each anchor in millimeters, index-aligned with the anchors, 0 where invalid
+    pub distances_mm: [i32; 16],
+}
+
+/// Immutable results of a finished ranging round, produced by `finalize` and `idle_with_results`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundResults {
+    /// Address of the tag that ran the round
+    address: u16,
+
+    /// Anchor addresses, in the same order as `distances`
+    anchors: Vec<u16, 16>,
+
+    /// Distance to each anchor in meters, `None` if the anchor did not participate
+    distances: Vec<Option<f64>, 16>,
+
+    /// Measurement quality of each anchor, `None` if the anchor did not participate
+    quality: Vec<Option<f32>, 16>,
+
+    /// Bitmask of anchors (by index) that participated in the round
+    participation: u16,
+}
+
+impl RoundResults {
+    /// Address of the tag that ran the round.
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// Anchor addresses, index-aligned with `distances`.
+    pub fn anchors(&self) -> &[u16] {
+        &self.anchors
+    }
+
+    /// Distance to each anchor in meters.
+    pub fn distances(&self) -> &[Option<f64>] {
+        &self.distances
+    }
+
+    /// Distance to an anchor in meters, `None` if it did not participate or is out of range.
+    pub fn distance(&self, anchor_idx: usize) -> Option<f64> {
+        self.distances.get(anchor_idx).copied().flatten()
+    }
+
+    /// Measurement quality of an anchor, see `TagSideStateMachine::quality`.
+    pub fn quality(&self, anchor_idx: usize) -> Option<f32> {
+        self.quality.get(anchor_idx).copied().flatten()
+    }
+
+    /// Bitmask of anchors (by index) that participated in the round.
+    pub fn participation(&self) -> u16 {
+        self.participation
+    }
+
+    /// `(anchor address, distance in meters, quality)` of every anchor with a distance, best
+    /// quality first.
+    ///
+    /// Anchors that did not participate or are out of range are left out, so a solver can take
+    /// the first K entries.
+    pub fn results_by_quality(&self) -> Vec<(u16, f64, f32), 16> {
+        let mut results: Vec<_, 16> = self
+            .anchors
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &addr)| Some((addr, self.distance(i)?, self.quality(i)?)))
+            .collect();
+
+        results.sort_unstable_by(|a, b| b.2.total_cmp(&a.2));
+        results
+    }
+}
+
+// Type erasure for `TagSideStateMachine`.
+
+/// Type erasure for `TagSideStateMachine`.
+#[derive(Debug)]
+pub enum AnyTagSideStateMachineErased {
+    /// The `Idle` state.
+    Idle(TagSideStateMachine<Idle>),
+
+    /// The `WaitingForAnchorPoll` state.
+    WaitingForAnchorPoll(TagSideStateMachine<WaitingForAnchorPoll>),
+
+    /// The `WaitingForAnchorFinal` state.
+    WaitingForAnchorFinal(TagSideStateMachine<WaitingForAnchorFinal>),
+}
+
+/// Type erasure for `TagSideStateMachine`.
+#[derive(Debug)]
+pub struct AnyTagSideStateMachine {
+    /// The type-erased state machine.
+    state_machine: AnyTagSideStateMachineErased,
+}
+
+/// Implement mutation methods for `AnyTagSideStateMachine`.
+impl AnyTagSideStateMachine {
+    /// Protocol phase of the current state.
+    ///
+    /// The tag sends its response when entering `WaitingForAnchorFinal`, so it moves from the
+    /// poll phase straight to the final phase.
+    pub fn phase(&self) -> Phase {
+        match self.state_machine {
+            AnyTagSideStateMachineErased::Idle(_) => Phase::Poll,
+            AnyTagSideStateMachineErased::WaitingForAnchorPoll(_) => Phase::Poll,
+            AnyTagSideStateMachineErased::WaitingForAnchorFinal(_) => Phase::Final,
+        }
+    }
+
+    /// Address of the tag.
+    pub fn address(&self) -> u16 {
+        match &self.state_machine {
+            AnyTagSideStateMachineErased::Idle(state_machine) => state_machine.address(),
+            AnyTagSideStateMachineErased::WaitingForAnchorPoll(state_machine) => {
+                state_machine.address()
+            }
+            AnyTagSideStateMachineErased::WaitingForAnchorFinal(state_machine) => {
+                state_machine.address()
+            }
+        }
+    }
+
+    /// Names of the transitions that are valid from the current state.
+    pub fn valid_transitions(&self) -> &'static [&'static str] {
+        match self.state_machine {
+            AnyTagSideStateMachineErased::Idle(_) => &["waiting_for_anchor_poll"],
+            AnyTagSideStateMachineErased::WaitingForAnchorPoll(_) => {
+                &["waiting_for_anchor_final", "idle"]
+            }
+            AnyTagSideStateMachineErased::WaitingForAnchorFinal(_) => &["idle"],
+        }
+    }
+
+    /// Extract the underlying state machine type.
+    pub fn as_idle_mut(&mut self) -> Option<&mut TagSideStateMachine<Idle>> {
+        match &mut self.state_machine {
+            AnyTagSideStateMachineErased::Idle(state_machine) => Some(state_machine),
+            _ => None,
+        }
+    }
+
+    /// Extract the underlying state machine type.
+    pub fn as_waiting_for_anchor_poll_mut(
+        &mut self,
+    ) -> Option<&mut TagSideStateMachine<WaitingForAnchorPoll>> {
+        match &mut self.state_machine {
+            AnyTagSideStateMachineErased::WaitingForAnchorPoll(state_machine) => {
+                Some(state_machine)
+            }
+            _ => None,
+        }
+    }
+
+    /// Extract the underlying state machine type.
+    pub fn as_waiting_for_anchor_final_mut(
+        &mut self,
+    ) -> Option<&mut TagSideStateMachine<WaitingForAnchorFinal>> {
+        match &mut self.state_machine {
+            AnyTagSideStateMachineErased::WaitingForAnchorFinal(state_machine) => {
+                Some(state_machine)
+            }
+            _ => None,
+        }
+    }
+
+    /// Set the TX timestamp for a poll message.
+    ///
+    /// Error if the state machine is not in the `WaitingForAnchorPoll` state.
+    pub fn set_poll_tx_ts_idx(&mut self, anchor_idx: usize, ts: u64) -> Result<(), WrongPhase> {
+        let state_machine = self.as_waiting_for_anchor_poll_mut().ok_or(WrongPhase)?;
+        state_machine.set_poll_tx_ts_idx(anchor_idx, ts);
+        Ok(())
+    }
+
+    /// Set the RX timestamp for a poll message.
+    ///
+    /// Error if the state machine is not in the `WaitingForAnchorPoll` state.
+    pub fn set_poll_rx_ts_idx(&mut self, anchor_idx: usize, ts: u64) -> Result<(), WrongPhase> {
+        let state_machine = self.as_waiting_for_anchor_poll_mut().ok_or(WrongPhase)?;
+        state_machine.set_poll_rx_ts_idx(anchor_idx, ts);
+        Ok(())
+    }
+
+    /// Set the TX timestamp for the response message.
+    ///
+    /// Error if the state machine is not in the `WaitingForAnchorFinal` state.
+    pub fn set_response_tx_ts(&mut self, ts: u64) -> Result<(), WrongPhase> {
+        let state_machine = self.as_waiting_for_anchor_final_mut().ok_or(WrongPhase)?;
+        state_machine.set_response_tx_ts(ts);
+        Ok(())
+    }
+
+    /// Set the RX timestamp for a response message.
+    ///
+    /// Error if the state machine is not in the `WaitingForAnchorFinal` state.
+    pub fn set_response_rx_ts_idx(&mut self, anchor_idx: usize, ts: u64) -> Result<(), WrongPhase> {
+        let state_machine = self.as_waiting_for_anchor_final_mut().ok_or(WrongPhase)?;
+        state_machine.set_response_rx_ts_idx(anchor_idx, ts);
+        Ok(())
+    }
+
+    /// Set the TX timestamp for a final message.
+    ///
+    /// Error if the state machine is not in the `WaitingForAnchorFinal` state.
+    pub fn set_final_tx_ts_idx(&mut self, anchor_idx: usize, ts: u64) -> Result<(), WrongPhase> {
+        let state_machine = self.as_waiting_for_anchor_final_mut().ok_or(WrongPhase)?;
+        state_machine.set_final_tx_ts_idx(anchor_idx, ts);
+        Ok(())
+    }
+
+    /// Set the RX timestamp for a final message.
+    ///
+    /// Error if the state machine is not in the `WaitingForAnchorFinal` state.
+    pub fn set_final_rx_ts_idx(&mut self, anchor_idx: usize, ts: u64) -> Result<(), WrongPhase> {
+        let state_machine = self.as_waiting_for_anchor_final_mut().ok_or(WrongPhase)?;
+        state_machine.set_final_rx_ts_idx(anchor_idx, ts);
+        Ok(())
     }
 
     /// Transition to the `WaitingForAnchorPoll` state.
@@ -307,6 +1660,26 @@ impl AnyTagSideStateMachine {
             _ => Err(()),
         }
     }
+
+    /// Transition to the `Idle` state, from `WaitingForAnchorPoll` (aborting the round) or
+    /// `WaitingForAnchorFinal`.
+    ///
+    /// Error if the state machine is already in the `Idle` state.
+    pub fn to_idle(&mut self) -> Result<(), ()> {
+        match self.state_machine {
+            AnyTagSideStateMachineErased::WaitingForAnchorPoll(ref mut state_machine) => {
+                let state_machine = core::mem::take(state_machine);
+                self.state_machine = AnyTagSideStateMachineErased::Idle(state_machine.idle());
+                Ok(())
+            }
+            AnyTagSideStateMachineErased::WaitingForAnchorFinal(ref mut state_machine) => {
+                let state_machine = core::mem::take(state_machine);
+                self.state_machine = AnyTagSideStateMachineErased::Idle(state_machine.idle());
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
 }
 
 // Implement `From` for `TagSideStateMachine` and `AnyTagSideStateMachine`.
@@ -433,4 +1806,1094 @@ mod tests {
 
         assert_eq!(state_machine.poll_tx_ts.len(), 8);
     }
+
+    #[test]
+    fn test_apply_final_packet_skips_invalid_slot() {
+        use crate::packet::PacketType;
+        use bilge::prelude::*;
+
+        let tags = Vec::from_iter([100, 101, 102]);
+        let anchors: Vec<u16, 16> = Vec::from_iter([0, 1]);
+
+        let mut packet = FinalPacket::new(
+            PacketType::Final,
+            u4::new(0),
+            [u40::new(2_000), u40::new(2_100), u40::new(0)],
+            u40::new(9_000),
+        );
+        // Anchor 0 never heard tag 102
+        packet.set_slot_valid(2, false);
+
+        let mut results = [false; 3];
+        for (i, &address) in tags.iter().enumerate() {
+            let state_machine = TagSideStateMachine::new(address, anchors.clone(), tags.clone());
+            let mut state_machine = state_machine.waiting_for_anchor_poll();
+            state_machine.set_poll_tx_ts_idx(0, 1_000);
+            state_machine.set_poll_rx_ts_idx(0, 4_000);
+            let mut state_machine = state_machine.waiting_for_anchor_final();
+            state_machine.set_response_tx_ts(5_000);
+
+            results[i] = state_machine.apply_final_packet(0, &packet, 12_000);
+
+            let participating = state_machine.participation() & 1 != 0;
+            assert_eq!(participating, results[i]);
+            assert_eq!(
+                state_machine.compute_distances(RangingMode::DoubleSided)[0].is_some(),
+                results[i]
+            );
+        }
+
+        assert_eq!(results, [true, true, false]);
+    }
+
+    #[test]
+    fn test_apply_poll_packet() {
+        use crate::packet::PacketType;
+        use bilge::prelude::*;
+
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([3, 9, 5, 7]), Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+
+        let poll = PollPacket::new(PacketType::Poll, u4::new(0), u40::new(1_000), 5, 0);
+        assert_eq!(state_machine.apply_poll_packet(&poll, 4_000), Some(2));
+        assert_eq!(state_machine.poll_tx_ts[2], 1_000);
+        assert_eq!(state_machine.poll_rx_ts[2], 4_000);
+
+        let unknown = PollPacket::new(PacketType::Poll, u4::new(0), u40::new(1_000), 6, 0);
+        assert_eq!(state_machine.apply_poll_packet(&unknown, 4_000), None);
+
+        // Polls of inactive anchors are not recorded, not even their source
+        state_machine.set_active_anchors(0b0111);
+        let inactive = PollPacket::new(PacketType::Poll, u4::new(0), u40::new(1_000), 7, 0);
+        assert_eq!(state_machine.apply_poll_packet(&inactive, 4_000), None);
+        assert_eq!(state_machine.poll_tx_ts[3], 0);
+        assert_eq!(state_machine.poll_sources[3], None);
+    }
+
+    #[test]
+    fn test_active_anchors() {
+        let anchors = (0..8).collect();
+        let state_machine = TagSideStateMachine::new(100, anchors, Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.set_active_anchors(0b1111_0000);
+
+        // Polls arrive from all 8 anchors, and from indices past them which are ignored
+        for i in 0..8 {
+            state_machine.set_poll_rx_ts_idx(i, 1_000 * i as u64);
+        }
+        state_machine.set_poll_rx_ts_idx(16, 1_000);
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        for i in 4..8 {
+            assert!(!state_machine.is_complete());
+            state_machine.set_final_rx_ts_idx(i, 100_000);
+        }
+
+        // Complete after the 4 active anchors, inactive finals are ignored
+        assert!(state_machine.is_complete());
+        state_machine.set_final_rx_ts_idx(0, 100_000);
+        assert_eq!(state_machine.participation(), 0b1111_0000);
+        assert_eq!(state_machine.poll_rx_ts[0], 0);
+
+        // Indices past the anchors are ignored, even past the 16 bits of the masks
+        for i in [8, 16, 100] {
+            state_machine.set_final_rx_ts_idx(i, 100_000);
+        }
+        assert_eq!(state_machine.participation(), 0b1111_0000);
+    }
+
+    #[test]
+    fn test_blacklist_anchor() {
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0, 1, 2]), Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.blacklist_anchor(1).unwrap();
+        state_machine.set_active_anchors(0b111);
+
+        // Only known anchors can be blacklisted
+        for index in [3, 16, 100] {
+            let error = IndexError { index, len: 3 };
+            assert_eq!(state_machine.blacklist_anchor(index), Err(error));
+        }
+
+        // The blacklisted anchor's poll is ignored even though it arrives
+        for i in 0..3 {
+            state_machine.set_poll_rx_ts_idx(i, 1_000 * (i as u64 + 1));
+        }
+        assert_eq!(state_machine.poll_rx_ts[1], 0);
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.set_final_rx_ts_idx(0, 100_000);
+        state_machine.set_final_rx_ts_idx(2, 100_000);
+        assert!(state_machine.is_complete());
+        assert_eq!(state_machine.participation(), 0b101);
+
+        // Until the blacklist is cleared
+        let mut state_machine = state_machine.idle().waiting_for_anchor_poll();
+        state_machine.clear_blacklist();
+        state_machine.set_poll_rx_ts_idx(1, 5_000);
+        assert_eq!(state_machine.poll_rx_ts[1], 5_000);
+    }
+
+    #[test]
+    fn test_reindex() {
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([1, 2, 4]), Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.set_active_anchors(0b011);
+        state_machine.set_poll_rx_ts_idx(0, 1_000);
+        state_machine.set_poll_rx_ts_idx(1, 2_000);
+
+        // Anchor 3 joins in front, anchor 4 leaves
+        state_machine.reindex(Vec::from_iter([3, 1, 2]));
+        assert_eq!(state_machine.anchor_index(1), Some(1));
+        assert_eq!(state_machine.poll_rx_ts, [0, 1_000, 2_000]);
+        assert_eq!(state_machine.polls_received, 0b110);
+
+        // The new anchor is active, the remaining ones keep their setting
+        state_machine.set_poll_rx_ts_idx(0, 3_000);
+        assert_eq!(state_machine.poll_rx_ts[0], 3_000);
+        assert_eq!(state_machine.active_anchors & 0b111, 0b111);
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.set_final_rx_ts_idx(1, 10_000);
+        assert_eq!(state_machine.participation(), 0b010);
+    }
+
+    #[test]
+    fn test_combine_strategy() {
+        let tof = (10.0 / METERS_PER_TICK) as u64;
+        let mut state_machine = run_round(&[Some(tof)]);
+
+        let average = RangingMode::Combined(CombineStrategy::Average);
+        let best = RangingMode::Combined(CombineStrategy::BestConsistency);
+        let distance = |sm: &TagSideStateMachine<WaitingForAnchorFinal>, mode| {
+            sm.compute_distances(mode)[0].unwrap()
+        };
+        assert!((distance(&state_machine, average) - 10.0).abs() < 0.01);
+        assert!((distance(&state_machine, best) - 10.0).abs() < 0.01);
+
+        // Corrupt the final RX timestamp, which only affects the second round trip
+        state_machine.final_rx_ts[0] += 20_000;
+
+        let average_error = (distance(&state_machine, average) - 10.0).abs();
+        let best_error = (distance(&state_machine, best) - 10.0).abs();
+        assert!(average_error > 10.0);
+        assert!(best_error < 0.01);
+    }
+
+    #[test]
+    fn test_twr_intervals() {
+        // Anchor 0: poll TX 1000, tag clock 3000 ahead, TOF 1000 (see `run_round`)
+        let state_machine = run_round(&[Some(1000), None]);
+
+        assert_eq!(
+            state_machine.twr_intervals(0),
+            Some(TwrIntervals {
+                // (500_000 - 3000 + 1000) - 1000
+                ra: 497_000,
+                // (498_000 + 100_000 + 1000 + 3000) - 500_000
+                rb: 102_000,
+                da: 100_000,
+                // 500_000 - (1000 + 1000 + 3000)
+                db: 495_000,
+            })
+        );
+        assert_eq!(state_machine.twr_intervals(1), None);
+
+        // Out of range of the anchors, and of the participation mask
+        assert_eq!(state_machine.twr_intervals(2), None);
+        assert_eq!(state_machine.twr_intervals(16), None);
+        assert_eq!(state_machine.twr_intervals(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_any_setters_check_phase() {
+        let mut any_sm = AnyTagSideStateMachine::from(TagSideStateMachine::new(
+            100,
+            Vec::from_iter([0, 1]),
+            Vec::from_iter([100]),
+        ));
+
+        assert_eq!(any_sm.set_poll_rx_ts_idx(0, 1_000), Err(WrongPhase));
+
+        any_sm.to_waiting_for_anchor_poll().unwrap();
+        assert_eq!(any_sm.set_poll_rx_ts_idx(0, 1_000), Ok(()));
+        assert_eq!(any_sm.set_final_rx_ts_idx(0, 9_000), Err(WrongPhase));
+
+        any_sm.to_waiting_for_anchor_final().unwrap();
+        assert_eq!(any_sm.set_poll_tx_ts_idx(0, 0), Err(WrongPhase));
+        assert_eq!(any_sm.set_final_rx_ts_idx(0, 9_000), Ok(()));
+
+        let state_machine = any_sm.as_waiting_for_anchor_final_mut().unwrap();
+        assert_eq!(state_machine.poll_rx_ts[0], 1_000);
+        assert_eq!(state_machine.final_rx_ts[0], 9_000);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "per-anchor vectors are not aligned with the 3 anchors")]
+    fn test_misaligned_vectors() {
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0, 1, 2]), Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+
+        state_machine.poll_rx_ts.push(0).unwrap();
+        state_machine.set_poll_tx_ts_idx(0, 1_000);
+    }
+
+    /// Meters per device time unit
+    const METERS_PER_TICK: f64 = DWT_TIME_UNIT * SPEED_OF_LIGHT;
+
+    /// Run a synthetic round against one anchor per entry of `tofs` (time of flight in device
+    /// time units, `None` if the anchor is not heard). The tag clock runs 3000 ticks ahead of
+    /// the anchor clocks.
+    fn run_round(tofs: &[Option<u64>]) -> TagSideStateMachine<WaitingForAnchorFinal> {
+        const OFFSET: u64 = 3000;
+        const RESPONSE_TX: u64 = 500_000;
+
+        let anchors = (0..tofs.len() as u16).collect();
+        let state_machine = TagSideStateMachine::<Idle>::new(100, anchors, Vec::from_iter([100]));
+
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        for (i, tof) in tofs.iter().enumerate() {
+            if let Some(tof) = tof {
+                let poll_tx = 1000 * (i as u64 + 1);
+                state_machine.set_poll_tx_ts_idx(i, poll_tx);
+                state_machine.set_poll_rx_ts_idx(i, poll_tx + tof + OFFSET);
+            }
+        }
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.set_response_tx_ts(RESPONSE_TX);
+        for (i, tof) in tofs.iter().enumerate() {
+            if let Some(tof) = tof {
+                let response_rx = RESPONSE_TX - OFFSET + tof;
+                let final_tx = response_rx + 100_000 + 1000 * i as u64;
+                state_machine.set_response_rx_ts_idx(i, response_rx);
+                state_machine.set_final_tx_ts_idx(i, final_tx);
+                state_machine.set_final_rx_ts_idx(i, final_tx + tof + OFFSET);
+            }
+        }
+
+        state_machine
+    }
+
+    #[test]
+    fn test_results_by_quality() {
+        let mut state_machine = run_round(&[Some(1000), None, Some(3000), Some(2000)]);
+
+        // Late final RX timestamps pull the second single-sided estimate away from the first
+        let final_rx_0 = state_machine.final_rx_ts[0];
+        state_machine.set_final_rx_ts_idx(0, final_rx_0 + 400);
+        let final_rx_3 = state_machine.final_rx_ts[3];
+        state_machine.set_final_rx_ts_idx(3, final_rx_3 + 100);
+
+        assert_eq!(state_machine.quality(1), None);
+        assert_eq!(state_machine.quality(2), Some(1.0));
+        assert_eq!(state_machine.quality(16), None);
+
+        let results = state_machine.finalize();
+        let sorted = results.results_by_quality();
+
+        let addresses: Vec<u16, 16> = sorted.iter().map(|&(addr, _, _)| addr).collect();
+        assert_eq!(addresses, [2, 3, 0]);
+        assert!(sorted.windows(2).all(|w| w[0].2 >= w[1].2));
+        for &(addr, distance, _) in &sorted {
+            assert_eq!(Some(distance), results.distance(addr as usize));
+        }
+    }
+
+    #[test]
+    fn test_antenna_delays() {
+        // Tag clock 3000 ticks ahead, ToF 1000 ticks. The tag's radio timestamps a received frame
+        // 200 ticks after it reached the antenna, and a sent frame 600 ticks before it left.
+        let delays = AntennaDelays {
+            tx_delay_ticks: 600,
+            rx_delay_ticks: 200,
+        };
+
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.set_poll_tx_ts_idx(0, 1_000);
+        state_machine.set_poll_rx_ts_idx(0, 5_000 + 200);
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.set_response_tx_ts(105_000 - 600);
+        state_machine.set_response_rx_ts_idx(0, 103_000);
+        state_machine.set_final_tx_ts_idx(0, 203_000);
+        state_machine.set_final_rx_ts_idx(0, 207_000 + 200);
+
+        let distance = |state_machine: &TagSideStateMachine<WaitingForAnchorFinal>| {
+            state_machine.compute_distances(RangingMode::DoubleSided)[0].unwrap()
+        };
+        let expected = 1000.0 * DWT_TIME_UNIT * SPEED_OF_LIGHT;
+
+        state_machine.set_antenna_delays(delays);
+        assert!((distance(&state_machine) - expected).abs() < 1e-6);
+
+        // A single lumped delay for both directions (here the TX delay) is off
+        state_machine.set_antenna_delays(AntennaDelays {
+            tx_delay_ticks: 600,
+            rx_delay_ticks: 600,
+        });
+        assert!((distance(&state_machine) - expected).abs() > 0.5);
+    }
+
+    #[test]
+    fn test_abort() {
+        fn abort_and_restart<S: WaitingState>(
+            state_machine: TagSideStateMachine<S>,
+        ) -> TagSideStateMachine<WaitingForAnchorPoll> {
+            abort(state_machine).waiting_for_anchor_poll()
+        }
+
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0, 1]), Vec::from_iter([100]));
+        let mut state_machine = abort_and_restart(state_machine.waiting_for_anchor_poll());
+        state_machine.set_poll_rx_ts_idx(1, 5_000);
+
+        let state_machine = abort_and_restart(state_machine.waiting_for_anchor_final());
+        assert_eq!(state_machine.poll_rx_ts[1], 5_000);
+        assert_eq!(state_machine.polls_received, 0);
+    }
+
+    #[test]
+    fn test_clear_round_data() {
+        let delays = AntennaDelays {
+            tx_delay_ticks: 600,
+            rx_delay_ticks: 200,
+        };
+
+        let mut state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0, 1]), Vec::from_iter([100]));
+        state_machine.set_antenna_delays(delays);
+        state_machine.set_max_range(Some(50.0));
+
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.set_poll_tx_ts_idx(0, 1_000);
+        state_machine.set_poll_rx_ts_idx(0, 5_000);
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.set_response_tx_ts(105_000);
+        state_machine.set_response_rx_ts_idx(0, 103_000);
+        state_machine.set_final_tx_ts_idx(0, 203_000);
+        state_machine.set_final_rx_ts_idx(0, 207_000);
+        state_machine.compute_and_cache();
+        assert_eq!(state_machine.participation(), 0b01);
+
+        state_machine.clear_round_data();
+
+        // Timestamps and participation are gone
+        assert_eq!(state_machine.poll_tx_ts, [0, 0]);
+        assert_eq!(state_machine.poll_rx_ts, [0, 0]);
+        assert_eq!(state_machine.response_tx_ts, 0);
+        assert_eq!(state_machine.response_rx_ts, [0, 0]);
+        assert_eq!(state_machine.final_tx_ts, [0, 0]);
+        assert_eq!(state_machine.final_rx_ts, [0, 0]);
+        assert_eq!(state_machine.participation(), 0);
+        assert_eq!(state_machine.cached_distances(), None);
+
+        // The configuration survives
+        assert_eq!(state_machine.address(), 100);
+        assert_eq!(state_machine.anchors, [0, 1]);
+        assert_eq!(state_machine.tags, [100]);
+        assert_eq!(state_machine.antenna_delays, delays);
+        assert_eq!(state_machine.max_range_m, Some(50.0));
+    }
+
+    #[test]
+    fn test_address_arrays() {
+        let anchors = [3, 1, 2];
+        let state_machine = TagSideStateMachine::from_addresses(100, anchors, [100, 101]);
+
+        assert_eq!(state_machine.anchors_array(), Some(anchors));
+        assert_eq!(state_machine.tags, [100, 101]);
+        assert_eq!(state_machine.anchors_array::<2>(), None);
+    }
+
+    #[test]
+    fn test_smoothing() {
+        /// The timestamps of a round against anchor 0 with a time of flight of `tof` ticks
+        fn exchange(
+            state_machine: TagSideStateMachine<Idle>,
+            tof: u64,
+        ) -> TagSideStateMachine<WaitingForAnchorFinal> {
+            let mut state_machine = state_machine.waiting_for_anchor_poll();
+            state_machine.set_poll_tx_ts_idx(0, 1_000);
+            state_machine.set_poll_rx_ts_idx(0, 4_000 + tof);
+
+            let mut state_machine = state_machine.waiting_for_anchor_final();
+            state_machine.set_response_tx_ts(500_000);
+            state_machine.set_response_rx_ts_idx(0, 497_000 + tof);
+            state_machine.set_final_tx_ts_idx(0, 597_000 + tof);
+            state_machine.set_final_rx_ts_idx(0, 600_000 + 2 * tof);
+            state_machine
+        }
+        let round = |state_machine, tof| exchange(state_machine, tof).idle_with_results();
+        let meters = |tof: f64| tof * DWT_TIME_UNIT * SPEED_OF_LIGHT;
+
+        let mut state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+        state_machine.set_smoothing_alpha(Some(0.25));
+        assert_eq!(state_machine.smoothed_distance(0), None);
+
+        let (state_machine, results) = round(state_machine, 1_000);
+        assert!((results.distance(0).unwrap() - meters(1_000.0)).abs() < 1e-6);
+
+        // A single outlier only moves the smoothed distance by a quarter, the raw distance of the
+        // round is left alone
+        let state_machine = exchange(state_machine, 1_400);
+        let raw = state_machine.compute_distances(RangingMode::DoubleSided)[0].unwrap();
+        let smoothed = state_machine.smoothed_distances(RangingMode::DoubleSided)[0].unwrap();
+        assert!((raw - meters(1_400.0)).abs() < 1e-6);
+        assert!((smoothed - meters(1_100.0)).abs() < 1e-6);
+
+        let (mut state_machine, results) = state_machine.idle_with_results();
+        assert!((results.distance(0).unwrap() - meters(1_100.0)).abs() < 1e-6);
+
+        // And is forgotten over the following rounds
+        for _ in 0..30 {
+            state_machine = round(state_machine, 1_000).0;
+        }
+        let smoothed = state_machine.smoothed_distance(0).unwrap() as f64;
+        assert!((smoothed - meters(1_000.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_weighted_mean_distance() {
+        let meters = |tof: f64| (tof * DWT_TIME_UNIT * SPEED_OF_LIGHT) as f32;
+
+        let mut state_machine = run_round(&[Some(1000), Some(3000), None]);
+        state_machine.rssi[0] = -60.0;
+        state_machine.rssi[1] = -90.0;
+        state_machine.rssi[2] = -40.0;
+
+        // The strong link dominates, the anchor without a distance is left out
+        let mean = state_machine.weighted_mean_distance().unwrap();
+        let expected = (1000.0 * 1000.0 + 3000.0) / 1001.0;
+        assert!((mean - meters(expected)).abs() < 1e-4);
+        assert!((mean - meters(1000.0)).abs() < 0.05);
+
+        assert_eq!(run_round(&[None, None]).weighted_mean_distance(), None);
+    }
+
+    #[test]
+    fn test_fix_timestamp() {
+        let mut state_machine = run_round(&[Some(1000), Some(1000), Some(1000)]);
+
+        // Finals straddling the 40-bit rollover, the last one is not counted
+        state_machine.final_rx_ts[0] = TIMESTAMP_MASK - 99;
+        state_machine.final_rx_ts[1] = 300;
+        state_machine.finals_received &= !0b100;
+        assert_eq!(state_machine.fix_timestamp(), 100);
+
+        let state_machine = run_round(&[None, None]);
+        assert_eq!(state_machine.fix_timestamp(), state_machine.response_tx_ts);
+    }
+
+    #[test]
+    fn test_timing_report() {
+        let state_machine = run_round(&[Some(1000), None]);
+        let report = state_machine.timing_report(0).unwrap();
+
+        assert_eq!(report.address, 0);
+        assert_eq!(report.poll_tx_ts, state_machine.poll_tx_ts[0]);
+        assert_eq!(report.poll_rx_ts, state_machine.poll_rx_ts[0]);
+        assert_eq!(report.response_tx_ts, state_machine.response_tx_ts);
+        assert_eq!(report.response_rx_ts, state_machine.response_rx_ts[0]);
+        assert_eq!(report.final_tx_ts, state_machine.final_tx_ts[0]);
+        assert_eq!(report.final_rx_ts, state_machine.final_rx_ts[0]);
+        assert_eq!(
+            report.distance_m,
+            state_machine.compute_distances(RangingMode::DoubleSided)[0]
+        );
+        assert_eq!(Some(report.quality), state_machine.quality(0));
+
+        assert_eq!(state_machine.timing_report(1), None);
+    }
+
+    #[test]
+    fn test_range_result_corrupt() {
+        let mut state_machine = run_round(&[Some(1000), Some(1000)]);
+        assert!(matches!(
+            state_machine.range_result(0, RangingMode::DoubleSided),
+            Some(RangeResult::Valid(_))
+        ));
+
+        // A rollover of the anchor's counter between poll and response is fine
+        let mut wrapped = run_round(&[Some(1000)]);
+        for ts in [
+            &mut wrapped.poll_tx_ts[0],
+            &mut wrapped.response_rx_ts[0],
+            &mut wrapped.final_tx_ts[0],
+        ] {
+            *ts = ts.wrapping_sub(2_000) & TIMESTAMP_MASK;
+        }
+        assert!(wrapped.poll_tx_ts[0] > wrapped.response_rx_ts[0]);
+        assert_eq!(
+            wrapped.range_result(0, RangingMode::DoubleSided),
+            state_machine.range_result(0, RangingMode::DoubleSided)
+        );
+
+        // The final sent before the response was received, by far more than any rollover
+        state_machine.final_tx_ts[1] = state_machine.response_rx_ts[1] - 50_000;
+        assert_eq!(
+            state_machine.range_result(1, RangingMode::DoubleSided),
+            Some(RangeResult::Corrupt)
+        );
+        assert_eq!(
+            state_machine.compute_distances(RangingMode::DoubleSided)[1],
+            None
+        );
+
+        state_machine.set_max_range(Some(1.0));
+        assert!(matches!(
+            state_machine.range_result(0, RangingMode::DoubleSided),
+            Some(RangeResult::OutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn test_results_pod() {
+        use zerocopy::{transmute, IntoBytes};
+
+        let state_machine = run_round(&[Some(1000), None, Some(3000)]);
+        let pod = state_machine.results_pod();
+
+        let distances = state_machine.compute_distances(RangingMode::DoubleSided);
+        assert_eq!(pod.address, 100);
+        assert_eq!(pod.valid_mask, 0b101);
+        assert_eq!(pod.distances_mm[1], 0);
+        assert!((pod.distances_mm[2] as f64 - distances[2].unwrap() * 1000.0).abs() <= 0.5);
+
+        let bytes: [u8; 68] = pod.as_bytes().try_into().unwrap();
+        let transmuted: RangingResultsPod = transmute!(bytes);
+        assert_eq!(transmuted, pod);
+    }
+
+    #[test]
+    fn test_compute_and_cache() {
+        let mut state_machine = run_round(&[Some(1000), None, Some(3000)]);
+        assert_eq!(state_machine.cached_distances(), None);
+
+        let distances = state_machine.compute_distances(RangingMode::DoubleSided);
+        state_machine.compute_and_cache();
+        assert_eq!(state_machine.cached_distances(), Some(distances.as_slice()));
+        assert_eq!(state_machine.cached_distances().unwrap()[1], None);
+
+        // Any timestamp update invalidates the cache
+        state_machine.set_final_rx_ts_idx(2, 0);
+        assert_eq!(state_machine.cached_distances(), None);
+
+        state_machine.compute_and_cache();
+        assert!(state_machine.cached_distances().is_some());
+        state_machine.set_tick_period_ps(15.0);
+        assert_eq!(state_machine.cached_distances(), None);
+    }
+
+    #[test]
+    fn test_results_from_cache() {
+        let mut state_machine = run_round(&[Some(1000), None, Some(3000)]);
+        state_machine.compute_and_cache();
+        let cached = state_machine.compute_distances(RangingMode::DoubleSided);
+
+        // Bypassing the invalidation shows the results come from the cache, not recomputed
+        state_machine.final_rx_ts[0] += 1_000;
+        assert_eq!(
+            state_machine.compute_distances(RangingMode::DoubleSided),
+            cached
+        );
+        assert_eq!(state_machine.results_pod().distances_mm[0], {
+            libm::round(cached[0].unwrap() * 1000.0) as i32
+        });
+        assert_eq!(state_machine.finalize().distances(), cached.as_slice());
+    }
+
+    #[test]
+    fn test_max_range() {
+        let far = (300.0 / METERS_PER_TICK) as u64;
+        let mut state_machine = run_round(&[Some(1000), Some(2000), Some(far)]);
+
+        let distances = state_machine.compute_distances(RangingMode::DoubleSided);
+        assert!((distances[2].unwrap() - 300.0).abs() < 0.01);
+
+        state_machine.set_max_range(Some(200.0));
+        let distances = state_machine.compute_distances(RangingMode::DoubleSided);
+        assert!((distances[0].unwrap() - 1000.0 * METERS_PER_TICK).abs() < 1e-6);
+        assert!((distances[1].unwrap() - 2000.0 * METERS_PER_TICK).abs() < 1e-6);
+        assert_eq!(distances[2], None);
+    }
+
+    /// Run a round against a single anchor whose clock drifts by `anchor_ppm` relative to the
+    /// tag, with a `reply` time in device time units between each message.
+    fn run_drift_round(
+        tof: f64,
+        anchor_ppm: f64,
+        reply: f64,
+    ) -> TagSideStateMachine<WaitingForAnchorFinal> {
+        let anchor_clock = |t: f64| (t * (1.0 + anchor_ppm * 1e-6)) as u64;
+        let tag_clock = |t: f64| (t + 3000.0) as u64;
+
+        let poll_tx = 1000.0;
+        let response_tx = poll_tx + tof + reply;
+        let final_tx = response_tx + tof + reply;
+
+        let state_machine =
+            TagSideStateMachine::<Idle>::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.set_poll_tx_ts_idx(0, anchor_clock(poll_tx));
+        state_machine.set_poll_rx_ts_idx(0, tag_clock(poll_tx + tof));
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.set_response_tx_ts(tag_clock(response_tx));
+        state_machine.set_response_rx_ts_idx(0, anchor_clock(response_tx + tof));
+        state_machine.set_final_tx_ts_idx(0, anchor_clock(final_tx));
+        state_machine.set_final_rx_ts_idx(0, tag_clock(final_tx + tof));
+
+        state_machine
+    }
+
+    #[test]
+    fn test_ranging_modes() {
+        let tof = 2000.0;
+        let expected = tof * METERS_PER_TICK;
+
+        // Without drift both modes agree
+        let state_machine = run_drift_round(tof, 0.0, 32_000_000.0);
+        let ss = state_machine.compute_distances(RangingMode::SingleSided)[0].unwrap();
+        let ds = state_machine.compute_distances(RangingMode::DoubleSided)[0].unwrap();
+        assert!((ss - expected).abs() < 0.01);
+        assert!((ds - expected).abs() < 0.01);
+
+        // With 20 ppm drift over a ~500 us reply, only DS stays accurate
+        let state_machine = run_drift_round(tof, 20.0, 32_000_000.0);
+        let ss = state_machine.compute_distances(RangingMode::SingleSided)[0].unwrap();
+        let ds = state_machine.compute_distances(RangingMode::DoubleSided)[0].unwrap();
+        assert!((ss - expected).abs() > 1.0);
+        assert!((ds - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_clock_offset_correction() {
+        use crate::calibration::clock_offset_ratio;
+        use dw3000_ng::Config;
+
+        // The carrier integrator of the anchor's frames says its clock runs ~20 ppm fast
+        let ratio = clock_offset_ratio(-35_000, &Config::default());
+        assert!((ratio * 1e6 - 20.06).abs() < 0.01);
+
+        // Both clocks are close to the end of the 40-bit range, and roll over during the round
+        let anchor_clock = |t: f64| {
+            (TIMESTAMP_MASK - 20_000_000).wrapping_add((t * (1.0 + ratio)) as u64) & TIMESTAMP_MASK
+        };
+        let tag_clock =
+            |t: f64| (TIMESTAMP_MASK - 40_000_000).wrapping_add(t as u64) & TIMESTAMP_MASK;
+
+        let (tof, reply) = (2000.0, 32_000_000.0);
+        let poll_tx = 1000.0;
+        let response_tx = poll_tx + tof + reply;
+        let final_tx = response_tx + tof + reply;
+
+        let state_machine =
+            TagSideStateMachine::<Idle>::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.set_poll_tx_ts_idx(0, anchor_clock(poll_tx));
+        state_machine.set_poll_rx_ts_idx(0, tag_clock(poll_tx + tof));
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.set_response_tx_ts(tag_clock(response_tx));
+        state_machine.set_response_rx_ts_idx(0, anchor_clock(response_tx + tof));
+        state_machine.set_final_tx_ts_idx(0, anchor_clock(final_tx));
+        state_machine.set_final_rx_ts_idx(0, tag_clock(final_tx + tof));
+
+        // Uncorrected, the single-sided estimate is off by half the drift over the reply
+        let expected = tof * METERS_PER_TICK;
+        let ss = state_machine.compute_distances(RangingMode::SingleSided)[0].unwrap();
+        assert!((ss - expected).abs() > 1.0);
+
+        state_machine.set_clock_offset_ratio_idx(0, ratio);
+        for mode in [
+            RangingMode::SingleSided,
+            RangingMode::DoubleSided,
+            RangingMode::Combined(CombineStrategy::Average),
+        ] {
+            let distance = state_machine.compute_distances(mode)[0].unwrap();
+            assert!((distance - expected).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_cfo_ppm() {
+        let state_machine = run_drift_round(2000.0, 10.0, 32_000_000.0);
+        assert!((state_machine.cfo_ppm(0).unwrap() - 10.0).abs() < 0.01);
+
+        let state_machine = run_drift_round(2000.0, -4.0, 32_000_000.0);
+        assert!((state_machine.cfo_ppm(0).unwrap() + 4.0).abs() < 0.01);
+
+        assert_eq!(state_machine.cfo_ppm(1), None);
+    }
+
+    #[test]
+    fn test_to_network_time() {
+        // Two tags with different skew and offset against the root
+        let clocks = [
+            |t: u64| ((t as f64) * (1.0 + 10e-6)) as u64 + 5_000,
+            |t: u64| ((t as f64) * (1.0 - 5e-6)) as u64 + 90_000,
+        ];
+
+        let mut network_ts = clocks.iter().map(|clock| {
+            let mut sync = TimeSync::new();
+            for i in 0..4 {
+                let t = i * 1_000_000_000;
+                sync.add_beacon(clock(t), t);
+            }
+
+            // Both tags hear the poll and the final at the same instants
+            let state_machine =
+                TagSideStateMachine::<Idle>::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+            let mut state_machine = state_machine.waiting_for_anchor_poll();
+            state_machine.set_poll_rx_ts_idx(0, clock(3_500_000_000));
+            let mut state_machine = state_machine.waiting_for_anchor_final();
+            state_machine.set_final_rx_ts_idx(0, clock(3_600_000_000));
+
+            state_machine.to_network_time(&sync)
+        });
+
+        let a = network_ts.next().unwrap();
+        let b = network_ts.next().unwrap();
+
+        for (a, b) in [
+            (a.poll_rx_ts[0], b.poll_rx_ts[0]),
+            (a.final_rx_ts[0], b.final_rx_ts[0]),
+        ] {
+            assert!(a.abs_diff(b) <= 2);
+        }
+        assert!(a.poll_rx_ts[0].abs_diff(3_500_000_000) <= 2);
+    }
+
+    #[test]
+    fn test_valid_transitions() {
+        let mut any_sm = AnyTagSideStateMachine::from(TagSideStateMachine::<Idle>::new(
+            100,
+            Vec::new(),
+            Vec::new(),
+        ));
+
+        assert_eq!(any_sm.valid_transitions(), ["waiting_for_anchor_poll"]);
+        any_sm.to_waiting_for_anchor_poll().unwrap();
+        assert_eq!(
+            any_sm.valid_transitions(),
+            ["waiting_for_anchor_final", "idle"]
+        );
+        any_sm.to_waiting_for_anchor_final().unwrap();
+        assert_eq!(any_sm.valid_transitions(), ["idle"]);
+    }
+
+    #[test]
+    fn test_empty_anchors() {
+        let state_machine = TagSideStateMachine::<Idle>::new(100, Vec::new(), Vec::new());
+        let state_machine = state_machine.waiting_for_anchor_poll();
+        let state_machine = state_machine.waiting_for_anchor_final();
+
+        assert_eq!(state_machine.participation(), 0);
+        assert!(state_machine
+            .compute_distances(RangingMode::SingleSided)
+            .is_empty());
+        assert!(state_machine
+            .compute_distances(RangingMode::DoubleSided)
+            .is_empty());
+
+        let network_ts = state_machine.to_network_time(&TimeSync::new());
+        assert!(network_ts.poll_rx_ts.is_empty());
+        assert!(network_ts.final_rx_ts.is_empty());
+
+        let results = state_machine.finalize();
+        assert!(results.distances().is_empty());
+        assert_eq!(results.distance(0), None);
+        assert_eq!(results.participation(), 0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let tofs = [Some(1000), Some(1100), Some(1200), Some(1300)];
+        let complete = run_round(&tofs);
+
+        // Split the finals of the complete round across two RX windows
+        let split = |finals: core::ops::Range<usize>| {
+            let state_machine =
+                TagSideStateMachine::<Idle>::new(100, Vec::from_iter(0..4), Vec::from_iter([100]));
+            let mut state_machine = state_machine.waiting_for_anchor_poll();
+            for i in 0..4 {
+                state_machine.set_poll_tx_ts_idx(i, complete.poll_tx_ts[i]);
+                state_machine.set_poll_rx_ts_idx(i, complete.poll_rx_ts[i]);
+            }
+
+            let mut state_machine = state_machine.waiting_for_anchor_final();
+            state_machine.set_response_tx_ts(complete.response_tx_ts);
+            for i in finals {
+                state_machine.set_response_rx_ts_idx(i, complete.response_rx_ts[i]);
+                state_machine.set_final_tx_ts_idx(i, complete.final_tx_ts[i]);
+                state_machine.set_final_rx_ts_idx(i, complete.final_rx_ts[i]);
+            }
+            state_machine
+        };
+
+        let mut first = split(0..2);
+        let second = split(2..4);
+        assert_eq!(first.participation(), 0b0011);
+
+        first.merge(&second);
+
+        assert_eq!(first.participation(), 0b1111);
+        assert_eq!(
+            first.compute_distances(RangingMode::DoubleSided),
+            complete.compute_distances(RangingMode::DoubleSided)
+        );
+    }
+
+    #[test]
+    fn test_merge_response_tx_at_zero() {
+        let state_machine =
+            TagSideStateMachine::<Idle>::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+        let empty = state_machine
+            .waiting_for_anchor_poll()
+            .waiting_for_anchor_final();
+
+        let state_machine =
+            TagSideStateMachine::<Idle>::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut other = state_machine
+            .waiting_for_anchor_poll()
+            .waiting_for_anchor_final();
+        other.set_response_tx_ts(0x1234);
+
+        // Sent right at the 40-bit rollover, a timestamp of zero is kept
+        let state_machine =
+            TagSideStateMachine::<Idle>::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+        let mut at_rollover = state_machine
+            .waiting_for_anchor_poll()
+            .waiting_for_anchor_final();
+        at_rollover.set_response_tx_ts(0);
+        at_rollover.merge(&other);
+        assert_eq!(at_rollover.response_tx_ts, 0);
+
+        // Without a response TX timestamp, the other's is taken
+        let mut missing = empty;
+        missing.merge(&other);
+        assert_eq!(missing.response_tx_ts, 0x1234);
+    }
+
+    #[test]
+    fn test_tick_period() {
+        let mut state_machine = run_round(&[Some(1000), Some(2000)]);
+        let nominal = state_machine.compute_distances(RangingMode::DoubleSided);
+
+        state_machine.set_tick_period_ps(DWT_TIME_UNIT * 1e12 * 1.001);
+        let adjusted = state_machine.compute_distances(RangingMode::DoubleSided);
+
+        for (nominal, adjusted) in nominal.iter().zip(adjusted.iter()) {
+            assert!((adjusted.unwrap() / nominal.unwrap() - 1.001).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_default() {
+        // A default machine, set up afterwards, ranges like one from `new`
+        let mut state_machine = TagSideStateMachine::<Idle>::default();
+        state_machine.reindex(Vec::from_iter([0]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.set_poll_tx_ts_idx(0, 1_000);
+        state_machine.set_poll_rx_ts_idx(0, 5_000);
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.set_response_tx_ts(105_000);
+        state_machine.set_response_rx_ts_idx(0, 103_000);
+        state_machine.set_final_tx_ts_idx(0, 203_000);
+        state_machine.set_final_rx_ts_idx(0, 207_000);
+
+        // Taken out the way the type-erased wrappers do
+        let state_machine = core::mem::take(&mut state_machine);
+        let distance = state_machine.compute_distances(RangingMode::DoubleSided)[0].unwrap();
+        assert!((distance - 1000.0 * METERS_PER_TICK).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_verify_anchor_consistency() {
+        use crate::packet::PacketType;
+        use bilge::prelude::*;
+
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([1, 2]), Vec::from_iter([100]));
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        for anchor_addr in [1, 2] {
+            let poll = PollPacket::new(
+                PacketType::Poll,
+                u4::new(0),
+                u40::new(1_000),
+                anchor_addr,
+                0,
+            );
+            state_machine.apply_poll_packet(&poll, 5_000);
+        }
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        for i in 0..2 {
+            state_machine.set_final_rx_ts_idx(i, 100_000);
+        }
+        state_machine.set_final_source_idx(0, 1);
+        assert_eq!(state_machine.verify_anchor_consistency(), Ok(()));
+
+        // Anchor 1's final was missed, and anchor 3's (unknown) final took its place
+        state_machine.set_final_source_idx(1, 3);
+        assert_eq!(state_machine.verify_anchor_consistency(), Err(2));
+
+        // Out of range, ignored
+        state_machine.set_final_source_idx(2, 3);
+        assert_eq!(state_machine.verify_anchor_consistency(), Err(2));
+    }
+
+    #[test]
+    fn test_propagation_speed() {
+        let mut state_machine = run_round(&[Some(1000), Some(2000)]);
+        let in_air = state_machine.compute_distances(RangingMode::DoubleSided);
+        assert!((in_air[0].unwrap() - 1000.0 * METERS_PER_TICK).abs() < 1e-6);
+
+        // A default machine starts out in air as well
+        let default = TagSideStateMachine::<WaitingForAnchorFinal>::default();
+        assert_eq!(default.propagation_speed_m_per_s, SPEED_OF_LIGHT);
+
+        state_machine.set_propagation_speed(SPEED_OF_LIGHT / 2.0);
+        let in_medium = state_machine.compute_distances(RangingMode::DoubleSided);
+
+        for (in_air, in_medium) in in_air.iter().zip(in_medium.iter()) {
+            assert!((in_medium.unwrap() - in_air.unwrap() / 2.0).abs() < 1e-9);
+        }
+    }
+
+    struct MockRx {
+        timestamp: u64,
+        rssi: f32,
+    }
+
+    impl RxMetadata for MockRx {
+        fn rx_timestamp(&self) -> u64 {
+            self.timestamp
+        }
+
+        fn rssi(&self) -> f32 {
+            self.rssi
+        }
+    }
+
+    #[test]
+    fn test_apply_rx() {
+        let state_machine =
+            TagSideStateMachine::<Idle>::new(100, Vec::from_iter([0, 1]), Vec::from_iter([100]));
+
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.apply_rx(
+            1,
+            &MockRx {
+                timestamp: 0x12_3456_789a,
+                rssi: -80.5,
+            },
+        );
+
+        assert_eq!(state_machine.poll_rx_ts[1], 0x12_3456_789a);
+        assert_eq!(state_machine.rssi[1], -80.5);
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.apply_rx(
+            1,
+            &MockRx {
+                // Bits above the 40-bit hardware timestamp are dropped
+                timestamp: 0xff00_0000_0000_0042,
+                rssi: -82.0,
+            },
+        );
+
+        assert_eq!(state_machine.final_rx_ts[1], 0x42);
+        assert_eq!(state_machine.rssi[1], -82.0);
+        assert_eq!(state_machine.participation(), 0b10);
+    }
+
+    #[test]
+    fn test_apply_rx_ignored() {
+        let rx = MockRx {
+            timestamp: 0x1234,
+            rssi: -70.0,
+        };
+        let state_machine =
+            TagSideStateMachine::<Idle>::new(100, Vec::from_iter([0, 1]), Vec::from_iter([100]));
+
+        // Out of range, neither stored nor a panic
+        let mut state_machine = state_machine.waiting_for_anchor_poll();
+        state_machine.apply_rx(2, &rx);
+
+        // Masked, the RSSI is kept too
+        state_machine.set_active_anchors(0b01);
+        state_machine.apply_rx(1, &rx);
+        assert_eq!(state_machine.poll_rx_ts[1], 0);
+        assert_eq!(state_machine.rssi[1], 0.0);
+
+        let mut state_machine = state_machine.waiting_for_anchor_final();
+        state_machine.apply_rx(2, &rx);
+        state_machine.blacklist_anchor(0).unwrap();
+        state_machine.apply_rx(0, &rx);
+        assert_eq!(state_machine.final_rx_ts[0], 0);
+        assert_eq!(state_machine.rssi[0], 0.0);
+        assert_eq!(state_machine.participation(), 0);
+    }
+
+    #[test]
+    fn test_finalize() {
+        let state_machine = run_round(&[Some(1000), Some(2000), None]);
+        let quality = state_machine.quality(1);
+        let results = state_machine.finalize();
+
+        assert_eq!(results.address(), 100);
+        assert_eq!(results.anchors(), [0, 1, 2]);
+        assert_eq!(results.participation(), 0b011);
+        assert!((results.distance(0).unwrap() - 1000.0 * METERS_PER_TICK).abs() < 1e-6);
+        assert!((results.distance(1).unwrap() - 2000.0 * METERS_PER_TICK).abs() < 1e-6);
+        assert_eq!(results.distance(2), None);
+        assert!(quality.is_some());
+        assert_eq!(results.quality(1), quality);
+        assert_eq!(results.quality(2), None);
+    }
+
+    #[test]
+    fn test_idle_with_results() {
+        /// One round against anchor 0 with a time of flight of `tof` ticks
+        fn round(
+            state_machine: TagSideStateMachine<Idle>,
+            tof: u64,
+        ) -> (TagSideStateMachine<Idle>, RoundResults) {
+            let mut state_machine = state_machine.waiting_for_anchor_poll();
+            state_machine.set_poll_tx_ts_idx(0, 1_000);
+            state_machine.set_poll_rx_ts_idx(0, 4_000 + tof);
+
+            let mut state_machine = state_machine.waiting_for_anchor_final();
+            state_machine.set_response_tx_ts(500_000);
+            state_machine.set_response_rx_ts_idx(0, 497_000 + tof);
+            state_machine.set_final_tx_ts_idx(0, 597_000 + tof);
+            state_machine.set_final_rx_ts_idx(0, 600_000 + 2 * tof);
+            state_machine.idle_with_results()
+        }
+
+        let state_machine =
+            TagSideStateMachine::new(100, Vec::from_iter([0]), Vec::from_iter([100]));
+        let (state_machine, first) = round(state_machine, 1_000);
+        let snapshot = first.clone();
+
+        // Reusing the machine for another round leaves the first results as they were
+        let (_, second) = round(state_machine, 3_000);
+        assert_eq!(first, snapshot);
+        assert!((first.distance(0).unwrap() - 1000.0 * METERS_PER_TICK).abs() < 1e-6);
+        assert!((second.distance(0).unwrap() - 3000.0 * METERS_PER_TICK).abs() < 1e-6);
+        assert_ne!(first.quality(0), None);
+        assert_ne!(second.quality(0), None);
+    }
 }
@@ -8,3 +8,365 @@
 //
 // After all anchors have synchronized their time to the root, the tags just need to calculate their time slot
 // based on their address.
+
+use heapless::Deque;
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::util::{wrapping_before, wrapping_interval, MAX_TIMESTAMP_INTERVAL, TIMESTAMP_MASK};
+
+/// Maximum number of beacons kept for the fit
+const MAX_SAMPLES: usize = 8;
+
+/// Signed difference `a - b` of two 40-bit timestamps, assuming they are within half the
+/// counter range of each other.
+///
+/// Negative if `a` is before `b` (see `wrapping_before`).
+pub(crate) fn signed_diff(a: u64, b: u64) -> i64 {
+    if wrapping_before(a, b, 40) {
+        -(wrapping_interval(a, b) as i64)
+    } else {
+        wrapping_interval(b, a) as i64
+    }
+}
+
+/// Linear fit of the root anchor's clock against the local clock.
+///
+/// Every beacon from the root yields a (local RX time, root TX time) pair. The fit models
+/// `root = local + skew * (local - ref) + offset` around the most recent beacon, so both the
+/// clock offset and the relative frequency error (skew) are tracked.
+#[derive(Debug, Clone, Default)]
+pub struct TimeSync {
+    /// Most recent beacons as (local, root) timestamp pairs
+    samples: Deque<(u64, u64), MAX_SAMPLES>,
+
+    /// Relative frequency error of the root clock against the local clock
+    skew: f64,
+
+    /// Skew assumed while the beacons do not allow fitting it, see `set_skew_prior`
+    skew_prior: f64,
+
+    /// Offset of the fit at the reference (most recent) beacon, in device time units
+    offset: f64,
+}
+
+/// Saved state of a `TimeSync`, to hand the clock fit over across a restart.
+///
+/// Plain data without padding, so it can be stored or sent as raw bytes.
+#[derive(
+    Debug, Clone, Copy, PartialEq, defmt::Format, FromBytes, IntoBytes, Immutable, KnownLayout,
+)]
+#[repr(C)]
+pub struct FitSnapshot {
+    /// Local timestamps of the buffered beacons, oldest first
+    pub local_ts: [u64; MAX_SAMPLES],
+
+    /// Root timestamps of the buffered beacons, oldest first
+    pub root_ts: [u64; MAX_SAMPLES],
+
+    /// Number of buffered beacons
+    pub len: u64,
+
+    /// Relative frequency error of the root clock against the local clock
+    pub skew: f64,
+
+    /// Offset of the fit at the most recent beacon, in device time units
+    pub offset: f64,
+
+    /// Skew assumed while the beacons do not allow fitting it, see `TimeSync::set_skew_prior`
+    pub skew_prior: f64,
+}
+
+impl TimeSync {
+    /// Create a new `TimeSync` with no beacons.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a beacon, received at `local_rx_ts` and sent by the root at `root_tx_ts`, and
+    /// refit the clock model.
+    pub fn add_beacon(&mut self, local_rx_ts: u64, root_tx_ts: u64) {
+        if self.samples.is_full() {
+            self.samples.pop_front();
+        }
+        // Cannot fail, we just made room
+        let _ = self.samples.push_back((local_rx_ts, root_tx_ts));
+
+        self.fit();
+    }
+
+    /// Assume a skew until the beacons allow fitting it, e.g. the skew of a neighbor from a
+    /// bootstrap packet.
+    ///
+    /// Crystals of the same type on the same network tend to be close, so this is a useful prior
+    /// for the first beacon, which alone only fixes the offset. Does not make the fit valid, the
+    /// root time stays unknown until the first beacon.
+    pub fn set_skew_prior(&mut self, skew: f64) {
+        self.skew_prior = skew;
+        if self.samples.len() < 2 {
+            self.skew = skew;
+        }
+    }
+
+    /// Whether at least one beacon has been received.
+    pub fn is_valid(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    /// Whether the fit is fresh at local time `now`: the most recent beacon was received at most
+    /// `max_age` device time units before.
+    ///
+    /// The skew is only tracked while beacons arrive, so a stale fit drifts away from the root
+    /// clock. Never synced before the first beacon.
+    pub fn is_synced(&self, now: u64, max_age: u64) -> bool {
+        let Some(&(local_ref, _)) = self.samples.back() else {
+            return false;
+        };
+
+        // A beacon timestamped after `now` is as fresh as it gets
+        wrapping_before(now, local_ref, 40)
+            || wrapping_interval(local_ref, now) <= max_age.min(MAX_TIMESTAMP_INTERVAL)
+    }
+
+    /// Relative frequency error of the root clock against the local clock, in ppm.
+    pub fn skew_ppm(&self) -> f64 {
+        self.skew * 1e6
+    }
+
+    /// Longest beacon interval, in device time units, that keeps the clock offset error within
+    /// `max_offset_error_ticks`.
+    ///
+    /// Between beacons the offset drifts by the skew times the elapsed time, so the interval is
+    /// the tolerable error over the fitted skew. Capped at `MAX_TIMESTAMP_INTERVAL`, beyond which
+    /// timestamps can no longer be ordered.
+    pub fn recommended_beacon_interval(&self, max_offset_error_ticks: u64) -> u64 {
+        let interval = max_offset_error_ticks as f64 / self.skew.abs();
+        if interval >= MAX_TIMESTAMP_INTERVAL as f64 {
+            MAX_TIMESTAMP_INTERVAL
+        } else {
+            interval as u64
+        }
+    }
+
+    /// Convert a local timestamp to root (network) time.
+    ///
+    /// Returns the timestamp unchanged if no beacon has been received yet.
+    pub fn local_to_root(&self, local: u64) -> u64 {
+        let Some(&(local_ref, root_ref)) = self.samples.back() else {
+            return local;
+        };
+
+        let x = signed_diff(local, local_ref) as f64;
+        let correction = (x * self.skew + self.offset) as i64;
+
+        (root_ref as i64 + x as i64 + correction) as u64 & TIMESTAMP_MASK
+    }
+
+    /// Convert a local timestamp to root (network) time, if the fit is fresh at that time.
+    ///
+    /// `None` if the fit is stale (see `is_synced`), so the caller can fall back to a safe
+    /// default, e.g. wider slots.
+    pub fn try_local_to_root(&self, local: u64, max_age: u64) -> Option<u64> {
+        self.is_synced(local, max_age)
+            .then(|| self.local_to_root(local))
+    }
+
+    /// Save the clock fit and the buffered beacons.
+    pub fn export_fit(&self) -> FitSnapshot {
+        let mut snap = FitSnapshot {
+            local_ts: [0; MAX_SAMPLES],
+            root_ts: [0; MAX_SAMPLES],
+            len: self.samples.len() as u64,
+            skew: self.skew,
+            offset: self.offset,
+            skew_prior: self.skew_prior,
+        };
+        for (i, &(local, root)) in self.samples.iter().enumerate() {
+            snap.local_ts[i] = local;
+            snap.root_ts[i] = root;
+        }
+
+        snap
+    }
+
+    /// Restore a clock fit saved with `export_fit`, replacing the current state.
+    pub fn import_fit(&mut self, snap: FitSnapshot) {
+        let len = (snap.len as usize).min(MAX_SAMPLES);
+
+        self.samples.clear();
+        for i in 0..len {
+            // Cannot fail, at most `MAX_SAMPLES` samples
+            let _ = self.samples.push_back((snap.local_ts[i], snap.root_ts[i]));
+        }
+        self.skew = snap.skew;
+        self.offset = snap.offset;
+        self.skew_prior = snap.skew_prior;
+    }
+
+    /// Least-squares fit of the clock model over all buffered beacons.
+    fn fit(&mut self) {
+        let Some(&(local_ref, root_ref)) = self.samples.back() else {
+            return;
+        };
+
+        // x: local time since the reference, y: how much the root clock gained on it
+        let n = self.samples.len() as f64;
+        let (mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0);
+        for &(local, root) in self.samples.iter() {
+            let x = signed_diff(local, local_ref) as f64;
+            let y = signed_diff(root, root_ref) as f64 - x;
+            sx += x;
+            sy += y;
+            sxx += x * x;
+            sxy += x * y;
+        }
+
+        let denominator = n * sxx - sx * sx;
+        if denominator == 0.0 {
+            self.skew = self.skew_prior;
+            self.offset = (sy - self.skew * sx) / n;
+        } else {
+            self.skew = (n * sxy - sx * sy) / denominator;
+            self.offset = (sy - self.skew * sx) / n;
+        }
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fit() {
+        // Local clock runs 10 ppm fast with a large offset
+        let local = |t: u64| ((t as f64) * (1.0 + 10e-6)) as u64 + 1_000_000;
+
+        let mut sync = TimeSync::new();
+        assert_eq!(sync.local_to_root(1234), 1234);
+
+        for i in 0..4 {
+            let t = i * 1_000_000_000;
+            sync.add_beacon(local(t), t);
+        }
+
+        assert!((sync.skew_ppm() + 10.0).abs() < 0.01);
+
+        let t = 3_500_000_000;
+        assert!(signed_diff(sync.local_to_root(local(t)), t).abs() <= 2);
+    }
+
+    #[test]
+    fn test_export_import_fit() {
+        let local = |t: u64| ((t as f64) * (1.0 - 5e-6)) as u64 + 42_000;
+
+        let mut sync = TimeSync::new();
+        for i in 0..10 {
+            let t = i * 500_000_000;
+            sync.add_beacon(local(t), t);
+        }
+
+        let mut restored = TimeSync::new();
+        restored.import_fit(sync.export_fit());
+
+        assert_eq!(restored.export_fit(), sync.export_fit());
+        assert_eq!(restored.skew_ppm(), sync.skew_ppm());
+        for t in [4_000_000_000, 5_000_000_000, 7_000_000_000] {
+            assert_eq!(
+                restored.local_to_root(local(t)),
+                sync.local_to_root(local(t))
+            );
+        }
+
+        // New beacons refit over the restored samples too
+        sync.add_beacon(local(5_000_000_000), 5_000_000_000);
+        restored.add_beacon(local(5_000_000_000), 5_000_000_000);
+        assert_eq!(restored.export_fit(), sync.export_fit());
+
+        // The skew prior of a node that joined from a bootstrap packet survives a restart
+        let mut joined = TimeSync::new();
+        joined.set_skew_prior(-5e-6);
+        let mut restored = TimeSync::new();
+        restored.import_fit(joined.export_fit());
+        assert_eq!(restored.export_fit().skew_prior, -5e-6);
+
+        joined.add_beacon(local(0), 0);
+        restored.add_beacon(local(0), 0);
+        assert_eq!(restored.export_fit(), joined.export_fit());
+        assert_eq!(restored.skew_ppm(), -5.0);
+    }
+
+    #[test]
+    fn test_recommended_beacon_interval() {
+        let fit = |ppm: f64| {
+            let mut sync = TimeSync::new();
+            for i in 0..4 {
+                let t = i * 1_000_000_000;
+                sync.add_beacon(((t as f64) * (1.0 + ppm * 1e-6)) as u64, t);
+            }
+            sync
+        };
+
+        let stable = fit(1.0).recommended_beacon_interval(1_000);
+        let drifting = fit(40.0).recommended_beacon_interval(1_000);
+        assert!(drifting < stable);
+        // 1000 ticks at 40 ppm
+        assert!(drifting.abs_diff(25_000_000) < 100_000);
+
+        // Without skew the interval is only bounded by the counter range
+        assert_eq!(
+            TimeSync::new().recommended_beacon_interval(1_000),
+            MAX_TIMESTAMP_INTERVAL
+        );
+    }
+
+    #[test]
+    fn test_is_synced() {
+        const MAX_AGE: u64 = 1_000_000;
+
+        let mut sync = TimeSync::new();
+        assert!(!sync.is_synced(0, MAX_AGE));
+        assert_eq!(sync.try_local_to_root(0, MAX_AGE), None);
+
+        sync.add_beacon(10_000, 510_000);
+        assert!(sync.is_synced(10_000 + MAX_AGE, MAX_AGE));
+        assert_eq!(sync.try_local_to_root(20_000, MAX_AGE), Some(520_000));
+
+        // Stale fit
+        assert!(!sync.is_synced(10_001 + MAX_AGE, MAX_AGE));
+        assert_eq!(sync.try_local_to_root(5_000_000, MAX_AGE), None);
+
+        // A fresh beacon, after the 40-bit rollover
+        sync.add_beacon(TIMESTAMP_MASK - 999, 499_000);
+        assert!(sync.is_synced(100_000, MAX_AGE));
+        assert!(sync.is_synced(TIMESTAMP_MASK - 1_999, MAX_AGE));
+        assert!(!sync.is_synced(MAX_AGE, MAX_AGE));
+    }
+
+    #[test]
+    fn test_signed_diff() {
+        assert_eq!(signed_diff(1_500, 1_000), 500);
+        assert_eq!(signed_diff(1_000, 1_500), -500);
+        assert_eq!(signed_diff(0, 0), 0);
+
+        // Across the 40-bit rollover, both ways
+        assert_eq!(signed_diff(499, TIMESTAMP_MASK - 499), 999);
+        assert_eq!(signed_diff(TIMESTAMP_MASK - 499, 499), -999);
+
+        // Up to the longest interval that can be ordered
+        let max = MAX_TIMESTAMP_INTERVAL;
+        assert_eq!(signed_diff(max, 0), max as i64);
+        assert_eq!(signed_diff(0, max), -(max as i64));
+    }
+
+    #[test]
+    fn test_wrapping() {
+        // Beacons straddle the 40-bit rollover of both clocks
+        let mut sync = TimeSync::new();
+        sync.add_beacon(TIMESTAMP_MASK - 999, TIMESTAMP_MASK - 499);
+        sync.add_beacon(1_000, 1_500);
+
+        assert_eq!(sync.local_to_root(2_000), 2_500);
+        assert_eq!(sync.local_to_root(TIMESTAMP_MASK), 499);
+    }
+}
@@ -1,6 +1,123 @@
 use dw3000_ng::Config;
 
+use crate::packet::PacketType;
+
+/// Mask for the 40-bit DW3000 device timestamps
+pub const TIMESTAMP_MASK: u64 = (1 << 40) - 1;
+
+/// Longest interval between two device timestamps that `wrapping_before` can still order, just
+/// under half the 40-bit counter range (~8.6 s)
+pub const MAX_TIMESTAMP_INTERVAL: u64 = TIMESTAMP_MASK >> 1;
+
+/// Duration of one DW3000 device time unit in seconds (1 / (128 * 499.2 MHz), ~15.65 ps)
+pub const DWT_TIME_UNIT: f64 = 1.0 / (128.0 * 499.2e6);
+
+/// Speed of light in air in m/s
+pub const SPEED_OF_LIGHT: f64 = 299_702_547.0;
+
+/// Guard time inserted after each protocol phase in nanoseconds
+pub const PHASE_GUARD_TIME: u32 = 500_000;
+
+/// RX metadata reported by the radio for a received frame.
+///
+/// `dw3000_ng` reports the RX timestamp and the signal quality through separate calls, implement
+/// this on whatever your driver bundles them into to feed received frames straight into the
+/// state machines.
+pub trait RxMetadata {
+    /// RX timestamp in device time units (40-bit)
+    fn rx_timestamp(&self) -> u64;
+
+    /// Estimated receive power in dBm
+    fn rssi(&self) -> f32;
+}
+
+/// Source of random numbers, e.g. a hardware RNG.
+///
+/// `no_std` targets have no default RNG, so the randomized features (such as
+/// `Backoff::next_delay_with`) take one from the caller. `Xorshift16` is a simple built-in.
+pub trait RngSource {
+    /// Next random 16-bit value
+    fn next_u16(&mut self) -> u16;
+}
+
+/// 16-bit xorshift pseudo-random generator (shifts 7, 9, 8), with a period of 2^16 - 1.
+///
+/// Not suitable where an adversary could predict it, but enough to desynchronize nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Xorshift16 {
+    state: u16,
+}
+
+impl Xorshift16 {
+    /// Create a new generator from a seed, e.g. the node address. A zero seed is replaced, as
+    /// xorshift would be stuck at zero.
+    pub fn new(seed: u16) -> Self {
+        Self {
+            state: if seed == 0 { 0xACE1 } else { seed },
+        }
+    }
+}
+
+impl RngSource for Xorshift16 {
+    fn next_u16(&mut self) -> u16 {
+        self.state ^= self.state << 7;
+        self.state ^= self.state >> 9;
+        self.state ^= self.state << 8;
+        self.state
+    }
+}
+
+/// Interval from timestamp `a` to timestamp `b` in device time units, across 40-bit rollovers
+///
+/// Assumes `b` is after `a` by less than half the counter range (~8.6 s); the interval to an
+/// earlier `b` comes out as a huge value instead of going negative.
+pub fn wrapping_interval(a: u64, b: u64) -> u64 {
+    b.wrapping_sub(a) & TIMESTAMP_MASK
+}
+
+/// Whether `a` is strictly before `b` on a wrapping counter of `width_bits` bits
+///
+/// `a` is before `b` if `b` is ahead of it by less than half the counter range, modulo the
+/// range. Exactly half the range apart is ambiguous and neither is before the other. Bits of `a`
+/// and `b` above the width are ignored. Use this for 40-bit device timestamps (width 40) and
+/// 8-bit sequence numbers (width 8) alike. Will panic if `width_bits` is not in `1..=64`.
+pub fn wrapping_before(a: u64, b: u64, width_bits: u32) -> bool {
+    assert!((1..=64).contains(&width_bits));
+
+    let mask = u64::MAX >> (64 - width_bits);
+    let delta = b.wrapping_sub(a) & mask;
+    delta != 0 && delta < 1 << (width_bits - 1)
+}
+
+/// Length in bytes of the IEEE 802.15.4 frame around a `payload_len` byte payload
+///
+/// Adds the MAC header (frame control, sequence number, one PAN ID with PAN ID compression, and
+/// the short destination and/or source address) and the 2-byte FCS. This is the `frame_len` to
+/// pass to `frame_tx_time`.
+pub fn total_frame_len(payload_len: u32, has_dst: bool, has_src: bool) -> u32 {
+    const FRAME_CONTROL_LEN: u32 = 2;
+    const SEQUENCE_NUMBER_LEN: u32 = 1;
+    const PAN_ID_LEN: u32 = 2;
+    const SHORT_ADDRESS_LEN: u32 = 2;
+    const FCS_LEN: u32 = 2;
+
+    let mut mhr_len = FRAME_CONTROL_LEN + SEQUENCE_NUMBER_LEN;
+    if has_dst || has_src {
+        mhr_len += PAN_ID_LEN;
+    }
+    if has_dst {
+        mhr_len += SHORT_ADDRESS_LEN;
+    }
+    if has_src {
+        mhr_len += SHORT_ADDRESS_LEN;
+    }
+
+    mhr_len + payload_len + FCS_LEN
+}
+
 /// Calculate frame TX time in nanoseconds
+///
+/// `frame_len` is the length of the whole frame in bytes, see `total_frame_len`.
 pub fn frame_tx_time(mut frame_len: u32, config: &Config, include_body: bool) -> u32 {
     let mut tx_time;
     let mut shr_len;
@@ -78,3 +195,163 @@ pub fn frame_tx_time(mut frame_len: u32, config: &Config, include_body: bool) ->
 
     tx_time
 }
+
+/// Air time in nanoseconds of a packet in its MAC frame, with short destination and source
+/// addresses (see `total_frame_len`)
+///
+/// `num_tags` is the number of tag slots of a final packet, see `PacketType::wire_len`.
+pub fn packet_tx_time(packet_type: PacketType, num_tags: usize, config: &Config) -> u32 {
+    let payload_len = packet_type.wire_len(num_tags) as u32;
+
+    frame_tx_time(total_frame_len(payload_len, true, true), config, true)
+}
+
+/// Calculate the worst-case duration of one full ranging round in nanoseconds
+///
+/// Sums the air time of all polls, all responses, and all finals, plus a `PHASE_GUARD_TIME`
+/// after each phase. Without anchors or tags no ranging takes place and the budget is zero.
+pub fn round_budget(num_anchors: usize, num_tags: usize, config: &Config) -> u32 {
+    if num_anchors == 0 || num_tags == 0 {
+        return 0;
+    }
+
+    round_air_time(num_anchors, num_tags, config) + 3 * PHASE_GUARD_TIME
+}
+
+/// Air time of all polls, all responses, and all finals of one round in nanoseconds
+pub(crate) fn round_air_time(num_anchors: usize, num_tags: usize, config: &Config) -> u32 {
+    let air_time = |packet_type: PacketType| packet_tx_time(packet_type, num_tags, config);
+
+    let polls = num_anchors as u32 * air_time(PacketType::Poll);
+    let responses = num_tags as u32 * air_time(PacketType::Response);
+    let finals = num_anchors as u32 * air_time(PacketType::Final);
+
+    polls + responses + finals
+}
+
+/// Check that no address appears in both the anchor and the tag list
+///
+/// A node can't be both an anchor and a tag. Returns the first overlapping address on error.
+pub fn check_disjoint(anchors: &[u16], tags: &[u16]) -> Result<(), u16> {
+    match anchors.iter().find(|anchor| tags.contains(anchor)) {
+        Some(&address) => Err(address),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dw3000_ng::configs::{BitRate, PreambleLength, PulseRepetitionFrequency};
+
+    fn config_6m8() -> Config {
+        Config {
+            bitrate: BitRate::Kbps6800,
+            pulse_repetition_frequency: PulseRepetitionFrequency::Mhz64,
+            preamble_length: PreambleLength::Symbols128,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_total_frame_len() {
+        let config = config_6m8();
+
+        // A poll with the usual short destination and source addresses
+        let payload_len = PacketType::Poll.wire_len(0) as u32;
+        assert_eq!(
+            total_frame_len(payload_len, true, true),
+            9 + payload_len + 2
+        );
+        assert!(
+            frame_tx_time(total_frame_len(payload_len, true, true), &config, true)
+                > frame_tx_time(payload_len, &config, true)
+        );
+
+        assert_eq!(
+            total_frame_len(payload_len, true, false),
+            7 + payload_len + 2
+        );
+        assert_eq!(
+            total_frame_len(payload_len, false, false),
+            3 + payload_len + 2
+        );
+    }
+
+    #[test]
+    fn test_wrapping_interval() {
+        assert_eq!(wrapping_interval(1_000, 5_000), 4_000);
+
+        // Straddling the 40-bit rollover
+        assert_eq!(wrapping_interval(TIMESTAMP_MASK - 999, 3_000), 4_000);
+        // Unmasked high bits don't matter
+        assert_eq!(
+            wrapping_interval(TIMESTAMP_MASK - 999, (1 << 40) + 3_000),
+            4_000
+        );
+    }
+
+    #[test]
+    fn test_wrapping_before() {
+        // 40-bit device timestamps across the rollover
+        assert!(wrapping_before(TIMESTAMP_MASK - 10, 5, 40));
+        assert!(!wrapping_before(5, TIMESTAMP_MASK - 10, 40));
+        assert!(wrapping_before(0, (1 << 39) - 1, 40));
+        assert!(!wrapping_before(0, 1 << 39, 40));
+        assert!(!wrapping_before(1 << 39, 0, 40));
+        assert!(!wrapping_before(1234, 1234, 40));
+
+        // 8-bit sequence numbers
+        assert!(wrapping_before(255, 0, 8));
+        assert!(wrapping_before(250, 3, 8));
+        assert!(!wrapping_before(3, 250, 8));
+        assert!(wrapping_before(0, 127, 8));
+        assert!(!wrapping_before(0, 128, 8));
+        assert!(!wrapping_before(128, 0, 8));
+        // Bits above the width do not count
+        assert!(wrapping_before(0x1ff, 0x200, 8));
+
+        assert!(wrapping_before(u64::MAX, 0, 64));
+    }
+
+    #[test]
+    fn test_round_budget() {
+        let config = config_6m8();
+
+        // SHR: (128 + 8) * 1018, PHR: 21 * 1026
+        let header = 136 * 1018 + 21 * 1026;
+        // Data symbols (bits + Reed-Solomon parity) * 129, of the packets plus 11 bytes of MAC
+        // header and FCS
+        let poll = header + (160 + 71) * 129;
+        let response = header + (104 + 62) * 129;
+        let final_ = header + (280 + 88) * 129;
+
+        assert_eq!(packet_tx_time(PacketType::Poll, 3, &config), poll);
+        assert_eq!(packet_tx_time(PacketType::Response, 3, &config), response);
+        assert_eq!(packet_tx_time(PacketType::Final, 3, &config), final_);
+        assert_eq!(frame_tx_time(20, &config, true), poll);
+
+        assert_eq!(
+            round_budget(8, 3, &config),
+            8 * poll + 3 * response + 8 * final_ + 3 * PHASE_GUARD_TIME
+        );
+        assert_eq!(round_budget(8, 3, &config), 5_222_296);
+    }
+
+    #[test]
+    fn test_check_disjoint() {
+        assert_eq!(check_disjoint(&[0, 1, 2], &[100, 101]), Ok(()));
+        assert_eq!(check_disjoint(&[0, 1, 2], &[100, 1, 2]), Err(1));
+        assert_eq!(check_disjoint(&[], &[100]), Ok(()));
+    }
+
+    #[test]
+    fn test_round_budget_empty() {
+        let config = config_6m8();
+
+        assert_eq!(round_budget(0, 0, &config), 0);
+        assert_eq!(round_budget(0, 3, &config), 0);
+        assert_eq!(round_budget(8, 0, &config), 0);
+    }
+}
@@ -0,0 +1,46 @@
+// Propagating module errors into the crate-wide error with `?`
+
+use std::format;
+
+use heapless::Vec;
+use magic_loc_protocol::anchor_state_machine::{AnchorSideStateMachine, AnyAnchorSideStateMachine};
+use magic_loc_protocol::packet::{parse_packet, ParseError};
+use magic_loc_protocol::Error;
+
+/// Parse a packet and record it as a response, the way an application would
+fn handle_response(
+    state_machine: &mut AnyAnchorSideStateMachine,
+    buf: &[u8],
+    tag_idx: usize,
+    rx_ts: u64,
+) -> Result<(), Error> {
+    parse_packet(buf)?;
+    state_machine.set_response_rx_ts(tag_idx, rx_ts)?;
+    Ok(())
+}
+
+#[test]
+fn module_errors_convert() {
+    let mut state_machine: AnyAnchorSideStateMachine =
+        AnchorSideStateMachine::new(0, Vec::from_iter([0]), Vec::from_iter([100])).into();
+
+    // Version 2 response
+    assert_eq!(
+        handle_response(&mut state_machine, &[0x21, 0x00], 0, 1_000),
+        Err(Error::Parse(ParseError::VersionMismatch))
+    );
+
+    // Valid response, but the anchor has not sent its poll yet
+    let err = handle_response(&mut state_machine, &[0x11, 0x00], 0, 1_000).unwrap_err();
+    assert_eq!(err, Error::WrongPhase);
+    assert_eq!(format!("{:?}", err), "WrongPhase");
+
+    state_machine.to_waiting_for_response(0).unwrap();
+    assert_eq!(
+        handle_response(&mut state_machine, &[0x11, 0x00], 0, 1_000),
+        Ok(())
+    );
+
+    let err = Error::from(ParseError::InvalidLength);
+    assert_eq!(format!("{:?}", err), "Parse(InvalidLength)");
+}
@@ -0,0 +1,77 @@
+// Report the memory footprint of the state machines, for RAM budgeting
+
+use std::println;
+
+use magic_loc_protocol::anchor_state_machine::{self, AnchorSideStateMachine};
+use magic_loc_protocol::localizer::{memory_budget, Localizer, PositionFix};
+use magic_loc_protocol::position::Point;
+use magic_loc_protocol::tag_state_machine::{self, AnyTagSideStateMachine, TagSideStateMachine};
+use magic_loc_protocol::time_sync::TimeSync;
+
+#[test]
+fn report_sizes() {
+    println!(
+        "AnchorSideStateMachine: {} bytes",
+        AnchorSideStateMachine::<anchor_state_machine::Idle>::FOOTPRINT
+    );
+    println!(
+        "TagSideStateMachine: {} bytes",
+        TagSideStateMachine::<tag_state_machine::Idle>::FOOTPRINT
+    );
+
+    // The footprint does not depend on the state, only on the capacity of the per-node vectors
+    assert_eq!(
+        AnchorSideStateMachine::<anchor_state_machine::Idle>::FOOTPRINT,
+        AnchorSideStateMachine::<anchor_state_machine::SendingFinal>::FOOTPRINT
+    );
+    assert_eq!(
+        TagSideStateMachine::<tag_state_machine::Idle>::FOOTPRINT,
+        TagSideStateMachine::<tag_state_machine::WaitingForAnchorFinal>::FOOTPRINT
+    );
+}
+
+#[test]
+fn test_memory_budget() {
+    const BUDGET: usize = memory_budget(4, 8);
+    println!("Localizer (4 anchors, 8 tags): {} bytes", BUDGET);
+
+    // Full capacity is the assembled localizer
+    let full = memory_budget(16, 16);
+    assert_eq!(full, core::mem::size_of::<Localizer>());
+
+    // At least its parts, at most a few bytes of padding more
+    let parts = core::mem::size_of::<AnyTagSideStateMachine>()
+        + core::mem::size_of::<TimeSync>()
+        + core::mem::size_of::<u64>()
+        + core::mem::size_of::<heapless::Vec<Point, 16>>()
+        + core::mem::size_of::<Option<PositionFix>>();
+    assert!(full >= parts);
+    assert!(full < parts + 16);
+
+    // Every node adds its entries of the per-node buffers
+    type Tag = TagSideStateMachine<tag_state_machine::Idle>;
+    let per_anchor = Tag::ANCHOR_FOOTPRINT + core::mem::size_of::<Point>();
+    assert!(BUDGET < full);
+    assert_eq!(memory_budget(5, 8) - BUDGET, per_anchor);
+    assert_eq!(memory_budget(4, 9) - BUDGET, Tag::TAG_FOOTPRINT);
+    assert_eq!(full - BUDGET, 12 * per_anchor + 8 * Tag::TAG_FOOTPRINT);
+
+    // Without nodes, the fixed parts remain
+    assert!(memory_budget(0, 0) >= core::mem::size_of::<TimeSync>());
+}
+
+#[test]
+#[should_panic]
+fn test_memory_budget_over_capacity() {
+    memory_budget(17, 1);
+}
+
+// The tag keeps five timestamp vectors with a capacity of 16 anchors each, among its per-node
+// buffers
+const _: () = assert!(TagSideStateMachine::<tag_state_machine::Idle>::FOOTPRINT >= 5 * 16 * 8);
+const _: () = assert!(
+    TagSideStateMachine::<tag_state_machine::Idle>::FOOTPRINT
+        >= 16 * TagSideStateMachine::<tag_state_machine::Idle>::ANCHOR_FOOTPRINT
+            + 16 * TagSideStateMachine::<tag_state_machine::Idle>::TAG_FOOTPRINT
+);
+const _: () = assert!(AnchorSideStateMachine::<anchor_state_machine::Idle>::FOOTPRINT >= 16 * 16);
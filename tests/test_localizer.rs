@@ -0,0 +1,152 @@
+// Drive a full round of synthetic radio events through the localizer to a position fix
+
+use bilge::prelude::*;
+use heapless::Vec;
+use magic_loc_protocol::localizer::{Localizer, PositionFix, ProtocolEvent};
+use magic_loc_protocol::packet::{
+    AnyFinalPacket, FinalPacket, PacketType, PollPacket, PROTOCOL_VERSION,
+};
+use magic_loc_protocol::position::Point;
+use magic_loc_protocol::tag_state_machine::TagSideStateMachine;
+use magic_loc_protocol::util::{DWT_TIME_UNIT, SPEED_OF_LIGHT};
+
+/// Tag clock ahead of the network time, in device time units
+const TAG_OFFSET: f64 = 5_000.0;
+
+/// Tag clock reading at network time `network_ts`
+fn tag_ts(network_ts: f64) -> u64 {
+    libm::round(network_ts + TAG_OFFSET) as u64
+}
+
+/// Times of flight from each anchor to the tag, in device time units
+fn tofs(anchor_positions: &[Point; 4], tag_position: Point) -> [f64; 4] {
+    anchor_positions.map(|(x, y, z)| {
+        let d = (x - tag_position.0, y - tag_position.1, z - tag_position.2);
+        let distance = libm::sqrt((d.0 * d.0 + d.1 * d.1 + d.2 * d.2) as f64);
+        distance / (DWT_TIME_UNIT * SPEED_OF_LIGHT)
+    })
+}
+
+/// Feed the polls, the response and the finals of one round, returning the fix of the last final
+fn run_round(localizer: &mut Localizer, tofs: &[f64; 4]) -> Option<PositionFix> {
+    // Polls, in network time on the anchor side
+    for (i, tof) in tofs.iter().enumerate() {
+        let poll_tx_ts = 1_000_000 + 100_000 * i as u64;
+        let packet = PollPacket::new(
+            PacketType::Poll,
+            PROTOCOL_VERSION,
+            u40::new(poll_tx_ts),
+            i as u16,
+            0,
+        );
+        let rx_ts = tag_ts(poll_tx_ts as f64 + tof);
+        assert_eq!(
+            localizer.on_event(ProtocolEvent::PollReceived { packet, rx_ts }),
+            None
+        );
+    }
+
+    let response_tx_ts = 2_000_000.0;
+    let event = ProtocolEvent::ResponseSent {
+        tx_ts: tag_ts(response_tx_ts),
+    };
+    assert_eq!(localizer.on_event(event), None);
+
+    // Finals, the last one completes the round
+    let mut fix = None;
+    for (i, tof) in tofs.iter().enumerate() {
+        let response_rx_ts = libm::round(response_tx_ts + tof) as u64;
+        let final_tx_ts = 3_000_000 + 100_000 * i as u64;
+        // Finals carry one slot per tag of the network, five here with only ours valid
+        let mut timestamps = [u40::new(0); 5];
+        timestamps[0] = u40::new(response_rx_ts);
+        let mut packet = FinalPacket::new(
+            PacketType::Final,
+            PROTOCOL_VERSION,
+            timestamps,
+            u40::new(final_tx_ts),
+        );
+        for slot in 1..5 {
+            packet.set_slot_valid(slot, false);
+        }
+
+        assert_eq!(fix, None);
+        fix = localizer.on_event(ProtocolEvent::FinalReceived {
+            anchor_addr: i as u16,
+            packet: AnyFinalPacket::from(packet),
+            rx_ts: tag_ts(final_tx_ts as f64 + tof),
+        });
+    }
+
+    fix
+}
+
+#[test]
+fn localizer_full_round() {
+    let anchor_positions: [Point; 4] = [
+        (0.0, 0.0, 0.0),
+        (10.0, 0.0, 0.0),
+        (0.0, 10.0, 0.0),
+        (10.0, 10.0, 3.0),
+    ];
+    let tag_position: Point = (3.0, 4.0, 1.0);
+    let tofs = tofs(&anchor_positions, tag_position);
+
+    let state_machine = TagSideStateMachine::from_addresses(100, [0, 1, 2, 3], [100]);
+    let mut localizer = Localizer::new(state_machine, Vec::from_slice(&anchor_positions).unwrap());
+
+    // The root's beacon syncs the tag to network time
+    let beacon = ProtocolEvent::SyncBeacon {
+        local_rx_ts: tag_ts(0.0),
+        root_tx_ts: 0,
+    };
+    assert_eq!(localizer.on_event(beacon), None);
+
+    let fix = run_round(&mut localizer, &tofs).unwrap();
+    let error = (
+        fix.pos.0 - tag_position.0,
+        fix.pos.1 - tag_position.1,
+        fix.pos.2 - tag_position.2,
+    );
+    assert!(libm::sqrtf(error.0 * error.0 + error.1 * error.1 + error.2 * error.2) < 0.02);
+    assert!(fix.pdop.is_finite());
+
+    // Timestamped with the mean final RX time, in network time
+    let mean_final_rx = 3_150_000.0 + tofs.iter().sum::<f64>() / 4.0;
+    assert!(fix.synced);
+    assert!((fix.timestamp as f64 - mean_final_rx).abs() <= 2.0);
+
+    // Ready for the next round
+    assert_eq!(localizer.last_fix(), Some(fix));
+    assert!(localizer
+        .state_machine()
+        .valid_transitions()
+        .contains(&"waiting_for_anchor_final"));
+}
+
+#[test]
+fn localizer_stale_sync() {
+    let anchor_positions: [Point; 4] = [
+        (0.0, 0.0, 0.0),
+        (10.0, 0.0, 0.0),
+        (0.0, 10.0, 0.0),
+        (10.0, 10.0, 3.0),
+    ];
+    let tofs = tofs(&anchor_positions, (3.0, 4.0, 1.0));
+
+    let state_machine = TagSideStateMachine::from_addresses(100, [0, 1, 2, 3], [100]);
+    let mut localizer = Localizer::new(state_machine, Vec::from_slice(&anchor_positions).unwrap());
+    localizer.set_max_sync_age(1_000_000);
+
+    let beacon = ProtocolEvent::SyncBeacon {
+        local_rx_ts: tag_ts(0.0),
+        root_tx_ts: 0,
+    };
+    assert_eq!(localizer.on_event(beacon), None);
+
+    // The beacon is too old by the end of the round, the fix falls back to tag time
+    let fix = run_round(&mut localizer, &tofs).unwrap();
+    let mean_final_rx = 3_150_000.0 + tofs.iter().sum::<f64>() / 4.0;
+    assert!(!fix.synced);
+    assert!((fix.timestamp as f64 - (mean_final_rx + TAG_OFFSET)).abs() <= 2.0);
+}
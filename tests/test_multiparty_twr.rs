@@ -6,6 +6,7 @@ use heapless::Vec;
 use magic_loc_protocol::anchor_state_machine::AnchorSideStateMachine;
 use magic_loc_protocol::anchor_state_machine::{AnyAnchorSideStateMachine, Idle};
 use magic_loc_protocol::tag_state_machine;
+use magic_loc_protocol::Phase;
 
 #[test]
 fn scenario_8anchor_3tag() {
@@ -180,3 +181,36 @@ fn scenario_8anchor_3tag_fail() {
 
     println!("Tag SM status: {:#?}", tag_state_machines);
 }
+
+#[test]
+fn phases_are_paired() {
+    let anchor_addresses: [u16; 2] = [0, 1];
+    let tag_addresses: [u16; 1] = [100];
+
+    let mut anchor_state_machine: AnyAnchorSideStateMachine = AnchorSideStateMachine::<Idle>::new(
+        0,
+        Vec::from_slice(&anchor_addresses).unwrap(),
+        Vec::from_slice(&tag_addresses).unwrap(),
+    )
+    .into();
+    let mut tag_state_machine: tag_state_machine::AnyTagSideStateMachine =
+        tag_state_machine::TagSideStateMachine::new(
+            100,
+            Vec::from_slice(&anchor_addresses).unwrap(),
+            Vec::from_slice(&tag_addresses).unwrap(),
+        )
+        .into();
+
+    assert_eq!(anchor_state_machine.phase(), Phase::Poll);
+    assert_eq!(tag_state_machine.phase(), Phase::Poll);
+
+    anchor_state_machine.to_waiting_for_response(0).unwrap();
+    tag_state_machine.to_waiting_for_anchor_poll().unwrap();
+    assert_eq!(anchor_state_machine.phase(), Phase::Response);
+    assert_eq!(tag_state_machine.phase(), Phase::Poll);
+
+    anchor_state_machine.to_sending_final().unwrap();
+    tag_state_machine.to_waiting_for_anchor_final().unwrap();
+    assert_eq!(anchor_state_machine.phase(), Phase::Final);
+    assert_eq!(tag_state_machine.phase(), Phase::Final);
+}
@@ -0,0 +1,107 @@
+// Run a priority round through the priority slot of the superframe, over the wire
+
+use bilge::prelude::*;
+use dw3000_ng::Config;
+use magic_loc_protocol::anchor_state_machine::AnchorSideStateMachine;
+use magic_loc_protocol::driver::TagDriver;
+use magic_loc_protocol::packet::{parse_packet, Packet, PacketType, PollPacket, PROTOCOL_VERSION};
+use magic_loc_protocol::schedule::Schedule;
+use magic_loc_protocol::tag_state_machine::TagSideStateMachine;
+use magic_loc_protocol::util::{DWT_TIME_UNIT, SPEED_OF_LIGHT};
+use zerocopy::IntoBytes;
+
+/// Tag clock ahead of the anchor clock, in device time units
+const TAG_OFFSET: u64 = 3_900;
+
+/// Time of flight between the tag and the root, in device time units
+const TOF: u64 = 100;
+
+#[test]
+fn priority_round_in_priority_slot() {
+    let config = Config::default();
+    let anchors = [0, 1, 2];
+    let tags = [100, 101, 102];
+    let mut schedule = Schedule::new(anchors.len(), tags.len());
+    schedule.reserve_beacon_slot(&config);
+    schedule.reserve_priority_slot(&config);
+
+    // Only the root serves the priority slot
+    let root = AnchorSideStateMachine::from_addresses(0, anchors, tags);
+    assert!(root.serves_priority_slot());
+    assert!(!AnchorSideStateMachine::from_addresses(1, anchors, tags).serves_priority_slot());
+
+    let mut driver = TagDriver::new(TagSideStateMachine::from_addresses(101, anchors, tags));
+    driver.request_priority_round();
+    let priority_slot = schedule.priority_slot();
+    let (response_start, response_end) = driver.tx_slot(&schedule, 1, &config).unwrap();
+    assert!(priority_slot.0 <= response_start && response_end <= priority_slot.1);
+    assert_ne!(
+        Some((response_start, response_end)),
+        schedule.tx_slot(1, false, &config)
+    );
+
+    // The root polls at the start of the priority slot
+    let poll_tx_ts = 1_000;
+    let poll = PollPacket::new(
+        PacketType::Poll,
+        PROTOCOL_VERSION,
+        u40::new(poll_tx_ts),
+        0,
+        0,
+    );
+    let mut root = root.waiting_for_response(poll_tx_ts);
+
+    driver.start_round().unwrap();
+    let Ok(Packet::Poll(poll)) = parse_packet(&poll.value.to_le_bytes()) else {
+        panic!("poll not parsed");
+    };
+    driver
+        .on_poll(
+            poll.address(),
+            poll.tx_timestamp().value(),
+            poll_tx_ts + TAG_OFFSET + TOF,
+        )
+        .unwrap();
+
+    // The tag answers with a priority response, which the root attributes by its address
+    let response_tx_ts = 105_000;
+    let response = driver.priority_response().unwrap();
+    driver.on_response_sent(response_tx_ts).unwrap();
+
+    let Ok(Packet::PriorityResponse(response)) = parse_packet(&response.value.to_le_bytes()) else {
+        panic!("priority response not parsed");
+    };
+    let response_rx_ts = response_tx_ts - TAG_OFFSET + TOF;
+    assert_eq!(
+        root.set_priority_response_rx_ts(&response, response_rx_ts),
+        Some(1)
+    );
+
+    // The root's final completes the round
+    let root = root.sending_final();
+    let final_tx_ts = response_rx_ts + 100_000;
+    let final_ = root.build_final_packet::<3>(final_tx_ts);
+    let Ok(Packet::Final(final_)) = parse_packet(final_.as_bytes()) else {
+        panic!("final not parsed");
+    };
+    assert_eq!(final_.packet().valid_mask.get(), 0b010);
+
+    let results = driver
+        .on_final(
+            0,
+            final_.packet().rx_timestamps[1].value().value(),
+            final_.packet().tx_timestamp.value().value(),
+            final_tx_ts + TAG_OFFSET + TOF,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(results.participation(), 0b001);
+    assert!(results.distance(1).is_none());
+
+    let expected = TOF as f64 * DWT_TIME_UNIT * SPEED_OF_LIGHT;
+    assert!((results.distance(0).unwrap() - expected).abs() < 1e-3);
+
+    // Served, the tag is back to its regular slot
+    assert!(!driver.is_priority_pending());
+    assert_eq!(driver.priority_response(), None);
+}